@@ -0,0 +1,89 @@
+// Join/leave throughput of the presence registry while thousands of other
+// connections are online, compared with the `Mutex<HashSet>` it replaced.
+//
+// Run with `cargo bench --features ssr --bench presence`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    thread,
+};
+use tokio::sync::mpsc;
+use web_app_axum::{
+    presence::{Connection, Presence},
+    ws::ServerFrame,
+};
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: usize = 1_000;
+
+fn connection(sender: &mpsc::UnboundedSender<ServerFrame>) -> Connection {
+    Connection::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 3000)), sender.clone())
+}
+
+fn presence_join_leave(c: &mut Criterion) {
+    let (sender, _receiver) = mpsc::unbounded_channel();
+    let mut group = c.benchmark_group("join_leave");
+    group.throughput(Throughput::Elements((THREADS * OPS_PER_THREAD) as u64));
+
+    for online in [1_000, 10_000] {
+        let presence = Arc::new(Presence::new());
+        for i in 0..online {
+            presence.join(&format!("idle-{i}"), None, connection(&sender));
+        }
+
+        group.bench_with_input(BenchmarkId::new("presence", online), &online, |b, _| {
+            b.iter(|| {
+                let handles: Vec<_> = (0..THREADS)
+                    .map(|t| {
+                        let presence = presence.clone();
+                        let sender = sender.clone();
+                        thread::spawn(move || {
+                            for i in 0..OPS_PER_THREAD {
+                                let name = format!("user-{t}-{i}");
+                                let connection = connection(&sender);
+                                let id = connection.id;
+                                presence.join(&name, None, connection);
+                                presence.leave(id);
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            });
+        });
+
+        let user_set = Arc::new(Mutex::new(
+            (0..online).map(|i| format!("idle-{i}")).collect::<HashSet<_>>(),
+        ));
+
+        group.bench_with_input(BenchmarkId::new("mutex_hashset", online), &online, |b, _| {
+            b.iter(|| {
+                let handles: Vec<_> = (0..THREADS)
+                    .map(|t| {
+                        let user_set = user_set.clone();
+                        thread::spawn(move || {
+                            for i in 0..OPS_PER_THREAD {
+                                let name = format!("user-{t}-{i}");
+                                user_set.lock().unwrap().insert(name.clone());
+                                user_set.lock().unwrap().remove(&name);
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, presence_join_leave);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-use crate::error_template::{AppError, ErrorTemplate};
+use crate::error_template::{error_message, NotFound, PageErrors};
 use leptos::*;
 use leptos_meta::*;
 use leptos_router::*;
@@ -7,6 +7,9 @@ use leptos_router::*;
 pub fn App() -> impl IntoView {
     provide_meta_context();
 
+    #[cfg(feature = "ssr")]
+    crate::security::provide_page_policy();
+
     #[cfg(not(feature = "ssr"))]
     (move || {
         use crate::ws::provide_websocket;
@@ -40,92 +43,677 @@ pub fn App() -> impl IntoView {
         <Title text="Welcome to Leptos"/>
 
         // content for this welcome page
-        <Router fallback=|| {
-            let mut outside_errors = Errors::default();
-            outside_errors.insert_with_default_key(AppError::NotFound);
-            view! {
-                <ErrorTemplate outside_errors/>
-            }
-            .into_view()
-        }>
+        <Router fallback=|| view! { <NotFound/> }.into_view()>
             <main>
                 <Routes>
-                    <Route path="" view=|| view! { <HomePage/> }/>
+                    // Search opens next to the chat, which stays connected.
+                    // Each page has its own error boundary, so a failing search or
+                    // invitation leaves the chat around it alone.
+                    <Route path="" view=|| view! { <PageErrors><HomePage/></PageErrors> }>
+                        <Route path="search" view=|| view! { <PageErrors><SearchPage/></PageErrors> }/>
+                        <Route path="invite/:code" view=|| view! { <PageErrors><InvitePage/></PageErrors> }/>
+                        <Route path="" view=|| ()/>
+                    </Route>
                 </Routes>
             </main>
         </Router>
     }
 }
 
-use crate::ws::{ServerMessage, create_ws_signal, send_msg};
+use crate::commands::list_commands;
+use crate::markdown::Markdown;
+use crate::notify::{is_muted, notify, on_visible, request_permission, set_muted};
+use crate::rooms::{
+    create_invite, create_room, invite_member, list_rooms, revoke_member, update_room, InvitePage, Room, RoomSettings, Visibility, MAX_DESCRIPTION_CHARS, MAX_MESSAGE_CHARS, MAX_SLOW_MODE_SECS,
+    MAX_TOPIC_CHARS,
+};
+use crate::search::SearchPage;
+use crate::ws::{
+    create_ws_signal, send_msg, store_token, stored_token, upload_attachment, Attachment,
+    ClientMessage, JoinedAs, LinkPreview, Mention, ServerFrame, ServerMessage, DEFAULT_ROOM,
+};
+use std::collections::HashMap;
 
 use uuid::Uuid;
-use web_sys::SubmitEvent;
+use web_sys::{HtmlInputElement, SubmitEvent};
 
 #[derive(Debug, Clone)]
 enum WsMessage {
-    Me(String),
+    Me(ServerMessage),
+    // Ours, until the server sends it back to us, or turns it down.
+    Pending(ServerMessage),
     Server(ServerMessage),
 }
 
+// Images are shown as a thumbnail linking to the full file; anything else is a
+// download link.
 #[component]
-fn HomePage() -> impl IntoView {
-    //let mut disable_button = false;
+fn AttachmentPreview(attachment: Attachment) -> impl IntoView {
+    let url = attachment.url();
+
+    if attachment.is_image() {
+        let srcset = format!(
+            "{} 160w, {} 480w",
+            attachment.thumbnail_url(160),
+            attachment.thumbnail_url(480)
+        );
+        view! {
+            <a href=url target="_blank">
+                <img
+                    class="chat-message__image"
+                    src=attachment.thumbnail_url(480)
+                    srcset=srcset
+                    sizes="240px"
+                    alt=attachment.name
+                />
+            </a>
+        }
+        .into_view()
+    } else {
+        view! {
+            <a class="chat-message__file" href=url download=attachment.name.clone()>
+                {attachment.name}
+            </a>
+        }
+        .into_view()
+    }
+}
+
+// A card for a linked page: its image, if it has one, then where it's from,
+// its title and what it says about itself.
+#[component]
+fn LinkPreviewCard(preview: LinkPreview) -> impl IntoView {
+    view! {
+        <a class="chat-message__preview" href=preview.url target="_blank" rel="noopener noreferrer nofollow">
+            {preview.image.map(|image| view! {
+                <img class="chat-message__preview-image" src=image alt="" loading="lazy" referrerpolicy="no-referrer"/>
+            })}
+            {preview.site_name.map(|site_name| view! { <span class="chat-message__preview-site">{site_name}</span> })}
+            <strong class="chat-message__preview-title">{preview.title}</strong>
+            {preview.description.map(|description| view! {
+                <span class="chat-message__preview-description">{description}</span>
+            })}
+        </a>
+    }
+}
+
+// What the room we are in is about and what it allows, with its settings for
+// those who may change them.
+#[component]
+fn RoomHeader(room: Room, admin: bool) -> impl IntoView {
+    let joined_as = expect_context::<JoinedAs>();
+    let Room { name, settings, members, .. } = room;
+
+    let (topic, set_topic) = create_signal(settings.topic.clone());
+    let (description, set_description) = create_signal(settings.description.clone());
+    let (visibility, set_visibility) = create_signal(settings.visibility);
+    let (slow_mode, set_slow_mode) = create_signal(settings.slow_mode);
+    let (max_length, set_max_length) = create_signal(settings.max_length);
+
+    let room_name = name.clone();
+    let save = create_action(move |settings: &RoomSettings| {
+        let (room, settings) = (room_name.clone(), settings.clone());
+        let (user, token) = joined_as.credentials();
+        async move { update_room(user.unwrap_or_default(), token.unwrap_or_default(), room, settings).await }
+    });
+    let save_settings = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        save.dispatch(RoomSettings {
+            topic: topic.get(),
+            description: description.get(),
+            visibility: visibility.get(),
+            slow_mode: slow_mode.get(),
+            max_length: max_length.get(),
+        });
+    };
+    let save_error = move || save.value().get().and_then(Result::err).map(|err| error_message(&err));
+
+    // Who else may come in, when the room isn't public.
+    let members = store_value(members);
+    let (invitee, set_invitee) = create_signal(String::new());
+    let room_name = name.clone();
+    // Invites go by the name someone is online as; revoking goes by who the
+    // room knows them to be.
+    let change_member = create_action(move |(who, invite): &(String, bool)| {
+        let (room, who, invite) = (room_name.clone(), who.clone(), *invite);
+        let (user, token) = joined_as.credentials();
+        let (user, token) = (user.unwrap_or_default(), token.unwrap_or_default());
+        async move {
+            if invite {
+                invite_member(user, token, room, who).await
+            } else {
+                revoke_member(user, token, room, who).await
+            }
+        }
+    });
+    let invite_by_name = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        change_member.dispatch((invitee.get(), true));
+        set_invitee.set(String::new());
+    };
+    let member_error = move || change_member.value().get().and_then(Result::err).map(|err| error_message(&err));
+
+    // Links anyone can follow in, for a while or for so many people.
+    let (expires_in, set_expires_in) = create_signal(Some(24 * 60 * 60));
+    let (max_uses, set_max_uses) = create_signal(0);
+    let room_name = name.clone();
+    let make_link = create_action(move |_: &()| {
+        let room = room_name.clone();
+        let (user, token) = joined_as.credentials();
+        let max_uses = Some(max_uses.get_untracked()).filter(|max_uses| *max_uses > 0);
+        let expires_in = expires_in.get_untracked();
+        async move { create_invite(user.unwrap_or_default(), token.unwrap_or_default(), room, expires_in, max_uses).await }
+    });
+    let create_link = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        make_link.dispatch(());
+    };
+    let link = move || match make_link.value().get() {
+        Some(Ok(invite)) => {
+            let origin = window().location().origin().unwrap_or_default();
+            let url = format!("{origin}{}", invite.path());
+            view! { <input class="room__invite-link" readonly prop:value=url/> }.into_view()
+        }
+        Some(Err(err)) => view! { <p class="room__error">{error_message(&err)}</p> }.into_view(),
+        None => ().into_view(),
+    };
+
+    view! {
+        <header class="room">
+            <h2 class="room__name">"#"{name}</h2>
+            <span class="room__visibility">{settings.visibility.label()}</span>
+            {(!settings.topic.is_empty()).then(|| view! { <p class="room__topic">{settings.topic.clone()}</p> })}
+            {(!settings.description.is_empty()).then(|| view! {
+                <div class="room__description"><Markdown source=settings.description.clone()/></div>
+            })}
+            <p class="room__limits">
+                {(settings.slow_mode > 0).then(|| format!("Slow mode: one message every {}s. ", settings.slow_mode))}
+                {(settings.max_length > 0).then(|| format!("Messages up to {} characters.", settings.message_limit()))}
+            </p>
+            <Show when=move || admin fallback=|| ()>
+                <details class="room__settings">
+                    <summary>"settings"</summary>
+                    <form on:submit=save_settings>
+                        <label>
+                            "Topic"
+                            <input
+                                prop:value=topic
+                                maxlength=MAX_TOPIC_CHARS
+                                on:input=move |ev| set_topic.set(event_target_value(&ev))
+                            />
+                        </label>
+                        <label>
+                            "Description"
+                            <textarea
+                                prop:value=description
+                                maxlength=MAX_DESCRIPTION_CHARS
+                                on:input=move |ev| set_description.set(event_target_value(&ev))
+                            />
+                        </label>
+                        <label>
+                            "Visibility"
+                            <select on:change=move |ev| {
+                                if let Ok(chosen) = event_target_value(&ev).parse() {
+                                    set_visibility.set(chosen);
+                                }
+                            }>
+                                {Visibility::ALL.into_iter().map(|option| view! {
+                                    <option value=option.as_str() selected=move || visibility.get() == option>
+                                        {option.label()}
+                                    </option>
+                                }).collect_view()}
+                            </select>
+                        </label>
+                        <label>
+                            "Slow mode (seconds, 0 for off)"
+                            <input
+                                type="number"
+                                min="0"
+                                max=MAX_SLOW_MODE_SECS
+                                prop:value=move || slow_mode.get().to_string()
+                                on:input=move |ev| set_slow_mode.set(event_target_value(&ev).parse().unwrap_or(0))
+                            />
+                        </label>
+                        <label>
+                            "Longest message (characters, 0 for the most allowed)"
+                            <input
+                                type="number"
+                                min="0"
+                                max=MAX_MESSAGE_CHARS
+                                prop:value=move || max_length.get().to_string()
+                                on:input=move |ev| set_max_length.set(event_target_value(&ev).parse().unwrap_or(0))
+                            />
+                        </label>
+                        <button type="submit" disabled=move || save.pending().get()>"save"</button>
+                        {move || save_error().map(|err| view! { <p class="room__error">{err}</p> })}
+                    </form>
+                    <Show when=move || settings.visibility != Visibility::Public fallback=|| ()>
+                        <h3>"Members"</h3>
+                        <ul class="room__members">
+                            {move || members.get_value().into_iter().map(|member| {
+                                let revoked = member.id;
+                                view! {
+                                <li>
+                                    {member.name}
+                                    " "
+                                    <button type="button" on:click=move |_| change_member.dispatch((revoked.clone(), false))>
+                                        "remove"
+                                    </button>
+                                </li>
+                                }
+                            }).collect_view()}
+                        </ul>
+                        <form on:submit=invite_by_name>
+                            <input
+                                placeholder="Name"
+                                prop:value=invitee
+                                on:input=move |ev| set_invitee.set(event_target_value(&ev))
+                            />
+                            <button type="submit" disabled=move || invitee.get().trim().is_empty()>"invite"</button>
+                        </form>
+                        {move || member_error().map(|err| view! { <p class="room__error">{err}</p> })}
+                        <form on:submit=create_link>
+                            <label>
+                                "Link expires"
+                                <select on:change=move |ev| set_expires_in.set(event_target_value(&ev).parse().ok())>
+                                    <option value="3600">"in an hour"</option>
+                                    <option value="86400" selected>"in a day"</option>
+                                    <option value="604800">"in a week"</option>
+                                    <option value="never">"never"</option>
+                                </select>
+                            </label>
+                            <label>
+                                "Uses (0 for any number)"
+                                <input
+                                    type="number"
+                                    min="0"
+                                    prop:value=move || max_uses.get().to_string()
+                                    on:input=move |ev| set_max_uses.set(event_target_value(&ev).parse().unwrap_or(0))
+                                />
+                            </label>
+                            <button type="submit" disabled=move || make_link.pending().get()>"create link"</button>
+                        </form>
+                        {link}
+                    </Show>
+                </details>
+            </Show>
+        </header>
+    }
+}
 
-    let last_message = create_ws_signal();
+#[component]
+fn HomePage() -> impl IntoView {
+    let last_frame = create_ws_signal();
     let (messages, set_messages) = create_signal(Vec::<(Uuid, WsMessage)>::new());
 
-    // update message to me if sent by someone else (problem is that it also does that if I
-    // sent the message. Make sure to only do it when someone other than me sends a message)
-    create_effect(move |_| match last_message.get() {
-        Some(message) => {
-            set_messages.update(move |messages| {
-                (*messages).push((Uuid::new_v4(), WsMessage::Server(message)));
+    // The name the server accepted us under, once it has.
+    let (joined_as, set_joined_as) = create_signal(None::<String>);
+    // The newest message we (on any device) have seen.
+    let (last_read, set_last_read) = create_signal(None::<Uuid>);
+    // Link previews by message. They can turn up after their message does.
+    let (previews, set_previews) = create_signal(HashMap::<Uuid, LinkPreview>::new());
+    // Messages that mentioned us, and how many of them we haven't looked at.
+    let (mentions, set_mentions) = create_signal(Vec::<Mention>::new());
+    let (unread_mentions, set_unread_mentions) = create_signal(0);
+    // Whether this browser silenced the room.
+    let (muted, set_muted_signal) = create_signal(false);
+    // The room we are in, once the server put us in one, and whether we may
+    // change it.
+    let (room, set_room) = create_signal(None::<(Room, bool)>);
+    let room_name = move || room.with(|room| room.as_ref().map_or(DEFAULT_ROOM.to_owned(), |(room, _)| room.name.clone()));
+    provide_context(JoinedAs(joined_as));
+    // Private rooms are only listed for their members, so this depends on who
+    // we are.
+    let rooms = create_resource(
+        move || joined_as.get(),
+        move |_| {
+            let (user, token) = JoinedAs(joined_as).credentials();
+            list_rooms(user, token)
+        },
+    );
+
+    // Tell our other devices how far we have read.
+    let mark_read = move |id: Uuid| {
+        set_last_read.set(Some(id));
+        let _ = send_msg(&ClientMessage::Read { id });
+    };
+
+    // Adds a message from the server to the list, returning false if it was
+    // already there.
+    let add_message = move |message: ServerMessage| {
+        if message.room != room_name() {
+            return false;
+        }
+        let id = message.id;
+        let from_me = joined_as.get_untracked().as_ref() == Some(&message.sender);
+
+        // Our own messages come back to us too. Those this device already shows
+        // have gone out now; keep those typed on another of our devices.
+        let seen = messages.with_untracked(|messages| messages.iter().any(|(m, _)| *m == id));
+        if seen {
+            set_messages.update(|messages| {
+                if let Some((_, shown @ WsMessage::Pending(_))) = messages.iter_mut().find(|(m, _)| *m == id) {
+                    *shown = WsMessage::Me(message);
+                }
+            });
+            return false;
+        }
+
+        if let Some(preview) = message.preview.clone() {
+            set_previews.update(|previews| {
+                previews.insert(id, preview);
             });
         }
+
+        set_messages.update(move |messages| {
+            let message = if from_me {
+                WsMessage::Me(message)
+            } else {
+                WsMessage::Server(message)
+            };
+            (*messages).push((id, message));
+        });
+        true
+    };
+
+    // Something only we get to see, e.g. an error.
+    let notice = move |msg: String| {
+        let msg = ServerMessage {
+            id: Uuid::new_v4(),
+            room: room_name(),
+            sender: "Server".to_owned(),
+            msg,
+            attachment: None,
+            preview: None,
+            mentions: Vec::new(),
+        };
+        set_messages.update(move |messages| {
+            (*messages).push((msg.id, WsMessage::Server(msg)));
+        });
+    };
+
+    create_effect(move |_| match last_frame.get() {
+        Some(ServerFrame::Chat(message)) => {
+            let id = message.id;
+            if add_message(message) && !document().hidden() {
+                mark_read(id);
+            }
+        }
+        // What was said before we joined; the `Read` frame after it says how much
+        // of it we have seen.
+        Some(ServerFrame::History { messages }) => {
+            for message in messages {
+                add_message(message);
+            }
+        }
+        Some(ServerFrame::Session { name, token }) => {
+            store_token(&token);
+            set_joined_as.set(Some(name));
+        }
+        Some(ServerFrame::Read { id }) => set_last_read.set(Some(id)),
+        Some(ServerFrame::Preview { id, preview, .. }) => set_previews.update(|previews| {
+            previews.insert(id, preview);
+        }),
+        // A different room has different messages; its history comes next.
+        Some(ServerFrame::EnteredRoom { room, admin }) => {
+            set_muted_signal.set(is_muted(&room.name));
+            set_messages.set(Vec::new());
+            set_previews.set(HashMap::new());
+            set_room.set(Some((room, admin)));
+        }
+        Some(ServerFrame::RoomUpdated { room: updated }) => {
+            if room_name() == updated.name {
+                set_room.update(|room| {
+                    if let Some((room, _)) = room {
+                        *room = updated;
+                    }
+                });
+            }
+            rooms.refetch();
+        }
+        Some(ServerFrame::RoomsChanged) => rooms.refetch(),
+        Some(ServerFrame::Mention { mention, unread }) => {
+            if !is_muted(&mention.room) {
+                notify(&format!("{} mentioned you", mention.sender), &mention.msg, "mention");
+            }
+            set_mentions.update(|mentions| mentions.push(mention));
+            set_unread_mentions.set(unread);
+        }
+        Some(ServerFrame::Mentions { mentions, unread }) => {
+            set_mentions.set(mentions);
+            set_unread_mentions.set(unread);
+        }
+        Some(ServerFrame::Rejected { id, msg }) => {
+            set_messages.update(|messages| messages.retain(|(m, _)| *m != id));
+            notice(msg);
+        }
+        Some(ServerFrame::Notice { msg }) | Some(ServerFrame::Error { msg }) => notice(msg),
         None => (),
     });
 
+    let preview = move |id: Uuid| {
+        previews
+            .with(|previews| previews.get(&id).cloned())
+            .map(|preview| view! { <LinkPreviewCard preview/> })
+    };
+
+    // Opening the inbox counts as reading it, on all our devices.
+    let open_mentions = move |ev: ev::Event| {
+        let opened = event_target::<web_sys::HtmlDetailsElement>(&ev).open();
+        if opened && unread_mentions.get_untracked() > 0 {
+            set_unread_mentions.set(0);
+            let _ = send_msg(&ClientMessage::ReadMentions);
+        }
+    };
+
+    // Effects only run in the browser, which is where these preferences and
+    // events live.
+    create_effect(move |_| {
+        set_muted_signal.set(is_muted(&room_name()));
+
+        // Whatever arrived while we were away has been seen once we're back.
+        on_visible(move || {
+            let newest = messages.with_untracked(|messages| messages.last().map(|(id, _)| *id));
+            if let Some(id) = newest {
+                if last_read.get_untracked() != Some(id) {
+                    mark_read(id);
+                }
+            }
+        });
+    });
+
+    let toggle_mute = move |_| {
+        let mute = !muted.get();
+        set_muted(&room_name(), mute);
+        set_muted_signal.set(mute);
+    };
+
+    // What the tab title counts: other people's messages we haven't read yet.
+    let unread_count = create_memo(move |_| {
+        if muted.get() {
+            return 0;
+        }
+        messages.with(|messages| {
+            let read = match last_read.get() {
+                Some(last_read) => match messages.iter().position(|(id, _)| *id == last_read) {
+                    Some(read) => read + 1,
+                    None => return 0,
+                },
+                None => 0,
+            };
+            messages[read..]
+                .iter()
+                .filter(|(_, message)| matches!(message, WsMessage::Server(_)))
+                .count()
+        })
+    });
+
+    // Search results link to `/#message-<id>`; that message is brought into view
+    // and marked once it's in the list.
+    let location = use_location();
+    let target = move || {
+        location
+            .hash
+            .with(|hash| hash.strip_prefix("#message-").and_then(|id| id.parse::<Uuid>().ok()))
+    };
+    create_effect(move |_| {
+        let Some(id) = target() else {
+            return;
+        };
+        if messages.with(|messages| messages.iter().any(|(m, _)| *m == id)) {
+            request_animation_frame(move || {
+                if let Some(element) = document().get_element_by_id(&format!("message-{id}")) {
+                    element.scroll_into_view();
+                }
+            });
+        }
+    });
+
+    // Messages after the last one we have read are shown as unread.
+    let is_unread = move |id: Uuid| {
+        let Some(last_read) = last_read.get() else {
+            return true;
+        };
+        messages.with(|messages| {
+            let position = |target: Uuid| messages.iter().position(|(m, _)| *m == target);
+            match (position(id), position(last_read)) {
+                (Some(message), Some(read)) => message > read,
+                _ => false,
+            }
+        })
+    };
+
     // get input and update it here
     let (message_input, set_message_input) = create_signal("".to_owned());
+    // A file already uploaded, waiting to go out with the next message.
+    let (attachment, set_attachment) = create_signal(None::<Attachment>);
+
+    let choose_file = move |ev: ev::Event| {
+        let input = event_target::<HtmlInputElement>(&ev);
+        let Some(file) = input.files().and_then(|files| files.get(0)) else {
+            return;
+        };
+        input.set_value("");
+
+        let (user, token) = JoinedAs(joined_as).credentials();
+        let (user, token) = (user.unwrap_or_default(), token.unwrap_or_default());
+        spawn_local(async move {
+            match upload_attachment(&file, &user, &token).await {
+                Ok(uploaded) => set_attachment.set(Some(uploaded)),
+                Err(err) => notice(format!(
+                    "Couldn't upload {}: {}",
+                    file.name(),
+                    err.as_string().unwrap_or_default()
+                )),
+            }
+        });
+    };
 
     // send message to everyone else if sent by me
+    // The commands the server knows, for suggesting as they're typed.
+    let commands = create_resource(|| (), |_| list_commands());
+    let matching_commands = move || {
+        let input = message_input.get();
+        let typed = input.strip_prefix('/').filter(|typed| !typed.starts_with('/'))?;
+        let name = typed.split_whitespace().next().unwrap_or_default();
+        let commands = commands.get()?.ok()?;
+        Some(
+            commands
+                .into_iter()
+                .filter(|command| command.name.starts_with(name))
+                .collect::<Vec<_>>(),
+        )
+    };
+
     let send_message = move |ev: SubmitEvent| {
         ev.prevent_default();
 
-        let msg = message_input.get();
+        // `/command` runs a command; `//text` says "/text".
+        let input = message_input.get();
+        let msg = match input.strip_prefix('/') {
+            Some(escaped) if escaped.starts_with('/') => escaped.to_owned(),
+            Some(_) => {
+                let _ = send_msg(&ClientMessage::Command { line: input });
+                set_message_input.set("".to_owned());
+                return;
+            }
+            None => input,
+        };
+
+        let message = ServerMessage {
+            id: Uuid::new_v4(),
+            room: room_name(),
+            sender: joined_as.get().unwrap_or_default(),
+            msg,
+            attachment: attachment.get(),
+            preview: None,
+            mentions: Vec::new(),
+        };
 
-        let _ = send_msg(msg.as_str());
+        let _ = send_msg(&ClientMessage::Chat {
+            id: message.id,
+            msg: message.msg.clone(),
+            attachment: message.attachment.clone(),
+        });
         set_message_input.set("".to_owned());
+        set_attachment.set(None);
 
+        let id = message.id;
         set_messages.update(move |messages| {
-            (*messages).push((Uuid::new_v4(), WsMessage::Me(msg)));
+            (*messages).push((id, WsMessage::Pending(message)));
         });
+        mark_read(id);
+    };
+
+    // New rooms are entered as soon as they exist.
+    let (new_room, set_new_room) = create_signal(String::new());
+    let create = create_action(move |name: &String| {
+        let name = name.clone();
+        let (user, token) = JoinedAs(joined_as).credentials();
+        async move { create_room(user.unwrap_or_default(), token.unwrap_or_default(), name).await }
+    });
+    create_effect(move |_| match create.value().get() {
+        Some(Ok(room)) => {
+            set_new_room.set(String::new());
+            let _ = send_msg(&ClientMessage::JoinRoom { room: room.name });
+        }
+        Some(Err(err)) => notice(error_message(&err)),
+        None => (),
+    });
+    let create_new_room = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        create.dispatch(new_room.get());
+    };
+    let enter_room = move |name: String| {
+        if name != room_name() {
+            let _ = send_msg(&ClientMessage::JoinRoom { room: name });
+        }
+    };
+    // The room's limit is enforced as it is typed.
+    let max_length = move || {
+        room.with(|room| room.as_ref().map(|(room, _)| room.settings.message_limit()))
+            .unwrap_or(MAX_MESSAGE_CHARS)
     };
 
     let (username, set_username_input) = create_signal("".to_owned());
 
-    // send message to everyone else if sent by me
+    // ask the server for the name; it answers with a session (or an error)
     let set_username = move |ev: SubmitEvent| {
         ev.prevent_default();
 
-        let mut msg = username.get();
-
-        let _ = send_msg(msg.as_str());
-        set_username_input.set(msg.to_owned());
-
-        msg.push_str(" has joined");
-        let msg = ServerMessage{sender: "Server".to_owned(), msg};
+        let name = username.get();
+        let token = stored_token();
+        request_permission();
 
-        log::info!("{msg:?}");
-
-        set_messages.update(move |messages| {
-            (*messages).push((Uuid::new_v4(), WsMessage::Server(msg)));
-        });
-        //disable_button = true;
+        let _ = send_msg(&ClientMessage::Join { name, token });
     };
 
     view! {
+        <Title text=move || match unread_count.get() {
+            0 => String::from("Welcome to Leptos"),
+            unread => format!("({unread}) Welcome to Leptos"),
+        }/>
+
         <form on:submit=set_username>
             <input
                 placeholder="Username"
@@ -135,45 +723,136 @@ fn HomePage() -> impl IntoView {
                 }
             />
             // submit button
-            <button type="submit" disabled=move || username.get() == "">
+            <button type="submit" disabled=move || username.get() == "" || joined_as.get().is_some()>
                 "set name"
             </button>
+            <button type="button" class="mute" on:click=toggle_mute>
+                {move || if muted.get() { "unmute" } else { "mute" }}
+            </button>
+            <A href="/search">"search"</A>
         </form>
 
+        <Outlet/>
+
+        <Show when=move || !mentions.with(Vec::is_empty) fallback=|| ()>
+            <details class="mentions" on:toggle=open_mentions>
+                <summary>
+                    "Mentions"
+                    <Show when=move || { unread_mentions.get() > 0 } fallback=|| ()>
+                        <span class="mentions__unread">{unread_mentions}</span>
+                    </Show>
+                </summary>
+                <ol class="mentions__list">
+                    {move || mentions.get().into_iter().rev().map(|mention| view! {
+                        <li class="mentions__item">
+                            <span class="chat-message__sender">{mention.sender}</span>
+                            <Markdown source=mention.msg/>
+                        </li>
+                    }).collect_view()}
+                </ol>
+            </details>
+        </Show>
+
+        <nav class="rooms">
+            <Suspense fallback=|| ()>
+                // The chat can't do much without its rooms, so failing to list
+                // them is an error for the whole page.
+                {move || rooms.get().map(|rooms| rooms.map_err(ServerFnErrorErr::from).map(|rooms| rooms.into_iter().map(|listed| {
+                    let name = listed.name.clone();
+                    let current = {
+                        let name = name.clone();
+                        move || if room_name() == name { "rooms__room rooms__room--current" } else { "rooms__room" }
+                    };
+                    view! {
+                        <button
+                            type="button"
+                            class=current
+                            disabled=move || joined_as.get().is_none()
+                            on:click=move |_| enter_room(name.clone())
+                        >
+                            "#"{listed.name}
+                        </button>
+                    }
+                }).collect_view()))}
+            </Suspense>
+            <form class="rooms__new" on:submit=create_new_room>
+                <input
+                    placeholder="new-room"
+                    maxlength="32"
+                    prop:value=new_room
+                    on:input=move |ev| set_new_room.set(event_target_value(&ev))
+                />
+                <button type="submit" disabled=move || new_room.get().is_empty() || joined_as.get().is_none()>
+                    "create"
+                </button>
+            </form>
+        </nav>
+
+        {move || room.get().map(|(room, admin)| view! { <RoomHeader room admin/> })}
+
         <div class="chat__container">
         <ol class="chat">
             <For
                 each=move || messages.get()
-                key=move |message| message.0
-                children=move |(_, message)| {
+                // A message of ours is shown anew once the server confirms it.
+                key=move |(id, message)| (*id, matches!(message, WsMessage::Pending(_)))
+                children=move |(id, message)| {
                     view! {
                         {
                             move || {
                                 let message = message.clone();
+                                let pending = matches!(message, WsMessage::Pending(_));
                             // check if the message was sent by me or another client then update from there
                             match message { 
-                            WsMessage::Me(msg) => view!{
-                                <li class="chat-message__container chat-message__container--me">
-                                <p class="chat-message__message chat-message__message--me">{
-                                    msg
-                                }</p>
+                            WsMessage::Me(message) | WsMessage::Pending(message) => view!{
+                                <li
+                                    id=format!("message-{id}")
+                                    class=move || {
+                                        let mut class = String::from("chat-message__container chat-message__container--me");
+                                        if pending {
+                                            class.push_str(" chat-message__container--pending");
+                                        }
+                                        if target() == Some(id) {
+                                            class.push_str(" chat-message__container--target");
+                                        }
+                                        class
+                                    }
+                                >
+                                {message.attachment.map(|attachment| view! { <AttachmentPreview attachment/> })}
+                                <div class="chat-message__message chat-message__message--me">
+                                    <Markdown source=message.msg mentions=message.mentions/>
+                                </div>
+                                {move || preview(id)}
                                 </li>
                             }.into_view(),
                             WsMessage::Server(message) => {
-                                if username.get() != message.sender {
-                                    view! {
-                                        <li class="chat-message__container">
-                                    <p class="chat-message__sender">{move || {
-                                        let sender = &message.sender;
-                                        sender.to_owned()
-                                    }}</p>
-                                    <p class="chat-message__message chat-message__message--server">{move || {
-                                        let msg = &message.msg;
-                                        msg.to_owned()
-                                    }}</p>
-                                    </li>
-                                }} else {view!{<li>""</li>}}
-                            }.into_view(),
+                                let mentions = message.mentions.clone();
+                                view! {
+                                <li id=format!("message-{id}") class=move || {
+                                    let mut class = String::from("chat-message__container");
+                                    if target() == Some(id) {
+                                        class.push_str(" chat-message__container--target");
+                                    }
+                                    if is_unread(id) {
+                                        class.push_str(" chat-message__container--unread");
+                                    }
+                                    if joined_as.with(|name| name.as_ref().is_some_and(|name| mentions.contains(name))) {
+                                        class.push_str(" chat-message__container--mentioned");
+                                    }
+                                    class
+                                }>
+                                <p class="chat-message__sender">{move || {
+                                    let sender = &message.sender;
+                                    sender.to_owned()
+                                }}</p>
+                                {message.attachment.clone().map(|attachment| view! { <AttachmentPreview attachment/> })}
+                                <div class="chat-message__message chat-message__message--server">
+                                    <Markdown source=message.msg.clone() mentions=message.mentions.clone()/>
+                                </div>
+                                {move || preview(id)}
+                                </li>
+                            }.into_view()
+                            }
                         }}}
                 }}
             />
@@ -183,17 +862,45 @@ fn HomePage() -> impl IntoView {
         // chat box that allows others to type on
         <form on:submit=send_message class="">
             <div class="chat-box">
+                <Show when=move || attachment.get().is_some() fallback=|| ()>
+                    <div class="chat-box__attachment">
+                        {move || attachment.get().map(|attachment| attachment.name)}
+                        <button type="button" on:click=move |_| set_attachment.set(None)>"remove"</button>
+                    </div>
+                </Show>
+                // Suggestions come from the server; nothing waits on them.
+                <Suspense fallback=|| ()>
+                    {move || matching_commands().filter(|commands| !commands.is_empty()).map(|commands| view! {
+                        <ul class="chat-box__commands">
+                            {commands.into_iter().map(|command| view! {
+                                <li><code>{command.usage}</code>" "{command.description}</li>
+                            }).collect_view()}
+                        </ul>
+                    })}
+                    <datalist id="chat-commands">
+                        {move || commands.get().and_then(Result::ok).unwrap_or_default().into_iter().map(|command| view! {
+                            <option value=format!("/{} ", command.name)/>
+                        }).collect_view()}
+                    </datalist>
+                </Suspense>
                 <div class="input-container">
+                    <label class="chat-box__attach" title="Attach a file">
+                        "+"
+                        <input type="file" on:change=choose_file hidden/>
+                    </label>
                     <input
                         id="user-input"
                         placeholder="Message"
+                        list="chat-commands"
+                        autocomplete="off"
+                        maxlength=max_length
                         prop:value=message_input
                         on:input=move |ev| {
                             set_message_input.set(event_target_value(&ev));
                         }
                     />
                     // submit button
-                    <button type="submit" id="send-button" disabled=move || message_input.get() == "" || username.get() == "">
+                    <button type="submit" id="send-button" disabled=move || (message_input.get() == "" && attachment.get().is_none()) || joined_as.get().is_none()>
                         "send"
                     </button>
                 </div>
@@ -0,0 +1,299 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{
+        chat::AppState,
+        error_template::{AppError, ErrorBody},
+        images::{make_thumbnail, strip_metadata, thumbnail_content_type, thumbnail_path, THUMBNAIL_SIZES},
+        ws::Attachment,
+    };
+    use axum::{
+        body::{boxed, Body, BoxBody},
+        extract::{Multipart, Path, State},
+        http::{header, HeaderValue, Request, Response},
+        response::{IntoResponse, Redirect},
+        Json,
+    };
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use std::{env, path::PathBuf, sync::Arc};
+    use tokio::{fs, sync::Semaphore};
+    use tower::ServiceExt;
+    use tower_http::services::ServeFile;
+    use uuid::Uuid;
+
+    // Uploads bigger than this are refused.
+    pub const MAX_ATTACHMENT_BYTES: usize = 10 * 1024 * 1024;
+
+    // How many images may be thumbnailed at once.
+    const THUMBNAIL_WORKERS: usize = 2;
+
+    // What people may share. Anything else could be served back as something a
+    // browser would run.
+    const ALLOWED_TYPES: [&str; 6] = [
+        "image/png",
+        "image/jpeg",
+        "image/gif",
+        "image/webp",
+        "application/pdf",
+        "text/plain",
+    ];
+
+    // What `<id>.json` holds.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct StoredAttachment {
+        #[serde(flatten)]
+        attachment: Attachment,
+        // The hash of the file's contents.
+        content: String,
+    }
+
+    // Uploaded files, stored under the hex SHA-256 of their contents so the same
+    // file shared twice is only kept once, with `<hash>.<width>` its thumbnails
+    // if it's an image. Every upload gets an id of its own, and `<id>.json` keeps
+    // its `Attachment` metadata and which file it is, so sharing the same bytes
+    // under another name doesn't change what earlier links show.
+    #[derive(Debug, Clone)]
+    pub struct AttachmentStore {
+        dir: PathBuf,
+        thumbnailers: Arc<Semaphore>,
+    }
+
+    impl AttachmentStore {
+        pub fn new(dir: impl Into<PathBuf>) -> Self {
+            AttachmentStore {
+                dir: dir.into(),
+                thumbnailers: Arc::new(Semaphore::new(THUMBNAIL_WORKERS)),
+            }
+        }
+
+        // Uses `CHAT_ATTACHMENTS_DIR`, or `attachments` in the working directory.
+        pub fn from_env() -> Self {
+            AttachmentStore::new(env::var("CHAT_ATTACHMENTS_DIR").unwrap_or_else(|_| String::from("attachments")))
+        }
+
+        fn file_path(&self, id: &str) -> PathBuf {
+            self.dir.join(id)
+        }
+
+        fn metadata_path(&self, id: &str) -> PathBuf {
+            self.dir.join(format!("{id}.json"))
+        }
+
+        // Looks up what was stored under `id`. Ids come from clients, so anything
+        // that isn't an id we could have made is simply not found.
+        pub async fn get(&self, id: &str) -> Option<Attachment> {
+            self.get_stored(id).await.map(|stored| stored.attachment)
+        }
+
+        async fn get_stored(&self, id: &str) -> Option<StoredAttachment> {
+            // Ours are 32 hex digits.
+            let hex = id.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase());
+            if !hex || id.len() != 32 {
+                return None;
+            }
+
+            let metadata = fs::read(self.metadata_path(id)).await.ok()?;
+            serde_json::from_slice(&metadata).ok()
+        }
+
+        // Writes a finished upload into place: the file under its hash, unless
+        // it's already there, then the metadata under the upload's id. Both go
+        // under a temporary name first, so they're never seen half-written.
+        async fn store(&self, bytes: &[u8], stored: &StoredAttachment) -> std::io::Result<()> {
+            fs::create_dir_all(&self.dir).await?;
+
+            let file = self.file_path(&stored.content);
+            if fs::metadata(&file).await.is_err() {
+                self.write_new(&file, bytes).await?;
+            }
+            let metadata = serde_json::to_vec(stored)?;
+            self.write_new(&self.metadata_path(&stored.attachment.id), &metadata).await
+        }
+
+        async fn write_new(&self, path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+            let upload = self.dir.join(format!(".upload-{}", Uuid::new_v4()));
+            if let Err(err) = fs::write(&upload, bytes).await {
+                let _ = fs::remove_file(&upload).await;
+                return Err(err);
+            }
+            fs::rename(&upload, path).await
+        }
+
+        // Makes thumbnails for an image in the background. Until they exist,
+        // asking for one gets the original instead.
+        fn queue_thumbnails(&self, stored: StoredAttachment) {
+            let original = self.file_path(&stored.content);
+            let attachment = stored.attachment;
+            let thumbnailers = self.thumbnailers.clone();
+
+            tokio::spawn(async move {
+                let Ok(_permit) = thumbnailers.acquire_owned().await else {
+                    return;
+                };
+                let made = tokio::task::spawn_blocking(move || {
+                    THUMBNAIL_SIZES
+                        .into_iter()
+                        .try_for_each(|width| make_thumbnail(&original, &attachment.content_type, width))
+                })
+                .await;
+
+                match made {
+                    Ok(Ok(())) => (),
+                    Ok(Err(err)) => tracing::warn!("Couldn't make thumbnails for {}: {err}", attachment.id),
+                    Err(err) => tracing::warn!("Thumbnail worker failed: {err}"),
+                }
+            });
+        }
+    }
+
+    // `POST /attachments`, a form with `user` and `token` from the uploader's
+    // session before its `file`.
+    pub async fn upload_handler(
+        State(state): State<Arc<AppState>>,
+        mut multipart: Multipart,
+    ) -> Result<Json<Attachment>, ErrorBody> {
+        let bad_request = |err: String| AppError::BadRequest.with_message(err);
+
+        let (mut user, mut token) = (None, None);
+        while let Some(mut field) = multipart.next_field().await.map_err(|err| bad_request(err.to_string()))? {
+            match field.name() {
+                Some("user") => {
+                    user = Some(field.text().await.map_err(|err| bad_request(err.to_string()))?);
+                    continue;
+                }
+                Some("token") => {
+                    token = Some(field.text().await.map_err(|err| bad_request(err.to_string()))?);
+                    continue;
+                }
+                Some("file") => (),
+                _ => continue,
+            }
+
+            // Only those in the chat may fill our disk.
+            let joined = match (&user, &token) {
+                (Some(user), Some(token)) => state.presence.holds(user, token),
+                _ => false,
+            };
+            if !joined {
+                return Err(AppError::Unauthorized.with_message("Join the chat to share files."));
+            }
+
+            let content_type = field.content_type().unwrap_or_default().to_owned();
+            if !ALLOWED_TYPES.contains(&content_type.as_str()) {
+                return Err(AppError::UnsupportedMediaType
+                    .with_message(format!("Files of type {content_type:?} can't be shared.")));
+            }
+            let name = field.file_name().unwrap_or("attachment").to_owned();
+
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.chunk().await.map_err(|err| bad_request(err.to_string()))? {
+                if bytes.len() + chunk.len() > MAX_ATTACHMENT_BYTES {
+                    return Err(AppError::PayloadTooLarge
+                        .with_message(format!("Attachments can be at most {MAX_ATTACHMENT_BYTES} bytes.")));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+
+            // Whatever a camera wrote into a photo, GPS position included, goes
+            // before anyone else can download it. The hash is of what we keep.
+            let bytes = strip_metadata(&content_type, &bytes)
+                .map_err(|err| AppError::Invalid.with_message(err.to_string()))?;
+
+            let stored = StoredAttachment {
+                attachment: Attachment {
+                    id: Uuid::new_v4().simple().to_string(),
+                    name,
+                    content_type,
+                    size: bytes.len(),
+                },
+                content: hex::encode(Sha256::digest(&bytes)),
+            };
+            let store = &state.attachments;
+            store
+                .store(&bytes, &stored)
+                .await
+                .map_err(|err| {
+                    tracing::error!("Couldn't store an attachment: {err}");
+                    AppError::Internal
+                })?;
+            let attachment = stored.attachment.clone();
+            if attachment.is_image() {
+                store.queue_thumbnails(stored);
+            }
+
+            return Ok(Json(attachment));
+        }
+
+        Err(bad_request(String::from("No file was uploaded.")))
+    }
+
+    // Serves an attachment the same way `get_static_file` serves the site, so
+    // range requests work, but with the content type recorded at upload.
+    pub async fn download_handler(
+        Path(id): Path<String>,
+        State(state): State<Arc<AppState>>,
+        req: Request<Body>,
+    ) -> Result<Response<BoxBody>, ErrorBody> {
+        let store = &state.attachments;
+        let StoredAttachment { attachment, content } = store
+            .get_stored(&id)
+            .await
+            .ok_or(AppError::NotFound.with_message("No such attachment."))?;
+
+        serve_attachment(&attachment, store.file_path(&content), &attachment.content_type, req).await
+    }
+
+    pub async fn thumbnail_handler(
+        Path((id, width)): Path<(String, u32)>,
+        State(state): State<Arc<AppState>>,
+        req: Request<Body>,
+    ) -> Result<Response<BoxBody>, ErrorBody> {
+        let store = &state.attachments;
+        let StoredAttachment { attachment, content } = store
+            .get_stored(&id)
+            .await
+            .filter(|stored| stored.attachment.is_image() && THUMBNAIL_SIZES.contains(&width))
+            .ok_or(AppError::NotFound.with_message("No such thumbnail."))?;
+
+        let path = thumbnail_path(&store.file_path(&content), width);
+        if fs::metadata(&path).await.is_err() {
+            // Not made yet; the original will do.
+            return Ok(Redirect::temporary(&attachment.url()).into_response());
+        }
+
+        serve_attachment(&attachment, path, thumbnail_content_type(&attachment.content_type), req).await
+    }
+
+    async fn serve_attachment(
+        attachment: &Attachment,
+        path: PathBuf,
+        content_type: &str,
+        req: Request<Body>,
+    ) -> Result<Response<BoxBody>, ErrorBody> {
+        let mime = content_type
+            .parse()
+            .map_err(|_| {
+                tracing::error!("Attachment {} has a bad content type: {content_type:?}", attachment.id);
+                AppError::Internal
+            })?;
+
+        let mut res = match ServeFile::new_with_mime(path, &mime).oneshot(req).await {
+            Ok(res) => res.map(boxed),
+            Err(err) => {
+                tracing::error!("Couldn't serve attachment {}: {err}", attachment.id);
+                return Err(AppError::Internal.into());
+            }
+        };
+
+        // Never let the browser guess a more dangerous type, and only show images
+        // inline.
+        let headers = res.headers_mut();
+        headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        if !attachment.is_image() {
+            headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment"));
+        }
+
+        Ok(res)
+    }
+}}
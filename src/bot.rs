@@ -0,0 +1,173 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}, rooms::{Member, MAX_MESSAGE_CHARS}, ws::ServerMessage};
+    use axum::{
+        async_trait,
+        extract::{FromRequestParts, Path, Query, State},
+        headers::{authorization::Bearer, Authorization},
+        http::{request::Parts, StatusCode},
+        Json, TypedHeader,
+    };
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use std::{env, sync::Arc};
+    use uuid::Uuid;
+
+    // Bots and the tokens they authenticate with, from `CHAT_BOT_TOKENS`, e.g.
+    // `ci=s3cret,deploys=an0ther`. Only hashes of the tokens are kept.
+    #[derive(Debug, Default)]
+    pub struct BotTokens {
+        bots: Vec<(String, [u8; 32])>,
+    }
+
+    impl BotTokens {
+        pub fn from_env() -> Self {
+            let bots = env::var("CHAT_BOT_TOKENS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|entry| {
+                    let (name, token) = entry.trim().split_once('=')?;
+                    let (name, token) = (name.trim(), token.trim());
+                    (!name.is_empty() && !token.is_empty()).then(|| (name.to_owned(), Sha256::digest(token).into()))
+                })
+                .collect();
+
+            BotTokens { bots }
+        }
+
+        // The bot `token` belongs to. Comparing hashes rather than the tokens
+        // themselves means how long the comparison takes says nothing useful
+        // about the token.
+        pub fn bot(&self, token: &str) -> Option<&str> {
+            let hash: [u8; 32] = Sha256::digest(token).into();
+            self.bots
+                .iter()
+                .find(|(_, known)| *known == hash)
+                .map(|(name, _)| name.as_str())
+        }
+
+        // The bot called `name`, as rooms know it. Only its token gets a request
+        // in as it, so its name is enough to tell it apart.
+        pub fn member(&self, name: &str) -> Option<Member> {
+            self.bots.iter().any(|(bot, _)| bot == name).then(|| bot_member(name))
+        }
+    }
+
+    fn bot_member(name: &str) -> Member {
+        Member {
+            id: format!("bot:{name}"),
+            name: name.to_owned(),
+        }
+    }
+
+    // A request carrying a valid `Authorization: Bearer <token>` header.
+    pub struct Bot(pub Member);
+
+    #[async_trait]
+    impl FromRequestParts<Arc<AppState>> for Bot {
+        type Rejection = AppError;
+
+        async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+            let TypedHeader(Authorization(bearer)) =
+                TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                    .await
+                    .map_err(|_| AppError::Unauthorized)?;
+
+            state
+                .bot_tokens
+                .bot(bearer.token())
+                .map(|name| Bot(bot_member(name)))
+                .ok_or(AppError::Unauthorized)
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Room {
+        pub name: String,
+        pub topic: Option<String>,
+        pub online: usize,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct PostMessage {
+        pub msg: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Posted {
+        pub id: Uuid,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct HistoryQuery {
+        pub limit: Option<usize>,
+    }
+
+    // Bots go by the same rules as everyone else: rooms that aren't public need
+    // them to be members.
+    fn check_room(state: &AppState, bot: &Member, room: &str) -> Result<(), ErrorBody> {
+        match state.rooms.get(room) {
+            Some(room) if state.can_enter(&room, bot) => Ok(()),
+            _ => Err(AppError::NotFound.with_message(format!("There is no room called {room}."))),
+        }
+    }
+
+    // `GET /bot/rooms`
+    pub async fn list_rooms_handler(Bot(bot): Bot, State(state): State<Arc<AppState>>) -> Json<Vec<Room>> {
+        let rooms = state
+            .rooms
+            .list()
+            .into_iter()
+            .filter(|room| state.can_enter(room, &bot))
+            .map(|room| Room {
+                online: state.presence.in_room(&room.name),
+                topic: Some(room.settings.topic).filter(|topic| !topic.is_empty()),
+                name: room.name,
+            })
+            .collect();
+        Json(rooms)
+    }
+
+    // `POST /bot/rooms/:room/messages` with `{"msg": "..."}`. The message goes
+    // out like any other, from the bot's name.
+    pub async fn post_message_handler(
+        Bot(bot): Bot,
+        Path(room): Path<String>,
+        State(state): State<Arc<AppState>>,
+        Json(message): Json<PostMessage>,
+    ) -> Result<(StatusCode, Json<Posted>), ErrorBody> {
+        check_room(&state, &bot, &room)?;
+        let msg = message.msg.trim();
+        if msg.is_empty() {
+            return Err(AppError::Invalid.with_message("The message is empty."));
+        }
+        if msg.chars().count() > MAX_MESSAGE_CHARS as usize {
+            return Err(AppError::PayloadTooLarge.with_message(format!(
+                "Messages can be at most {MAX_MESSAGE_CHARS} characters."
+            )));
+        }
+
+        state
+            .check_post(&room, &bot, msg)
+            .map_err(|msg| AppError::Invalid.with_message(msg))?;
+
+        let id = Uuid::new_v4();
+        state.post(&room, &bot.name, id, msg.to_owned(), None);
+        Ok((StatusCode::CREATED, Json(Posted { id })))
+    }
+
+    // `GET /bot/rooms/:room/messages?limit=50`, oldest first.
+    pub async fn history_handler(
+        Bot(bot): Bot,
+        Path(room): Path<String>,
+        Query(query): Query<HistoryQuery>,
+        State(state): State<Arc<AppState>>,
+    ) -> Result<Json<Vec<ServerMessage>>, ErrorBody> {
+        check_room(&state, &bot, &room)?;
+
+        let mut messages = state.history.recent(&room);
+        let limit = query.limit.unwrap_or(50).min(messages.len());
+        Ok(Json(messages.split_off(messages.len() - limit)))
+    }
+}}
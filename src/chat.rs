@@ -0,0 +1,687 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{
+        attachments::AttachmentStore,
+        bot::BotTokens,
+        commands::{CommandContext, CommandRegistry, Reply},
+        csrf::Csrf,
+        codec::{compress, decode, CodecError, Encoding, Payload},
+        error_template::AppError,
+        history::History,
+        mentions::{valid_name, Mentions, NAME_RULES},
+        metrics::Metrics,
+        presence::{identity, Connection, Presence},
+        rooms::{Member, Room, RoomStore, Visibility},
+        search::SearchIndex,
+        unfurl::{find_link, Unfurler},
+        webhooks::Webhooks,
+        ws::{Attachment, ClientMessage, ServerFrame, ServerMessage, DEFAULT_ROOM},
+    };
+    use axum::{
+        extract::{
+            ws::{Message, WebSocket, WebSocketUpgrade},
+            ConnectInfo, Path, State,
+        },
+        http::{header, HeaderMap, StatusCode},
+        response::{
+            sse::{Event, KeepAlive, Sse},
+            IntoResponse,
+        },
+        Json,
+    };
+    use dashmap::DashMap;
+    use futures::{
+        channel::mpsc::{self as frames, UnboundedSender},
+        future,
+        sink::{Sink, SinkExt},
+        stream::{self, Stream, StreamExt},
+    };
+    use std::{
+        collections::HashSet,
+        convert::Infallible,
+        env,
+        net::SocketAddr,
+        sync::{Arc, OnceLock},
+    };
+    use tokio::sync::{broadcast, mpsc};
+    use uuid::Uuid;
+
+    // Who chat lines from the server itself come from.
+    const NOTICE_SENDER: &str = "Server";
+
+    // How many messages of each room new clients get replayed.
+    const HISTORY_LENGTH: usize = 500;
+
+    // When websocket frames are worth compressing. Set from the environment:
+    // `CHAT_COMPRESS_MIN_BYTES` (default 1024, `off` to disable) and
+    // `CHAT_COMPRESS_LEVEL` (0-9, default 6).
+    #[derive(Debug, Clone, Copy)]
+    pub struct CompressionConfig {
+        pub min_bytes: Option<usize>,
+        pub level: u32,
+    }
+
+    impl CompressionConfig {
+        pub fn from_env() -> Self {
+            let min_bytes = match env::var("CHAT_COMPRESS_MIN_BYTES") {
+                Ok(value) if value == "off" => None,
+                Ok(value) => value.parse().ok(),
+                Err(_) => Some(1024),
+            };
+            let level = env::var("CHAT_COMPRESS_LEVEL")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(6)
+                .min(9);
+
+            CompressionConfig { min_bytes, level }
+        }
+    }
+
+    // Our shared state
+    pub struct AppState {
+        // We require unique usernames. This tracks who is online on which connections.
+        pub presence: Presence,
+        // Channel used to send messages to all connected clients.
+        pub tx: broadcast::Sender<Arc<Outgoing>>,
+        pub history: History,
+        // Clients that can't use websockets read frames from `/events` and post
+        // theirs to `/events/:id`. This routes those posts to the right session.
+        pub event_sessions: DashMap<Uuid, UnboundedSender<ClientMessage>>,
+        pub compression: CompressionConfig,
+        pub metrics: Metrics,
+        pub attachments: AttachmentStore,
+        pub unfurler: Unfurler,
+        pub mentions: Mentions,
+        pub search: SearchIndex,
+        pub commands: CommandRegistry,
+        // Who may use admin commands and change any room, from `CHAT_ADMIN_IDS`
+        // (comma-separated identities, as `/whoami` shows them). Names can be
+        // taken by anyone, identities only by whoever holds their token.
+        pub admins: HashSet<String>,
+        pub rooms: RoomStore,
+        pub bot_tokens: BotTokens,
+        pub webhooks: Webhooks,
+        // Which origins may open websockets and call server functions.
+        pub csrf: Csrf,
+    }
+
+    impl AppState {
+        pub fn new() -> Self {
+            let (tx, _rx) = broadcast::channel(100);
+
+            AppState {
+                presence: Presence::new(),
+                tx,
+                history: History::new(HISTORY_LENGTH),
+                event_sessions: DashMap::new(),
+                compression: CompressionConfig::from_env(),
+                metrics: Metrics::default(),
+                attachments: AttachmentStore::from_env(),
+                unfurler: Unfurler::default(),
+                mentions: Mentions::new(),
+                search: SearchIndex::from_env(),
+                commands: CommandRegistry::builtin(),
+                admins: admins_from_env(),
+                rooms: RoomStore::from_env(),
+                bot_tokens: BotTokens::from_env(),
+                webhooks: Webhooks::from_env(),
+                csrf: Csrf::from_env(),
+            }
+        }
+
+        // Sends a chat message to everyone in its room and keeps it for replaying
+        // later, and for searching unless it's one of our notices.
+        pub fn publish(&self, message: ServerMessage) {
+            if message.sender != NOTICE_SENDER {
+                let search = self.search.clone();
+                let message = message.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = search.index(&message.room, &message).await {
+                        tracing::warn!("Couldn't index message {}: {err}", message.id);
+                    }
+                });
+            }
+
+            self.history.push(message.clone());
+            self.broadcast(ServerFrame::Chat(message));
+        }
+
+        // Sends `frame` to every connection, which only encode it once between
+        // them.
+        pub fn broadcast(&self, frame: ServerFrame) {
+            let _ = self.tx.send(Arc::new(Outgoing::new(frame)));
+        }
+
+        // Sends what `sender` wrote to everyone in `room`, with its mentions
+        // checked and its first link previewed.
+        pub fn post(
+            self: &Arc<Self>,
+            room: &str,
+            sender: &str,
+            id: Uuid,
+            msg: String,
+            attachment: Option<Attachment>,
+        ) {
+            self.unfurl(id, room, &msg);
+            // Those who can't read the room don't hear about what's said in it.
+            let mut mentioned = self.mentions.resolve(&msg);
+            if let Some(room) = self.rooms.get(room).filter(|room| room.settings.visibility != Visibility::Public) {
+                mentioned.retain(|member| self.can_enter(&room, member));
+            }
+            let message = ServerMessage {
+                id,
+                room: room.to_owned(),
+                sender: sender.to_owned(),
+                mentions: mentioned.iter().map(|member| member.name.clone()).collect(),
+                msg,
+                attachment,
+                preview: None,
+            };
+            self.notify_mentions(&message, &mentioned);
+            self.publish(message);
+        }
+
+        // Whether `who` may say `msg` in `room` right now, going by the room's
+        // settings. Admins aren't slowed down.
+        pub fn check_post(&self, room: &str, who: &Member, msg: &str) -> Result<(), String> {
+            self.rooms.check_post(room, &who.id, msg, self.is_room_admin(room, who))
+        }
+
+        // The server's admins can change any room; everyone else only those they
+        // are an admin of.
+        pub fn is_room_admin(&self, room: &str, who: &Member) -> bool {
+            self.admins.contains(&who.id) || self.rooms.get(room).is_some_and(|room| room.is_admin(&who.id))
+        }
+
+        // Whether `who` may read and enter `room`.
+        pub fn can_enter(&self, room: &Room, who: &Member) -> bool {
+            self.admins.contains(&who.id) || room.allows(&who.id)
+        }
+
+        // Whether `name` belongs to someone other than identity `id`, who others
+        // would take them for: the server, a bot, or an admin of some room.
+        pub fn is_reserved_name(&self, name: &str, id: Option<&str>) -> bool {
+            let someone_else = |member: &Member| member.name == name && Some(member.id.as_str()) != id;
+
+            name == NOTICE_SENDER
+                || self.bot_tokens.member(name).is_some()
+                || self.rooms.list().iter().any(|room| room.admins.iter().any(someone_else))
+        }
+
+        // Tells everyone who can see `room` how it looks now. Those in it who
+        // aren't allowed any more are sent back to the default room.
+        pub fn room_updated(&self, room: Room) {
+            for (member, connection) in self.presence.connections_in(&room.name) {
+                if !self.can_enter(&room, &member) {
+                    self.send_away(&member, &connection, &room.name);
+                }
+            }
+
+            if room.settings.visibility == Visibility::Private {
+                for member in self.presence.online_members() {
+                    if self.can_enter(&room, &member) {
+                        self.presence.send_to(&member.name, ServerFrame::RoomUpdated { room: room.clone() });
+                    }
+                }
+                // So everyone else's room lists catch up, in case it only just
+                // became private.
+                self.broadcast(ServerFrame::RoomsChanged);
+            } else {
+                self.broadcast(ServerFrame::RoomUpdated { room });
+            }
+        }
+
+        // Moves `who`'s `connection` out of `room`, which they may no longer be in.
+        fn send_away(&self, who: &Member, connection: &Connection, room: &str) {
+            let _ = connection.sender.send(ServerFrame::Notice {
+                msg: format!("You are no longer a member of #{room}."),
+            });
+            if let Some(default_room) = self.rooms.get(DEFAULT_ROOM) {
+                self.enter_room(connection.id, who, default_room, &connection.sender);
+            }
+        }
+
+        // Moves connection `id`, on which `who` is, into `room` and catches it up
+        // on what was said there.
+        fn enter_room(&self, id: Uuid, who: &Member, room: Room, direct_tx: &mpsc::UnboundedSender<ServerFrame>) {
+            self.presence.enter(id, &room.name);
+            let messages = self.history.recent(&room.name);
+            let admin = self.is_room_admin(&room.name, who);
+            let _ = direct_tx.send(ServerFrame::EnteredRoom { room, admin });
+            let _ = direct_tx.send(ServerFrame::History { messages });
+        }
+
+        // Tells all of `name`'s devices that this is their name now.
+        pub fn rename_sessions(&self, name: &str) {
+            if let Some(token) = self.presence.token(name) {
+                self.presence.send_to(
+                    name,
+                    ServerFrame::Session {
+                        name: name.to_owned(),
+                        token,
+                    },
+                );
+            }
+        }
+
+        // Tells everyone `message` mentions, on all their devices, whatever they
+        // are looking at.
+        pub fn notify_mentions(&self, message: &ServerMessage, mentioned: &[Member]) {
+            for (id, mention, unread) in self.mentions.deliver(message, mentioned) {
+                self.presence.send_to_identity(&id, ServerFrame::Mention { mention, unread });
+            }
+        }
+
+        // Looks for a preview of the first link in message `id` without holding
+        // the message up, and sends it on if there is one.
+        pub fn unfurl(self: &Arc<Self>, id: Uuid, room: &str, msg: &str) {
+            let Some(url) = find_link(msg) else {
+                return;
+            };
+
+            let state = self.clone();
+            let room = room.to_owned();
+            tokio::spawn(async move {
+                let Some(preview) = state.unfurler.unfurl(&url).await else {
+                    return;
+                };
+
+                state.history.update(id, |message| message.preview = Some(preview.clone()));
+                state.broadcast(ServerFrame::Preview { id, room, preview });
+            });
+        }
+    }
+
+    fn admins_from_env() -> HashSet<String> {
+        if env::var_os("CHAT_ADMINS").is_some() {
+            tracing::error!("CHAT_ADMINS lists names, which anyone can take, and is ignored. List identities in CHAT_ADMIN_IDS instead.");
+        }
+
+        env::var("CHAT_ADMIN_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    impl Default for AppState {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    pub async fn websocket_handler(
+        ws: WebSocketUpgrade,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        // Browsers let any page open a websocket to us, with our users'
+        // cookies, so we have to check whose page it is.
+        if !state.csrf.origins.allows(&headers) {
+            tracing::warn!("Refused a websocket from {:?}", headers.get(header::ORIGIN));
+            return AppError::Forbidden.into_response();
+        }
+
+        ws.protocols(Encoding::PREFERENCE.map(Encoding::protocol))
+            .on_upgrade(move |socket| {
+                // Clients that didn't ask for an encoding get JSON.
+                let encoding = socket
+                    .protocol()
+                    .and_then(|protocol| protocol.to_str().ok())
+                    .and_then(Encoding::from_protocol)
+                    .unwrap_or(Encoding::Json);
+
+                websocket(socket, encoding, addr, state)
+            })
+            .into_response()
+    }
+
+    // A frame on its way out to one or many connections. Each encoding of it is
+    // made, and compressed if that pays off, by whichever connection needs it
+    // first; the others get the same bytes.
+    #[derive(Debug)]
+    pub struct Outgoing {
+        pub frame: ServerFrame,
+        // By `Encoding::PREFERENCE` index. `None` if the frame couldn't be
+        // encoded, which has already been logged.
+        encoded: [OnceLock<Option<Payload>>; Encoding::PREFERENCE.len()],
+    }
+
+    impl Outgoing {
+        pub fn new(frame: ServerFrame) -> Self {
+            Outgoing {
+                frame,
+                encoded: Default::default(),
+            }
+        }
+
+        fn encoded(&self, state: &AppState, encoding: Encoding) -> Option<Payload> {
+            let index = Encoding::PREFERENCE.iter().position(|known| *known == encoding)?;
+            self.encoded[index]
+                .get_or_init(|| match encode_frame(state, encoding, &self.frame) {
+                    Ok(payload) => Some(payload),
+                    Err(err) => {
+                        tracing::error!("Couldn't encode a frame: {err}");
+                        None
+                    }
+                })
+                .clone()
+        }
+    }
+
+    // Encodes `frame`, compressing it if it is big enough for that to pay off.
+    fn encode_frame(state: &AppState, encoding: Encoding, frame: &ServerFrame) -> Result<Payload, CodecError> {
+        let payload = encoding.encode(frame)?;
+        match state.compression.min_bytes {
+            Some(min_bytes) if payload.len() >= min_bytes => {
+                let compressed = compress(&payload, state.compression.level)?;
+                if compressed.len() >= payload.len() {
+                    return Ok(payload);
+                }
+
+                state.metrics.record_compression(payload.len(), compressed.len());
+                Ok(compressed)
+            }
+            _ => Ok(payload),
+        }
+    }
+
+    // Frames travel over the websocket in the encoding negotiated on upgrade.
+    async fn websocket(stream: WebSocket, encoding: Encoding, addr: SocketAddr, state: Arc<AppState>) {
+        // By splitting, we can send and receive at the same time.
+        let (sender, receiver) = stream.split();
+
+        let send_state = state.clone();
+        let sender = sender.with(move |frame: Arc<Outgoing>| {
+            let message = match frame.encoded(&send_state, encoding) {
+                Some(Payload::Text(text)) => Ok(Message::Text(text)),
+                Some(Payload::Binary(bytes)) => Ok(Message::Binary(bytes)),
+                None => Err(axum::Error::new(CodecError::UnknownEncoding)),
+            };
+            future::ready(message)
+        });
+        let receiver = receiver
+            .take_while(|message| future::ready(message.is_ok()))
+            .filter_map(|message| async move {
+                let payload = match message {
+                    Ok(Message::Text(text)) => Payload::Text(text),
+                    Ok(Message::Binary(bytes)) => Payload::Binary(bytes),
+                    _ => return None,
+                };
+                match decode(&payload) {
+                    Ok(frame) => Some(frame),
+                    Err(err) => {
+                        tracing::debug!("Unreadable frame: {err}");
+                        None
+                    }
+                }
+            })
+            .boxed();
+
+        chat_session(state, addr, receiver, sender).await;
+    }
+
+    // The fallback transport: server frames go out as server-sent events, and the
+    // first event tells the client which id to post its own frames to.
+    pub async fn events_handler(
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        State(state): State<Arc<AppState>>,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        let id = Uuid::new_v4();
+        let (in_tx, in_rx) = frames::unbounded();
+        let (out_tx, out_rx) = frames::unbounded();
+
+        state.event_sessions.insert(id, in_tx);
+        tokio::spawn(async move {
+            chat_session(state.clone(), addr, in_rx, out_tx).await;
+            state.event_sessions.remove(&id);
+        });
+
+        let hello = Event::default().event("connection").data(id.to_string());
+        let events = out_rx.filter_map(|frame: Arc<Outgoing>| async move {
+            Event::default().json_data(&frame.frame).ok()
+        });
+
+        Sse::new(stream::once(future::ready(hello)).chain(events).map(Ok))
+            .keep_alive(KeepAlive::default())
+    }
+
+    pub async fn post_event_handler(
+        Path(id): Path<Uuid>,
+        State(state): State<Arc<AppState>>,
+        Json(frame): Json<ClientMessage>,
+    ) -> StatusCode {
+        match state.event_sessions.get(&id) {
+            Some(session) if session.unbounded_send(frame).is_ok() => StatusCode::NO_CONTENT,
+            _ => StatusCode::NOT_FOUND,
+        }
+    }
+
+    // This function deals with a single connected client / user, whatever the
+    // transport, for which we will spawn two independent tasks (for receiving /
+    // sending chat messages).
+    async fn chat_session<R, S>(state: Arc<AppState>, addr: SocketAddr, mut receiver: R, mut sender: S)
+    where
+        R: Stream<Item = ClientMessage> + Unpin + Send + 'static,
+        S: Sink<Arc<Outgoing>> + Unpin + Send + 'static,
+    {
+        // Frames addressed to this connection alone, rather than to everyone.
+        let (direct_tx, mut direct_rx) = mpsc::unbounded_channel();
+        let connection = Connection::new(addr, direct_tx.clone());
+        let connection_id = connection.id;
+
+        // Loop until the client tells us who they are.
+        let (username, session) = loop {
+            let Some(message) = receiver.next().await else {
+                return;
+            };
+            let ClientMessage::Join { name, token } = message else {
+                continue;
+            };
+
+            if !valid_name(&name) {
+                let error = ServerFrame::Error {
+                    msg: NAME_RULES.to_owned(),
+                };
+                let _ = sender.send(Arc::new(Outgoing::new(error))).await;
+
+                return;
+            }
+
+            // Nobody gets to pass for an admin or a bot by their name alone.
+            if state.is_reserved_name(&name, token.as_deref().map(identity).as_deref()) {
+                let error = ServerFrame::Error {
+                    msg: format!("{name} is reserved."),
+                };
+                let _ = sender.send(Arc::new(Outgoing::new(error))).await;
+
+                return;
+            }
+
+            // If the username is free, or the client proved it is another device of
+            // the user holding it, we're in. Otherwise only tell our client.
+            match state.presence.join(&name, token.as_deref(), connection) {
+                Some(session) => break (name, session),
+                None => {
+                    let error = ServerFrame::Error {
+                        msg: AppError::Conflict.to_string(),
+                    };
+                    let _ = sender.send(Arc::new(Outgoing::new(error))).await;
+
+                    return;
+                }
+            }
+        };
+
+        // We subscribe *before* sending the "joined" message, so that we will also
+        // display it to our client.
+        let mut rx = state.tx.subscribe();
+
+        let _ = direct_tx.send(ServerFrame::Session {
+            name: username.clone(),
+            token: session.token,
+        });
+        let me = Member {
+            id: session.id,
+            name: username.clone(),
+        };
+        if let Some(room) = state.rooms.get(DEFAULT_ROOM) {
+            state.enter_room(connection_id, &me, room, &direct_tx);
+        }
+        if let Some(id) = session.last_read {
+            let _ = direct_tx.send(ServerFrame::Read { id });
+        }
+        state.mentions.register(&me);
+        let (mentions, unread) = state.mentions.inbox(&me.id);
+        if !mentions.is_empty() {
+            let _ = direct_tx.send(ServerFrame::Mentions { mentions, unread });
+        }
+
+        // Now send the "joined" message to all subscribers, unless this is just
+        // another tab of someone who is already here.
+        if session.first {
+            let msg = format!("{username} joined.");
+            tracing::debug!("{msg}");
+            state.publish(server_notice(DEFAULT_ROOM, msg));
+        }
+
+        // Spawn the first task that will receive broadcast messages and send them
+        // to our client.
+        let send_state = state.clone();
+        let mut send_task = tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    Ok(frame) = rx.recv() => frame,
+                    Some(frame) = direct_rx.recv() => Arc::new(Outgoing::new(frame)),
+                    else => break,
+                };
+
+                // What is said in other rooms isn't for us.
+                if let Some(room) = frame.frame.room() {
+                    if send_state.presence.room_of(connection_id).as_deref() != Some(room) {
+                        continue;
+                    }
+                }
+
+                // In any transport error, break loop.
+                if sender.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Clone things we want to pass (move) to the receiving task.
+        let recv_state = state.clone();
+
+        // Spawn a task that takes messages from the client, stamps them with the
+        // user name, and sends them to all broadcast subscribers.
+        let mut recv_task = tokio::spawn(async move {
+            while let Some(message) = receiver.next().await {
+                // Whoever this is now; `/nick` may have changed their name.
+                let Some(me) = recv_state.presence.member_of(connection_id) else {
+                    break;
+                };
+                let name = me.name.as_str();
+                let room = recv_state
+                    .presence
+                    .room_of(connection_id)
+                    .unwrap_or_else(|| DEFAULT_ROOM.to_owned());
+
+                match message {
+                    ClientMessage::Chat { id, msg, attachment } => {
+                        if let Err(msg) = recv_state.check_post(&room, &me, &msg) {
+                            let _ = direct_tx.send(ServerFrame::Rejected { id, msg });
+                            continue;
+                        }
+
+                        // Only pass on attachments that were really uploaded, with
+                        // the details we recorded rather than the client's.
+                        let attachment = match attachment {
+                            Some(attachment) => recv_state.attachments.get(&attachment.id).await,
+                            None => None,
+                        };
+
+                        recv_state.post(&room, name, id, msg, attachment);
+                    }
+                    ClientMessage::Command { line } => {
+                        let context = CommandContext {
+                            state: &recv_state,
+                            name,
+                            id: &me.id,
+                            room: &room,
+                            connection: connection_id,
+                        };
+                        match recv_state.commands.run(&context, &line) {
+                            Reply::Private(msg) => {
+                                let _ = direct_tx.send(ServerFrame::Notice { msg });
+                            }
+                            Reply::Say(msg) => match recv_state.check_post(&room, &me, &msg) {
+                                Ok(()) => recv_state.post(&room, name, Uuid::new_v4(), msg, None),
+                                Err(msg) => {
+                                    let _ = direct_tx.send(ServerFrame::Notice { msg });
+                                }
+                            },
+                            Reply::Announce(msg) => recv_state.publish(server_notice(&room, msg)),
+                            Reply::Nothing => (),
+                        }
+                    }
+                    // Only those allowed in get to hear what's said there. Private
+                    // rooms don't let on that they exist.
+                    ClientMessage::JoinRoom { room: wanted } => {
+                        let msg = match recv_state.rooms.get(&wanted) {
+                            Some(room) if recv_state.can_enter(&room, &me) => {
+                                recv_state.enter_room(connection_id, &me, room, &direct_tx);
+                                continue;
+                            }
+                            Some(room) if room.is_listed() => format!("#{wanted} is invite only."),
+                            _ => format!("There is no room called #{wanted}."),
+                        };
+                        let _ = direct_tx.send(ServerFrame::Notice { msg });
+                    }
+                    ClientMessage::Read { id } => {
+                        recv_state.presence.mark_read(name, connection_id, id);
+                    }
+                    ClientMessage::ReadMentions => {
+                        recv_state.mentions.mark_read(&me.id);
+                        let (mentions, unread) = recv_state.mentions.inbox(&me.id);
+                        recv_state.presence.send_to_identity(&me.id, ServerFrame::Mentions { mentions, unread });
+                    }
+                    // We already know who this is.
+                    ClientMessage::Join { .. } => (),
+                }
+            }
+        });
+
+        // If any one of the tasks run to completion, we abort the other.
+        tokio::select! {
+            _ = (&mut send_task) => recv_task.abort(),
+            _ = (&mut recv_task) => send_task.abort(),
+        };
+
+        // Remove this connection. Once the user's last one is gone the name is free
+        // again and we send "user left" (similar to "joined" above).
+        if let Some(username) = state.presence.leave(connection_id) {
+            let msg = format!("{username} left.");
+            tracing::debug!("{msg}");
+            state.publish(server_notice(DEFAULT_ROOM, msg));
+        }
+    }
+
+    // A chat line from the server itself, e.g. someone joining.
+    fn server_notice(room: &str, msg: String) -> ServerMessage {
+        ServerMessage {
+            id: Uuid::new_v4(),
+            room: room.to_owned(),
+            sender: String::from(NOTICE_SENDER),
+            msg,
+            attachment: None,
+            preview: None,
+            mentions: Vec::new(),
+        }
+    }
+}}
@@ -0,0 +1,182 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{Read, Write};
+use thiserror::Error;
+
+// First byte of a compressed binary message. MessagePack never uses it, so it
+// can't be mistaken for a plain frame. The second byte says how the frame was
+// encoded, and the rest is that encoding, deflated.
+const COMPRESSED: u8 = 0xc1;
+const COMPRESSED_JSON: u8 = b'j';
+const COMPRESSED_MESSAGEPACK: u8 = b'm';
+
+// Anything that inflates to more than this is refused rather than buffered.
+const MAX_INFLATED_BYTES: u64 = 16 * 1024 * 1024;
+
+// How frames are written on the websocket. The client lists the encodings it
+// understands in the `Sec-WebSocket-Protocol` header and the server picks one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    // Smaller frames for clients on slow or metered connections.
+    MessagePack,
+}
+
+// A frame ready to go out as either a text or a binary message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("messagepack: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("messagepack: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+    #[error("deflate: {0}")]
+    Deflate(#[from] std::io::Error),
+    #[error("compressed frame is larger than {MAX_INFLATED_BYTES} bytes")]
+    TooLarge,
+    #[error("compressed frame in an unknown encoding")]
+    UnknownEncoding,
+}
+
+impl Payload {
+    pub fn len(&self) -> usize {
+        match self {
+            Payload::Text(text) => text.len(),
+            Payload::Binary(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Encoding {
+    // In the order the server prefers them.
+    pub const PREFERENCE: [Encoding; 2] = [Encoding::MessagePack, Encoding::Json];
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Encoding::Json => "chat.json",
+            Encoding::MessagePack => "chat.msgpack",
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        Encoding::PREFERENCE
+            .into_iter()
+            .find(|encoding| encoding.protocol() == protocol)
+    }
+
+    pub fn encode<T: Serialize>(self, frame: &T) -> Result<Payload, CodecError> {
+        match self {
+            Encoding::Json => Ok(Payload::Text(serde_json::to_string(frame)?)),
+            // Field names are kept so that tagged enums read back the same way.
+            Encoding::MessagePack => Ok(Payload::Binary(rmp_serde::to_vec_named(frame)?)),
+        }
+    }
+}
+
+// Compresses an encoded frame into a binary message, keeping its encoding.
+pub fn compress(payload: &Payload, level: u32) -> Result<Payload, CodecError> {
+    let (format, bytes) = match payload {
+        Payload::Text(text) => (COMPRESSED_JSON, text.as_bytes()),
+        Payload::Binary(bytes) => (COMPRESSED_MESSAGEPACK, bytes.as_slice()),
+    };
+    let mut encoder = DeflateEncoder::new(vec![COMPRESSED, format], Compression::new(level));
+    encoder.write_all(bytes)?;
+
+    Ok(Payload::Binary(encoder.finish()?))
+}
+
+// Text messages are always JSON and plain binary ones MessagePack, whatever was
+// negotiated, so either side can read what the other sends.
+pub fn decode<T: DeserializeOwned>(payload: &Payload) -> Result<T, CodecError> {
+    match payload {
+        Payload::Text(text) => Ok(serde_json::from_str(text)?),
+        Payload::Binary(bytes) => match bytes.as_slice() {
+            [COMPRESSED, format, deflated @ ..] => {
+                let mut inflated = Vec::new();
+                DeflateDecoder::new(deflated)
+                    .take(MAX_INFLATED_BYTES + 1)
+                    .read_to_end(&mut inflated)?;
+                if inflated.len() as u64 > MAX_INFLATED_BYTES {
+                    return Err(CodecError::TooLarge);
+                }
+
+                match *format {
+                    COMPRESSED_JSON => Ok(serde_json::from_slice(&inflated)?),
+                    COMPRESSED_MESSAGEPACK => Ok(rmp_serde::from_slice(&inflated)?),
+                    _ => Err(CodecError::UnknownEncoding),
+                }
+            }
+            _ => Ok(rmp_serde::from_slice(bytes)?),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Frame {
+        Chat { msg: String },
+    }
+
+    fn chat(msg: &str) -> Frame {
+        Frame::Chat { msg: msg.to_owned() }
+    }
+
+    #[test]
+    fn each_encoding_reads_back() {
+        for encoding in Encoding::PREFERENCE {
+            let payload = encoding.encode(&chat("hi")).unwrap();
+            assert_eq!(decode::<Frame>(&payload).unwrap(), chat("hi"));
+        }
+        assert!(matches!(Encoding::Json.encode(&chat("hi")).unwrap(), Payload::Text(_)));
+        assert!(matches!(Encoding::MessagePack.encode(&chat("hi")).unwrap(), Payload::Binary(_)));
+    }
+
+    #[test]
+    fn compressed_frames_keep_their_encoding() {
+        let msg = "hello ".repeat(1000);
+        for encoding in Encoding::PREFERENCE {
+            let payload = encoding.encode(&chat(&msg)).unwrap();
+            let compressed = compress(&payload, 6).unwrap();
+            assert!(compressed.len() < payload.len());
+            assert_eq!(decode::<Frame>(&compressed).unwrap(), chat(&msg));
+        }
+
+        let Payload::Binary(json) = compress(&Encoding::Json.encode(&chat(&msg)).unwrap(), 6).unwrap() else {
+            panic!("compressed frames are binary");
+        };
+        assert_eq!(&json[..2], &[COMPRESSED, COMPRESSED_JSON]);
+    }
+
+    #[test]
+    fn refuses_frames_that_inflate_too_far() {
+        let bomb = Payload::Text(" ".repeat(MAX_INFLATED_BYTES as usize + 1));
+        let compressed = compress(&bomb, 9).unwrap();
+        assert!(compressed.len() < 64 * 1024);
+        assert!(matches!(decode::<Frame>(&compressed), Err(CodecError::TooLarge)));
+    }
+
+    #[test]
+    fn refuses_unknown_compressed_encodings() {
+        let Payload::Binary(mut bytes) = compress(&Payload::Text(String::from("{}")), 6).unwrap() else {
+            panic!("compressed frames are binary");
+        };
+        bytes[1] = b'x';
+        assert!(matches!(decode::<Frame>(&Payload::Binary(bytes)), Err(CodecError::UnknownEncoding)));
+    }
+}
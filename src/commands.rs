@@ -0,0 +1,409 @@
+use cfg_if::cfg_if;
+use leptos::*;
+use serde::{Deserialize, Serialize};
+
+// What the client needs to suggest a command.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandInfo {
+    pub name: String,
+    pub usage: String,
+    pub description: String,
+}
+
+// The commands this server understands, for autocompletion.
+#[server(ListCommands, "/api")]
+pub async fn list_commands() -> Result<Vec<CommandInfo>, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+
+    Ok(state.commands.list())
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{
+        chat::AppState,
+        mentions::{valid_name, NAME_RULES},
+        rooms::{Member, MAX_TOPIC_CHARS},
+    };
+    use rand::Rng;
+    use std::{collections::BTreeMap, sync::Arc};
+    use uuid::Uuid;
+
+    // Who may run a command.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Permission {
+        Anyone,
+        // Admins of the room it's run in, and identities listed in
+        // `CHAT_ADMIN_IDS`.
+        Admin,
+    }
+
+    // What running a command comes to.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Reply {
+        // Only the connection that ran it sees this.
+        Private(String),
+        // Goes out as a chat message from whoever ran it.
+        Say(String),
+        // Goes out as a notice from the server.
+        Announce(String),
+        Nothing,
+    }
+
+    // Who ran a command, and from where.
+    pub struct CommandContext<'a> {
+        pub state: &'a Arc<AppState>,
+        pub name: &'a str,
+        // Their identity, which rooms go by.
+        pub id: &'a str,
+        pub room: &'a str,
+        pub connection: Uuid,
+    }
+
+    impl CommandContext<'_> {
+        // Whoever ran the command, as rooms know them.
+        pub fn member(&self) -> Member {
+            Member {
+                id: self.id.to_owned(),
+                name: self.name.to_owned(),
+            }
+        }
+    }
+
+    // A `/command`. `args` is everything after its name, trimmed.
+    pub trait Command: Send + Sync {
+        fn name(&self) -> &'static str;
+        // How to call it, e.g. `/nick <name>`.
+        fn usage(&self) -> &'static str;
+        fn description(&self) -> &'static str;
+        fn permission(&self) -> Permission {
+            Permission::Anyone
+        }
+        fn run(&self, context: &CommandContext, args: &str) -> Reply;
+    }
+
+    // The commands available to everyone, by name.
+    #[derive(Default)]
+    pub struct CommandRegistry {
+        commands: BTreeMap<&'static str, Box<dyn Command>>,
+    }
+
+    impl CommandRegistry {
+        pub fn new() -> Self {
+            CommandRegistry::default()
+        }
+
+        // The commands every server has.
+        pub fn builtin() -> Self {
+            let mut registry = CommandRegistry::new();
+            registry.register(Help);
+            registry.register(Me);
+            registry.register(Nick);
+            registry.register(WhoAmI);
+            registry.register(Topic);
+            registry.register(Roll);
+            registry
+        }
+
+        // Adds `command`, replacing any other of the same name.
+        pub fn register(&mut self, command: impl Command + 'static) {
+            self.commands.insert(command.name(), Box::new(command));
+        }
+
+        pub fn list(&self) -> Vec<CommandInfo> {
+            self.commands
+                .values()
+                .map(|command| CommandInfo {
+                    name: command.name().to_owned(),
+                    usage: command.usage().to_owned(),
+                    description: command.description().to_owned(),
+                })
+                .collect()
+        }
+
+        // Runs `line`, which starts with a `/`.
+        pub fn run(&self, context: &CommandContext, line: &str) -> Reply {
+            let line = line.trim_start().trim_start_matches('/');
+            let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+            let Some(command) = self.commands.get(name) else {
+                return Reply::Private(format!("There is no /{name} command. Try /help."));
+            };
+            if command.permission() == Permission::Admin && !context.state.is_room_admin(context.room, &context.member()) {
+                return Reply::Private(format!("Only admins of #{} can use /{name}.", context.room));
+            }
+
+            command.run(context, args.trim())
+        }
+    }
+
+    struct Help;
+
+    impl Command for Help {
+        fn name(&self) -> &'static str {
+            "help"
+        }
+
+        fn usage(&self) -> &'static str {
+            "/help"
+        }
+
+        fn description(&self) -> &'static str {
+            "Lists the commands."
+        }
+
+        fn run(&self, context: &CommandContext, _args: &str) -> Reply {
+            let lines: Vec<String> = context
+                .state
+                .commands
+                .list()
+                .into_iter()
+                .map(|command| format!("`{}` {}", command.usage, command.description))
+                .collect();
+            Reply::Private(lines.join("\n\n"))
+        }
+    }
+
+    struct Me;
+
+    impl Command for Me {
+        fn name(&self) -> &'static str {
+            "me"
+        }
+
+        fn usage(&self) -> &'static str {
+            "/me <action>"
+        }
+
+        fn description(&self) -> &'static str {
+            "Says what you are doing."
+        }
+
+        fn run(&self, context: &CommandContext, args: &str) -> Reply {
+            if args.is_empty() {
+                return Reply::Private(format!("Usage: `{}`", self.usage()));
+            }
+            Reply::Say(format!("*{} {args}*", context.name))
+        }
+    }
+
+    struct Nick;
+
+    impl Command for Nick {
+        fn name(&self) -> &'static str {
+            "nick"
+        }
+
+        fn usage(&self) -> &'static str {
+            "/nick <name>"
+        }
+
+        fn description(&self) -> &'static str {
+            "Changes your name."
+        }
+
+        fn run(&self, context: &CommandContext, args: &str) -> Reply {
+            let state = context.state;
+            let old = context.name;
+            if args.is_empty() {
+                return Reply::Private(format!("Usage: `{}`", self.usage()));
+            }
+            if !valid_name(args) {
+                return Reply::Private(NAME_RULES.to_owned());
+            }
+            if state.is_reserved_name(args, Some(context.id)) {
+                return Reply::Private(format!("{args} is reserved."));
+            }
+            if let Err(err) = state.presence.rename(old, args) {
+                return Reply::Private(err.to_string());
+            }
+
+            state.mentions.register(&Member {
+                id: context.id.to_owned(),
+                name: args.to_owned(),
+            });
+            state.rename_sessions(args);
+            Reply::Announce(format!("{old} is now {args}."))
+        }
+    }
+
+    struct WhoAmI;
+
+    impl Command for WhoAmI {
+        fn name(&self) -> &'static str {
+            "whoami"
+        }
+
+        fn usage(&self) -> &'static str {
+            "/whoami"
+        }
+
+        fn description(&self) -> &'static str {
+            "Shows the identity rooms and admins know you by."
+        }
+
+        fn run(&self, context: &CommandContext, _args: &str) -> Reply {
+            Reply::Private(format!(
+                "You are {}, with identity `{}`. It stays yours, whatever your name, for as long as this browser keeps its session.",
+                context.name, context.id
+            ))
+        }
+    }
+
+    struct Topic;
+
+    impl Command for Topic {
+        fn name(&self) -> &'static str {
+            "topic"
+        }
+
+        fn usage(&self) -> &'static str {
+            "/topic <topic>"
+        }
+
+        fn description(&self) -> &'static str {
+            "Sets what the room is about."
+        }
+
+        fn permission(&self) -> Permission {
+            Permission::Admin
+        }
+
+        fn run(&self, context: &CommandContext, args: &str) -> Reply {
+            if args.chars().count() > MAX_TOPIC_CHARS {
+                return Reply::Private(format!("Topics can be at most {MAX_TOPIC_CHARS} characters."));
+            }
+            let Ok(room) = context.state.rooms.update(context.room, |room| room.settings.topic = args.to_owned()) else {
+                return Reply::Nothing;
+            };
+            context.state.room_updated(room);
+            if args.is_empty() {
+                Reply::Announce(format!("{} cleared the topic.", context.name))
+            } else {
+                Reply::Announce(format!("{} set the topic to: {args}", context.name))
+            }
+        }
+    }
+
+    struct Roll;
+
+    impl Roll {
+        // `NdM`, `dM` or `M`, for N dice of M sides. One six-sided die by default.
+        fn parse(args: &str) -> Option<(u32, u32)> {
+            if args.is_empty() {
+                return Some((1, 6));
+            }
+            let (count, sides) = match args.split_once(['d', 'D']) {
+                Some(("", sides)) => (1, sides.parse().ok()?),
+                Some((count, sides)) => (count.parse().ok()?, sides.parse().ok()?),
+                None => (1, args.parse().ok()?),
+            };
+
+            ((1..=20).contains(&count) && (2..=1000).contains(&sides)).then_some((count, sides))
+        }
+    }
+
+    impl Command for Roll {
+        fn name(&self) -> &'static str {
+            "roll"
+        }
+
+        fn usage(&self) -> &'static str {
+            "/roll [NdM]"
+        }
+
+        fn description(&self) -> &'static str {
+            "Rolls up to 20 dice, six-sided unless you say otherwise."
+        }
+
+        fn run(&self, context: &CommandContext, args: &str) -> Reply {
+            let Some((count, sides)) = Roll::parse(args) else {
+                return Reply::Private(format!("Usage: `{}`, e.g. `/roll 2d6`", self.usage()));
+            };
+
+            let mut rng = rand::thread_rng();
+            let rolls: Vec<u32> = (0..count).map(|_| rng.gen_range(1..=sides)).collect();
+            let total: u32 = rolls.iter().sum();
+            let rolls = rolls.iter().map(u32::to_string).collect::<Vec<_>>().join(", ");
+
+            Reply::Announce(format!("{} rolled {count}d{sides}: {rolls} (total **{total}**)", context.name))
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use crate::{presence::Connection, ws::DEFAULT_ROOM};
+    use std::{
+        env,
+        net::{Ipv4Addr, SocketAddr},
+    };
+    use tokio::sync::mpsc;
+
+    fn state() -> Arc<AppState> {
+        env::set_var("CHAT_DATABASE", ":memory:");
+        Arc::new(AppState::new())
+    }
+
+    fn connection() -> Connection {
+        Connection::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 3000)), mpsc::unbounded_channel().0)
+    }
+
+    // Joins `name` and runs `line` as them.
+    fn run(state: &Arc<AppState>, name: &str, line: &str) -> Reply {
+        let token = state.presence.token(name);
+        let session = state.presence.join(name, token.as_deref(), connection()).unwrap();
+        let context = CommandContext {
+            state,
+            name,
+            id: &session.id,
+            room: DEFAULT_ROOM,
+            connection: Uuid::new_v4(),
+        };
+        state.commands.run(&context, line)
+    }
+
+    #[test]
+    fn rolls() {
+        assert_eq!(Roll::parse(""), Some((1, 6)));
+        assert_eq!(Roll::parse("2d20"), Some((2, 20)));
+        assert_eq!(Roll::parse("d8"), Some((1, 8)));
+        assert_eq!(Roll::parse("12"), Some((1, 12)));
+        assert_eq!(Roll::parse("21d6"), None);
+        assert_eq!(Roll::parse("1d1"), None);
+        assert_eq!(Roll::parse("lots"), None);
+    }
+
+    #[test]
+    fn names_of_room_admins_are_reserved() {
+        let state = state();
+        let first = connection();
+        let first_id = first.id;
+        let alice = Member {
+            id: state.presence.join("alice", None, first).unwrap().id,
+            name: "alice".to_owned(),
+        };
+        state.rooms.create("ops", alice.clone()).unwrap();
+        state.presence.leave(first_id);
+
+        assert_eq!(run(&state, "mallory", "/nick alice"), Reply::Private("alice is reserved.".to_owned()));
+        assert_eq!(run(&state, "mallory", "/nick Server"), Reply::Private("Server is reserved.".to_owned()));
+        assert!(state.is_reserved_name("alice", None));
+        assert!(!state.is_reserved_name("alice", Some(&alice.id)));
+        assert_eq!(run(&state, "mallory", "/nick mal"), Reply::Announce("mallory is now mal.".to_owned()));
+    }
+
+    #[test]
+    fn admin_commands_need_an_admin() {
+        let state = state();
+        assert_eq!(
+            run(&state, "mallory", "/topic mine now"),
+            Reply::Private(format!("Only admins of #{DEFAULT_ROOM} can use /topic."))
+        );
+        assert!(matches!(run(&state, "mallory", "/whoami"), Reply::Private(msg) if msg.contains("identity")));
+    }
+}
@@ -0,0 +1,248 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}, security::CSP_REPORT_PATH};
+    use axum::{
+        extract::State,
+        headers::{Cookie, HeaderMapExt},
+        http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+        middleware::Next,
+        response::{IntoResponse, Response},
+    };
+    use hmac::{Hmac, Mac};
+    use leptos::ServerFnError;
+    use rand::RngCore;
+    use sha2::Sha256;
+    use std::{env, sync::Arc};
+    use url::Url;
+
+    // Holds the token that proves a request came from one of our own pages.
+    const COOKIE: &str = "chat_csrf";
+
+    // Where server functions are called.
+    const SERVER_FN_PREFIX: &str = "/api/";
+
+    // Where bots call us, with a token of their own rather than a cookie.
+    const BOT_PREFIX: &str = "/bot/";
+
+    // Whether a request could change something on a user's behalf, and so has
+    // to come from one of our pages. Bots don't browse other sites, and
+    // browsers send CSP reports without cookies.
+    fn needs_cookie(method: &Method, path: &str) -> bool {
+        !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            && !path.starts_with(BOT_PREFIX)
+            && path != CSP_REPORT_PATH
+    }
+
+    // Which sites' pages may talk to us from a browser, from
+    // `CHAT_ALLOWED_ORIGINS` (comma-separated, like `https://chat.example.com`).
+    // Without it, only pages from the host the request was sent to may.
+    #[derive(Debug, Clone, Default)]
+    pub struct AllowedOrigins {
+        origins: Vec<String>,
+    }
+
+    impl AllowedOrigins {
+        pub fn new(origins: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+            let origins = origins
+                .into_iter()
+                .filter_map(|origin| match Url::parse(origin.as_ref()) {
+                    Ok(url) => Some(url.origin().ascii_serialization()),
+                    Err(err) => {
+                        tracing::error!("Ignoring allowed origin {:?}: {err}", origin.as_ref());
+                        None
+                    }
+                })
+                .collect();
+            AllowedOrigins { origins }
+        }
+
+        pub fn from_env() -> Self {
+            let origins = env::var("CHAT_ALLOWED_ORIGINS").unwrap_or_default();
+            AllowedOrigins::new(origins.split(',').map(str::trim).filter(|origin| !origin.is_empty()))
+        }
+
+        // Browsers always say where a websocket or a cross-site POST comes from.
+        // Requests that don't aren't from a browser, so there's nobody whose
+        // credentials could be abused.
+        pub fn allows(&self, headers: &HeaderMap) -> bool {
+            let Some(origin) = headers.get(header::ORIGIN) else {
+                return true;
+            };
+            let Some(origin) = origin.to_str().ok().and_then(|origin| Url::parse(origin).ok()) else {
+                return false;
+            };
+
+            if !self.origins.is_empty() {
+                return self.origins.contains(&origin.origin().ascii_serialization());
+            }
+            let Some(host) = headers.get(header::HOST).and_then(|host| host.to_str().ok()) else {
+                return false;
+            };
+            let authority = match (origin.host_str(), origin.port()) {
+                (Some(name), Some(port)) => format!("{name}:{port}"),
+                (Some(name), None) => name.to_owned(),
+                (None, _) => return false,
+            };
+            authority.eq_ignore_ascii_case(host)
+        }
+    }
+
+    // Protects server functions, uploads and everything else that changes
+    // something from being called by other sites' pages with our users'
+    // credentials. Our pages get a signed `SameSite=Strict` cookie, which
+    // browsers leave out of requests other sites make, and those requests have
+    // to bring it back from an allowed origin.
+    pub struct Csrf {
+        pub origins: AllowedOrigins,
+        key: Vec<u8>,
+    }
+
+    impl Csrf {
+        // Signs with `CHAT_CSRF_SECRET`, or a key of our own that changes with
+        // every restart. Pages loaded before then get a new cookie on their
+        // next request.
+        pub fn from_env() -> Self {
+            let key = env::var("CHAT_CSRF_SECRET").map(String::into_bytes).unwrap_or_else(|_| {
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            });
+            Csrf { origins: AllowedOrigins::from_env(), key }
+        }
+
+        fn mac(&self) -> Hmac<Sha256> {
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size")
+        }
+
+        // `<random>.<signature>`, both hex.
+        fn new_token(&self) -> String {
+            let mut random = [0; 16];
+            rand::thread_rng().fill_bytes(&mut random);
+            let mut mac = self.mac();
+            mac.update(&random);
+            format!("{}.{}", hex::encode(random), hex::encode(mac.finalize().into_bytes()))
+        }
+
+        fn is_valid(&self, token: &str) -> bool {
+            let Some((random, signature)) = token.split_once('.') else {
+                return false;
+            };
+            let (Ok(random), Ok(signature)) = (hex::decode(random), hex::decode(signature)) else {
+                return false;
+            };
+            let mut mac = self.mac();
+            mac.update(&random);
+            mac.verify_slice(&signature).is_ok()
+        }
+
+        fn has_valid_cookie(&self, headers: &HeaderMap) -> bool {
+            headers
+                .typed_get::<Cookie>()
+                .and_then(|cookie| cookie.get(COOKIE).map(|token| self.is_valid(token)))
+                .unwrap_or(false)
+        }
+    }
+
+    // Turns away requests that change something without a valid cookie or from
+    // an origin we don't allow, and hands the cookie to whoever doesn't have one
+    // yet.
+    pub async fn csrf_protection<B>(State(state): State<Arc<AppState>>, req: Request<B>, next: Next<B>) -> Response {
+        let csrf = &state.csrf;
+        let has_cookie = csrf.has_valid_cookie(req.headers());
+
+        let path = req.uri().path();
+        let mut res = if needs_cookie(req.method(), path) && !(has_cookie && csrf.origins.allows(req.headers())) {
+            tracing::warn!("Refused a {} to {path} from {:?}", req.method(), req.headers().get(header::ORIGIN));
+            if path.starts_with(SERVER_FN_PREFIX) {
+                refused()
+            } else {
+                AppError::Forbidden.into_response()
+            }
+        } else {
+            next.run(req).await
+        };
+
+        if !has_cookie {
+            let cookie = format!("{COOKIE}={}; Path=/; SameSite=Strict; HttpOnly", csrf.new_token());
+            if let Ok(cookie) = HeaderValue::try_from(cookie) {
+                res.headers_mut().append(header::SET_COOKIE, cookie);
+            }
+        }
+        res
+    }
+
+    // Server function clients read errors they get as `ServerFnError`s, so
+    // that's what we send, carrying our code and message.
+    fn refused() -> Response {
+        let err = ServerFnError::from(ErrorBody::from(AppError::Forbidden));
+        match serde_json::to_string(&err) {
+            Ok(body) => (StatusCode::FORBIDDEN, [(header::CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(_) => AppError::Forbidden.into_response(),
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    fn csrf(key: &[u8], origins: &[&str]) -> Csrf {
+        Csrf { origins: AllowedOrigins::new(origins), key: key.to_vec() }
+    }
+
+    fn headers(origin: Option<&str>, host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn tokens_are_only_good_with_our_key() {
+        let ours = csrf(b"ours", &[]);
+        let token = ours.new_token();
+        assert!(ours.is_valid(&token));
+        assert!(!csrf(b"theirs", &[]).is_valid(&token));
+
+        let (random, signature) = token.split_once('.').unwrap();
+        let other_random = format!("{}{}", if random.starts_with('0') { '1' } else { '0' }, &random[1..]);
+        assert!(!ours.is_valid(&format!("{other_random}.{signature}")));
+        assert!(!ours.is_valid(random));
+        assert!(!ours.is_valid("not.hex"));
+        assert!(!ours.is_valid(""));
+    }
+
+    #[test]
+    fn pages_from_the_same_host() {
+        let origins = AllowedOrigins::default();
+        assert!(origins.allows(&headers(Some("https://chat.example.com"), "chat.example.com")));
+        assert!(origins.allows(&headers(Some("http://localhost:3000"), "localhost:3000")));
+        assert!(!origins.allows(&headers(Some("http://localhost:4000"), "localhost:3000")));
+        assert!(!origins.allows(&headers(Some("https://evil.example"), "chat.example.com")));
+        assert!(!origins.allows(&headers(Some("null"), "chat.example.com")));
+        // Not from a browser.
+        assert!(origins.allows(&headers(None, "chat.example.com")));
+    }
+
+    #[test]
+    fn pages_from_allowed_origins() {
+        let origins = AllowedOrigins::new(["https://chat.example.com/", "not an origin"]);
+        assert!(origins.allows(&headers(Some("https://chat.example.com"), "backend:3000")));
+        assert!(!origins.allows(&headers(Some("http://chat.example.com"), "backend:3000")));
+        assert!(!origins.allows(&headers(Some("http://backend:3000"), "backend:3000")));
+    }
+
+    #[test]
+    fn what_needs_a_cookie() {
+        assert!(needs_cookie(&Method::POST, "/api/create_room123"));
+        assert!(needs_cookie(&Method::POST, "/events/3f2c"));
+        assert!(needs_cookie(&Method::POST, "/attachments"));
+        assert!(needs_cookie(&Method::DELETE, "/anything"));
+        assert!(!needs_cookie(&Method::GET, "/attachments/3f2c"));
+        assert!(!needs_cookie(&Method::POST, "/bot/rooms/lobby/messages"));
+        assert!(!needs_cookie(&Method::POST, CSP_REPORT_PATH));
+    }
+}
@@ -1,74 +1,379 @@
 use cfg_if::cfg_if;
 use http::status::StatusCode;
 use leptos::*;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[cfg(feature = "ssr")]
 use leptos_axum::ResponseOptions;
 
-#[derive(Clone, Debug, Error)]
+// What can go wrong, as far as whoever asked needs to know. Each error has a
+// status code, a message for people (its `Display`) and a code for programs.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
 pub enum AppError {
-    #[error("Not Found")]
+    #[error("We couldn't make sense of that.")]
+    BadRequest,
+    #[error("We couldn't find what you were looking for.")]
     NotFound,
+    #[error("We need to know who you are first.")]
+    Unauthorized,
+    #[error("You aren't allowed to do that.")]
+    Forbidden,
+    #[error("That was too much at once. Wait a moment and try again.")]
+    RateLimited,
+    #[error("That's too large for us to take.")]
+    PayloadTooLarge,
+    #[error("We can't take that kind of file.")]
+    UnsupportedMediaType,
+    #[error("That doesn't look right.")]
+    Invalid,
+    #[error("That name is already taken.")]
+    Conflict,
+    #[error("Something went wrong on our side.")]
+    Internal,
+    #[error("We can't handle that right now. Try again shortly.")]
+    ServiceUnavailable,
 }
 
 impl AppError {
+    pub const ALL: [AppError; 11] = [
+        AppError::BadRequest,
+        AppError::NotFound,
+        AppError::Unauthorized,
+        AppError::Forbidden,
+        AppError::RateLimited,
+        AppError::PayloadTooLarge,
+        AppError::UnsupportedMediaType,
+        AppError::Invalid,
+        AppError::Conflict,
+        AppError::Internal,
+        AppError::ServiceUnavailable,
+    ];
+
     pub fn status_code(&self) -> StatusCode {
         match self {
+            AppError::BadRequest => StatusCode::BAD_REQUEST,
             AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::Invalid => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Conflict => StatusCode::CONFLICT,
+            AppError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    // Stays the same whatever the message says, for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest => "bad_request",
+            AppError::NotFound => "not_found",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::RateLimited => "rate_limited",
+            AppError::PayloadTooLarge => "payload_too_large",
+            AppError::UnsupportedMediaType => "unsupported_media_type",
+            AppError::Invalid => "invalid",
+            AppError::Conflict => "conflict",
+            AppError::Internal => "internal",
+            AppError::ServiceUnavailable => "service_unavailable",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<AppError> {
+        AppError::ALL.into_iter().find(|err| err.code() == code)
+    }
+
+    // This error, saying more precisely what was wrong than its `Display` does.
+    pub fn with_message(self, message: impl Into<String>) -> ErrorBody {
+        ErrorBody {
+            error: self.code().to_owned(),
+            message: message.into(),
+        }
+    }
+
+    // The error a server function failed with, if it was one of ours.
+    pub fn from_server_fn_error(err: &ServerFnError) -> Option<AppError> {
+        ErrorBody::from_server_fn_error(err).and_then(|body| AppError::from_code(&body.error))
+    }
+}
+
+// What went wrong in a server function, as its user should read it.
+pub fn error_message(err: &ServerFnError) -> String {
+    match (ErrorBody::from_server_fn_error(err), err) {
+        (Some(body), _) => body.message,
+        (None, ServerFnError::ServerError(msg)) => msg.clone(),
+        (None, err) => err.to_string(),
+    }
+}
+
+// The body of an error response: `{"error": "<code>", "message": "..."}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub error: String,
+    pub message: String,
+}
+
+impl From<AppError> for ErrorBody {
+    fn from(err: AppError) -> Self {
+        err.with_message(err.to_string())
+    }
+}
+
+impl ErrorBody {
+    // Server functions can only fail with a string, so ours are sent as
+    // `<code>: <message>`, which this reads back.
+    pub fn from_server_fn_error(err: &ServerFnError) -> Option<ErrorBody> {
+        let ServerFnError::ServerError(msg) = err else {
+            return None;
+        };
+        let (code, message) = msg.split_once(": ")?;
+        let err = AppError::from_code(code)?;
+        Some(err.with_message(message))
+    }
+}
+
+// Failing a server function with `?` on an `ErrorBody`, e.g.
+// `ErrorBody::from(AppError::Internal)`, keeps its code. An `AppError` on its
+// own would only send its message.
+impl From<ErrorBody> for ServerFnError {
+    fn from(body: ErrorBody) -> Self {
+        ServerFnError::ServerError(format!("{}: {}", body.error, body.message))
+    }
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use axum::{
+        body::{boxed, Full},
+        response::{IntoResponse, Response},
+        Json,
+    };
+
+    impl IntoResponse for AppError {
+        fn into_response(self) -> Response {
+            ErrorBody::from(self).into_response()
+        }
+    }
+
+    impl IntoResponse for ErrorBody {
+        fn into_response(self) -> Response {
+            let status = AppError::from_code(&self.error).map_or(StatusCode::INTERNAL_SERVER_ERROR, |err| err.status_code());
+            (status, Json(self)).into_response()
+        }
+    }
+
+    // leptos_axum answers every server function that fails with a 500, and
+    // `ResponseOptions` aren't used for those, so the status of ours is put on
+    // afterwards. The body stays the `ServerFnError` clients expect.
+    pub async fn server_fn_error_status(res: Response) -> Response {
+        if res.status() != StatusCode::INTERNAL_SERVER_ERROR {
+            return res;
+        }
+        let (mut parts, body) = res.into_parts();
+        let Ok(bytes) = hyper::body::to_bytes(body).await else {
+            return AppError::Internal.into_response();
+        };
+        if let Some(err) = serde_json::from_slice::<ServerFnError>(&bytes)
+            .ok()
+            .and_then(|err| AppError::from_server_fn_error(&err))
+        {
+            parts.status = err.status_code();
+        }
+        Response::from_parts(parts, boxed(Full::from(bytes)))
+    }
+}}
+
+// Where the request id is shown, so hydrating can read back what the server
+// rendered.
+const REQUEST_ID_ELEMENT: &str = "error-request-id";
+
+// One error as we show it, whatever type it was.
+#[derive(Clone, Debug)]
+struct ShownError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl From<AppError> for ShownError {
+    fn from(err: AppError) -> Self {
+        ShownError {
+            status: err.status_code(),
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl ShownError {
+    // Our own errors say what they are; server function errors may carry one of
+    // ours; anything else is our fault as far as the user is concerned.
+    fn new(error: &leptos::error::Error) -> Self {
+        if let Some(err) = error.downcast_ref::<AppError>() {
+            return ShownError::from(*err);
+        }
+
+        // Server function errors land in `Errors` as `ServerFnErrorErr`.
+        let message = match error.downcast_ref::<ServerFnErrorErr>() {
+            Some(err) => {
+                let err = ServerFnError::from(err.clone());
+                if let Some(body) = ErrorBody::from_server_fn_error(&err) {
+                    if let Some(app_error) = AppError::from_code(&body.error) {
+                        return ShownError {
+                            message: body.message,
+                            ..ShownError::from(app_error)
+                        };
+                    }
+                }
+                error_message(&err)
+            }
+            None => error.to_string(),
+        };
+        ShownError {
+            message,
+            ..ShownError::from(AppError::Internal)
         }
     }
 }
 
-// A basic function to display errors served by the error boundaries.
-// Feel free to do more complicated things here than just displaying the error.
+// The `x-request-id` of the request this page is the answer to.
+#[cfg(feature = "ssr")]
+fn request_id() -> Option<String> {
+    let request = use_context::<leptos_axum::RequestParts>()?;
+    let id = request.headers.get("x-request-id")?.to_str().ok()?;
+    Some(id.to_owned())
+}
+
+// In the browser, that's whatever the server put on the page, if it rendered
+// this error.
+#[cfg(not(feature = "ssr"))]
+fn request_id() -> Option<String> {
+    document().get_element_by_id(REQUEST_ID_ELEMENT)?.text_content()
+}
+
+// Shows everything in `Errors`, whether it was given as `outside_errors` or by
+// an error boundary, as a page of its own. The response gets the most severe of
+// their status codes, which also decides what the page says.
 #[component]
 pub fn ErrorTemplate(
     #[prop(optional)] outside_errors: Option<Errors>,
     #[prop(optional)] errors: Option<RwSignal<Errors>>,
 ) -> impl IntoView {
-    let errors = match outside_errors {
-        Some(e) => create_rw_signal(e),
-        None => match errors {
-            Some(e) => e,
-            None => panic!("No Errors found and we expected errors!"),
-        },
+    let errors = match (outside_errors, errors) {
+        (Some(errors), _) => errors,
+        (None, Some(errors)) => errors.get_untracked(),
+        (None, None) => Errors::default(),
     };
-    // Get Errors from Signal
-    let errors = errors.get_untracked();
-
-    // Downcast lets us take a type that implements `std::error::Error`
-    let errors: Vec<AppError> = errors
-        .into_iter()
-        .filter_map(|(_k, v)| v.downcast_ref::<AppError>().cloned())
-        .collect();
-    println!("Errors: {errors:#?}");
-
-    // Only the response code for the first error is actually sent from the server
-    // this may be customized by the specific application
-    cfg_if! { if #[cfg(feature="ssr")] {
-        let response = use_context::<ResponseOptions>();
-        if let Some(response) = response {
-            response.set_status(errors[0].status_code());
-        }
-    }}
+    let errors: Vec<ShownError> = errors.into_iter().map(|(_key, error)| ShownError::new(&error)).collect();
+    logging::warn!("Errors: {errors:#?}");
+
+    let status = errors.iter().map(|error| error.status).max_by_key(StatusCode::as_u16);
+    #[cfg(feature = "ssr")]
+    if let (Some(response), Some(status)) = (use_context::<ResponseOptions>(), status) {
+        response.set_status(status);
+    }
+    let request_id = request_id();
+
+    let heading = match status {
+        Some(StatusCode::NOT_FOUND) => "Not found",
+        Some(status) if status.is_server_error() => "Something went wrong",
+        _ if errors.len() > 1 => "Errors",
+        _ => "Error",
+    };
+    // Trying again only helps when it was our fault. It's a full reload, so
+    // whatever failed runs again.
+    let retry = status.is_some_and(|status| status.is_server_error());
 
     view! {
-        <h1>{if errors.len() > 1 {"Errors"} else {"Error"}}</h1>
-        <For
-            // a function that returns the items we're iterating over; a signal is fine
-            each= move || {errors.clone().into_iter().enumerate()}
-            // a unique key for each item as a reference
-            key=|(index, _error)| *index
-            // renders each item to a view
-            children=move |error| {
-                let error_string = error.1.to_string();
-                let error_code= error.1.status_code();
-                view! {
-                    <h2>{error_code.to_string()}</h2>
-                    <p>"Error: " {error_string}</p>
+        <section class="error-page">
+            <h1>{heading}</h1>
+            <For
+                // a function that returns the items we're iterating over; a signal is fine
+                each= move || {errors.clone().into_iter().enumerate()}
+                // a unique key for each item as a reference
+                key=|(index, _error)| *index
+                // renders each item to a view
+                children=move |(_index, error)| {
+                    view! {
+                        <h2>{error.status.to_string()}</h2>
+                        <p>"Error: " {error.message}</p>
+                        <p class="error__code"><code>{error.code}</code></p>
+                    }
                 }
-            }
-        />
+            />
+            {request_id.map(|id| view! {
+                <p class="error__request">"Request id: " <code id=REQUEST_ID_ELEMENT>{id}</code></p>
+            })}
+            <nav class="error-page__nav">
+                {retry.then(|| view! { <a href="" rel="external">"Try again"</a> })}
+                <a href="/">"Back to the chat"</a>
+                <a href="/search">"Search messages"</a>
+            </nav>
+        </section>
+    }
+}
+
+// What's shown for paths we have no page for, with a 404.
+#[component]
+pub fn NotFound() -> impl IntoView {
+    let mut outside_errors = Errors::default();
+    outside_errors.insert_with_default_key(AppError::NotFound);
+    view! { <ErrorTemplate outside_errors/> }
+}
+
+// Errors a page runs into replace just that page with an `ErrorTemplate`;
+// anything around it keeps working.
+#[component]
+pub fn PageErrors(children: Children) -> impl IntoView {
+    view! {
+        <ErrorBoundary fallback=|errors| view! { <ErrorTemplate errors/> }>
+            {children()}
+        </ErrorBoundary>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_survive_server_functions() {
+        for err in AppError::ALL {
+            let sent = ServerFnError::from(err.with_message("Only admins of #ops can change it."));
+            assert_eq!(AppError::from_server_fn_error(&sent), Some(err));
+            assert_eq!(error_message(&sent), "Only admins of #ops can change it.");
+        }
+    }
+
+    #[test]
+    fn other_errors_are_not_ours() {
+        let err = ServerFnError::ServerError(String::from("nope: We couldn't find what you were looking for."));
+        assert_eq!(AppError::from_server_fn_error(&err), None);
+        assert_eq!(error_message(&err), "nope: We couldn't find what you were looking for.");
+
+        // Our message without our code could be anything.
+        let err = ServerFnError::ServerError(AppError::NotFound.to_string());
+        assert_eq!(AppError::from_server_fn_error(&err), None);
+    }
+
+    #[cfg(feature = "ssr")]
+    #[tokio::test]
+    async fn failed_server_functions_get_our_status() {
+        let failed = |err: ServerFnError| {
+            let body = serde_json::to_string(&err).unwrap();
+            (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        };
+
+        let res = server_fn_error_status(failed(ErrorBody::from(AppError::Forbidden).into())).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let err: ServerFnError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(AppError::from_server_fn_error(&err), Some(AppError::Forbidden));
+
+        let res = server_fn_error_status(failed(ServerFnError::ServerError(String::from("oops")))).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 }
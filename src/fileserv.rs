@@ -2,39 +2,214 @@ use cfg_if::cfg_if;
 
 cfg_if! { if #[cfg(feature = "ssr")] {
     use axum::{
-        body::{boxed, Body, BoxBody},
+        body::{boxed, Body, BoxBody, Full},
         extract::State,
         response::IntoResponse,
-        http::{Request, Response, StatusCode, Uri},
+        http::{header, Extensions, HeaderMap, HeaderValue, Request, Response, StatusCode, Uri, Version},
     };
     use axum::response::Response as AxumResponse;
+    use dashmap::DashMap;
+    use sha2::{Digest, Sha256};
+    use std::sync::OnceLock;
     use tower::ServiceExt;
-    use tower_http::services::ServeDir;
+    use tower_http::{
+        compression::{predicate::{Predicate, SizeAbove}, CompressionLayer},
+        services::ServeDir,
+    };
     use leptos::*;
     use crate::app::App;
+    use crate::error_template::AppError;
+
+    // Everything, pages included, is checked with us before it's used again.
+    // With an ETag that's a 304 when nothing changed. The package directory's
+    // files keep their names across deploys (Leptos 0.5 only knows those), so
+    // they can't be cached for longer either.
+    const REVALIDATE: &str = "no-cache";
 
     pub async fn file_and_error_handler(uri: Uri, State(options): State<LeptosOptions>, req: Request<Body>) -> AxumResponse {
         let root = options.site_root.clone();
-        let res = get_static_file(uri.clone(), &root).await.unwrap();
+        let res = match get_static_file(uri.clone(), req.headers(), &root).await {
+            Ok(res) => res,
+            Err(err) => return err.into_response(),
+        };
 
-        if res.status() == StatusCode::OK {
+        if res.status() != StatusCode::NOT_FOUND {
             res.into_response()
+        } else if is_static_path(uri.path(), &options.site_pkg_dir) {
+            // Nobody is going to look at a page in place of a missing script,
+            // stylesheet or image, so they just get the status.
+            AppError::NotFound.into_response()
         } else {
+            // The app answers with its own 404 page, from the router's fallback.
             let handler = leptos_axum::render_app_to_stream(options.to_owned(), move || view!{<App/>});
             handler(req).await.into_response()
         }
     }
 
-    async fn get_static_file(uri: Uri, root: &str) -> Result<Response<BoxBody>, (StatusCode, String)> {
-        let req = Request::builder().uri(uri.clone()).body(Body::empty()).unwrap();
+    async fn get_static_file(uri: Uri, headers: &HeaderMap, root: &str) -> Result<Response<BoxBody>, AppError> {
+        // No file name has a NUL in it, and the file system would rather fail
+        // than say so when asked for one.
+        if uri.path().contains("%00") {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+        // Whatever reached us parsed once already, but if it won't go into a
+        // request again there's no file for it either.
+        let mut req = Request::builder().uri(uri.clone()).body(Body::empty()).map_err(|err| {
+            tracing::debug!("Not serving {uri}: {err}");
+            AppError::NotFound
+        })?;
+        // `ServeDir` picks the precompressed variant and answers ranges and
+        // `If-Modified-Since` from these. That last one doesn't count when
+        // there's an `If-None-Match`, which we answer ourselves.
+        *req.headers_mut() = headers.clone();
+        if headers.contains_key(header::IF_NONE_MATCH) {
+            req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+        }
+
         // `ServeDir` implements `tower::Service` so we can call it with `tower::ServiceExt::oneshot`
         // This path is relative to the cargo root
-        match ServeDir::new(root).oneshot(req).await {
-            Ok(res) => Ok(res.map(boxed)),
-            Err(err) => Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Something went wrong: {err}"),
-            )),
+        let res = match ServeDir::new(root).precompressed_br().precompressed_gzip().oneshot(req).await {
+            Ok(res) => res.map(boxed),
+            Err(err) => {
+                tracing::error!("Serving {uri} from {root} failed: {err}");
+                return Err(AppError::Internal);
+            }
+        };
+        if !res.status().is_success() && res.status() != StatusCode::NOT_MODIFIED {
+            return Ok(res);
+        }
+
+        let (mut parts, body) = res.into_parts();
+        parts.headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(REVALIDATE));
+        // Which variant was sent depends on `Accept-Encoding`, and caches on the
+        // way need to know that.
+        parts.headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if parts.status != StatusCode::OK {
+            return Ok(Response::from_parts(parts, body));
+        }
+
+        let (etag, body) = match etag(uri.path(), &parts.headers, body).await {
+            Ok(tagged) => tagged,
+            Err(err) => {
+                tracing::error!("Reading {uri} from {root} failed: {err}");
+                return Err(AppError::Internal);
+            }
+        };
+        let unchanged = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| etag_matches(value, &etag));
+        parts.headers.insert(header::ETAG, etag);
+        if unchanged {
+            parts.status = StatusCode::NOT_MODIFIED;
+            for name in [header::CONTENT_LENGTH, header::CONTENT_TYPE, header::ACCEPT_RANGES] {
+                parts.headers.remove(name);
+            }
+            return Ok(Response::from_parts(parts, boxed(Body::empty())));
+        }
+        Ok(Response::from_parts(parts, body))
+    }
+
+    // Path, `Content-Encoding`, `Last-Modified` and `Content-Length` of a file
+    // we've sent.
+    type EtagKey = (String, Option<HeaderValue>, Option<HeaderValue>, Option<HeaderValue>);
+
+    // How many tags we keep. The site is a few dozen files; more than that are
+    // tags of files that have since changed, so we start over.
+    const MAX_ETAGS: usize = 1024;
+
+    // A strong ETag for the file behind `path`, from a hash of exactly what's
+    // sent: each encoding gets its own. Tags are kept for as long as the file
+    // keeps its modification time and size, so each file is only hashed once.
+    // Modification times only go to the second, and the size catches most
+    // files rewritten within one.
+    async fn etag(path: &str, headers: &HeaderMap, body: BoxBody) -> Result<(HeaderValue, BoxBody), axum::Error> {
+        static ETAGS: OnceLock<DashMap<EtagKey, HeaderValue>> = OnceLock::new();
+
+        let key = (
+            path.to_owned(),
+            headers.get(header::CONTENT_ENCODING).cloned(),
+            headers.get(header::LAST_MODIFIED).cloned(),
+            headers.get(header::CONTENT_LENGTH).cloned(),
+        );
+        let etags = ETAGS.get_or_init(DashMap::new);
+        if let Some(etag) = etags.get(&key) {
+            return Ok((etag.clone(), body));
+        }
+
+        let bytes = hyper::body::to_bytes(body).await?;
+        let hash = Sha256::digest(&bytes);
+        let etag = HeaderValue::try_from(format!("\"{}\"", hex::encode(&hash[..16])))
+            .expect("hex digits in quotes are a valid header value");
+        // Without a modification time a later file might look the same.
+        if key.2.is_some() {
+            if etags.len() >= MAX_ETAGS {
+                etags.clear();
+            }
+            etags.insert(key, etag.clone());
         }
+        Ok((etag, boxed(Full::from(bytes))))
+    }
+
+    // `If-None-Match` uses the weak comparison, so `W/` is ignored on either side.
+    fn etag_matches(if_none_match: &str, etag: &HeaderValue) -> bool {
+        let etag = etag.to_str().unwrap_or_default().trim_start_matches("W/");
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+
+    fn is_in_pkg_dir(path: &str, pkg_dir: &str) -> bool {
+        path.trim_start_matches('/')
+            .strip_prefix(pkg_dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    // Paths that can only be meant for a file: anything in the package
+    // directory, or with an extension. Pages never have one.
+    fn is_static_path(path: &str, pkg_dir: &str) -> bool {
+        let has_extension = path.rsplit('/').next().is_some_and(|name| name.contains('.'));
+        is_in_pkg_dir(path, pkg_dir) || has_extension
+    }
+
+    // Pages and JSON are compressed as they're sent. Static files come
+    // precompressed or not at all, and event streams can't wait for a
+    // compressor to fill up.
+    pub fn compression_layer() -> CompressionLayer<impl Predicate> {
+        fn is_html_or_json(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+            headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|content_type| {
+                    content_type.starts_with("text/html") || content_type.starts_with("application/json")
+                })
+        }
+
+        CompressionLayer::new().compress_when(SizeAbove::default().and(is_html_or_json))
+    }
+
+    // Rendered pages are revalidated every time, like static files, so nobody
+    // is shown an old page after a deploy.
+    pub fn html_cache_control<B>(res: &Response<B>) -> Option<HeaderValue> {
+        let content_type = res.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+        content_type.starts_with("text/html").then(|| HeaderValue::from_static(REVALIDATE))
     }
 }}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_directory() {
+        assert!(is_in_pkg_dir("/pkg/web-app-axum.3f2a9c1d.js", "pkg"));
+        assert!(!is_in_pkg_dir("/pkg", "pkg"));
+        assert!(!is_in_pkg_dir("/pkgs/app.js", "pkg"));
+        assert!(!is_in_pkg_dir("/other/pkg/app.js", "pkg"));
+
+        assert!(is_static_path("/pkg/", "pkg"));
+        assert!(is_static_path("/favicon.ico", "pkg"));
+        assert!(!is_static_path("/rooms/lobby", "pkg"));
+    }
+}
@@ -0,0 +1,207 @@
+// A deliberately small syntax highlighter for code blocks in chat messages. It
+// knows comments, strings, numbers and the keywords of a few common languages,
+// which is most of what makes a snippet readable, without shipping a grammar
+// library to the browser.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Plain,
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+impl TokenKind {
+    pub fn class(self) -> &'static str {
+        match self {
+            TokenKind::Plain => "hl-plain",
+            TokenKind::Keyword => "hl-keyword",
+            TokenKind::String => "hl-string",
+            TokenKind::Number => "hl-number",
+            TokenKind::Comment => "hl-comment",
+        }
+    }
+}
+
+struct Language {
+    keywords: &'static [&'static str],
+    line_comment: &'static str,
+    block_comments: bool,
+    // Whether `'a` is a lifetime rather than the start of a string.
+    lifetimes: bool,
+}
+
+const RUST: Language = Language {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+        "type", "unsafe", "use", "where", "while",
+    ],
+    line_comment: "//",
+    block_comments: true,
+    lifetimes: true,
+};
+
+const JAVASCRIPT: Language = Language {
+    keywords: &[
+        "async", "await", "break", "case", "catch", "class", "const", "continue", "default",
+        "delete", "do", "else", "export", "extends", "false", "finally", "for", "function", "if",
+        "import", "in", "instanceof", "interface", "let", "new", "null", "return", "switch",
+        "this", "throw", "true", "try", "type", "typeof", "undefined", "var", "void", "while",
+        "yield",
+    ],
+    line_comment: "//",
+    block_comments: true,
+    lifetimes: false,
+};
+
+const PYTHON: Language = Language {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+        "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "True",
+        "try", "while", "with", "yield",
+    ],
+    line_comment: "#",
+    block_comments: false,
+    lifetimes: false,
+};
+
+const SHELL: Language = Language {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "then", "until", "while",
+    ],
+    line_comment: "#",
+    block_comments: false,
+    lifetimes: false,
+};
+
+fn language(name: &str) -> Option<Language> {
+    match name.to_ascii_lowercase().as_str() {
+        "rust" | "rs" => Some(RUST),
+        "javascript" | "js" | "typescript" | "ts" | "json" => Some(JAVASCRIPT),
+        "python" | "py" => Some(PYTHON),
+        "sh" | "bash" | "shell" | "zsh" => Some(SHELL),
+        _ => None,
+    }
+}
+
+// Splits `code` into runs of text of the same kind. Unknown languages come
+// back as one plain run.
+pub fn highlight(lang: Option<&str>, code: &str) -> Vec<(TokenKind, String)> {
+    let Some(language) = lang.and_then(language) else {
+        return vec![(TokenKind::Plain, code.to_owned())];
+    };
+
+    let mut tokens: Vec<(TokenKind, String)> = Vec::new();
+    let mut push = |kind: TokenKind, text: &str| match tokens.last_mut() {
+        Some((last, run)) if *last == kind => run.push_str(text),
+        _ => tokens.push((kind, text.to_owned())),
+    };
+
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let (kind, len) = if rest.starts_with(language.line_comment) {
+            (TokenKind::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if language.block_comments && rest.starts_with("/*") {
+            (TokenKind::Comment, rest[2..].find("*/").map_or(rest.len(), |end| end + 4))
+        } else if c == '\'' && language.lifetimes && is_lifetime(rest) {
+            (TokenKind::Plain, 1)
+        } else if c == '"' || c == '\'' || c == '`' {
+            (TokenKind::String, string_len(rest, c))
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            (TokenKind::Number, len)
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let kind = if language.keywords.contains(&&rest[..len]) {
+                TokenKind::Keyword
+            } else {
+                TokenKind::Plain
+            };
+            (kind, len)
+        } else {
+            (TokenKind::Plain, c.len_utf8())
+        };
+
+        push(kind, &rest[..len]);
+        rest = &rest[len..];
+    }
+
+    tokens
+}
+
+// Length of the string literal at the start of `rest`, quotes included. An
+// unterminated string runs to the end of the line.
+fn string_len(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '\n' if quote != '`' => return i,
+            _ if c == quote => return i + c.len_utf8(),
+            _ => (),
+        }
+    }
+    rest.len()
+}
+
+// `'a` but not `'a'`.
+fn is_lifetime(rest: &str) -> bool {
+    let mut chars = rest.chars().skip(1);
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_') && chars.next() != Some('\'')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(tokens: &[(TokenKind, String)]) -> Vec<(TokenKind, &str)> {
+        tokens.iter().map(|(kind, text)| (*kind, text.as_str())).collect()
+    }
+
+    #[test]
+    fn rust() {
+        let tokens = highlight(Some("rs"), "fn f<'a>() { \"s\" } // done");
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                (TokenKind::Keyword, "fn"),
+                (TokenKind::Plain, " f<'a>() { "),
+                (TokenKind::String, "\"s\""),
+                (TokenKind::Plain, " } "),
+                (TokenKind::Comment, "// done"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_strings_end_with_the_line() {
+        let tokens = highlight(Some("python"), "x = 'open\nreturn 1");
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                (TokenKind::Plain, "x = "),
+                (TokenKind::String, "'open"),
+                (TokenKind::Plain, "\n"),
+                (TokenKind::Keyword, "return"),
+                (TokenKind::Plain, " "),
+                (TokenKind::Number, "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_languages_are_plain() {
+        assert_eq!(highlight(Some("cobol"), "MOVE 1 TO X"), vec![(TokenKind::Plain, "MOVE 1 TO X".to_owned())]);
+        assert_eq!(highlight(None, "fn"), vec![(TokenKind::Plain, "fn".to_owned())]);
+    }
+}
@@ -0,0 +1,58 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::ws::ServerMessage;
+    use uuid::Uuid;
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{Mutex, PoisonError},
+    };
+
+    // The most recent chat messages in each room, replayed to clients when they
+    // come in.
+    #[derive(Debug)]
+    pub struct History {
+        // How many messages each room keeps.
+        capacity: usize,
+        rooms: Mutex<HashMap<String, VecDeque<ServerMessage>>>,
+    }
+
+    impl History {
+        pub fn new(capacity: usize) -> Self {
+            History {
+                capacity,
+                rooms: Mutex::new(HashMap::new()),
+            }
+        }
+
+        pub fn push(&self, message: ServerMessage) {
+            // Nothing can be left half-written in here, so a panic elsewhere while
+            // holding the lock doesn't need to take the history down with it.
+            let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+            let messages = rooms
+                .entry(message.room.clone())
+                .or_insert_with(|| VecDeque::with_capacity(self.capacity));
+            if messages.len() == self.capacity {
+                messages.pop_front();
+            }
+            messages.push_back(message);
+        }
+
+        // Changes message `id`, if it is still kept.
+        pub fn update(&self, id: Uuid, change: impl FnOnce(&mut ServerMessage)) {
+            let mut rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(message) = rooms.values_mut().flatten().find(|message| message.id == id) {
+                change(message);
+            }
+        }
+
+        // Oldest first.
+        pub fn recent(&self, room: &str) -> Vec<ServerMessage> {
+            let rooms = self.rooms.lock().unwrap_or_else(PoisonError::into_inner);
+            rooms
+                .get(room)
+                .map(|messages| messages.iter().cloned().collect())
+                .unwrap_or_default()
+        }
+    }
+}}
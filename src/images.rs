@@ -0,0 +1,392 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use image::{imageops::FilterType, io::Limits, DynamicImage, ImageFormat};
+    use std::{io::Cursor, path::Path};
+    use thiserror::Error;
+
+    // Widths of the thumbnails made for every shared image.
+    pub const THUMBNAIL_SIZES: [u32; 2] = [160, 480];
+
+    // Images bigger than this aren't thumbnailed. A small file can claim to be
+    // enormous, and decoding it would take all the memory it claims.
+    const MAX_DIMENSION: u32 = 12_000;
+    const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+
+    // The EXIF tag saying which way up a photo is, and how it's stored.
+    const ORIENTATION_TAG: u16 = 0x0112;
+    const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+    #[derive(Debug, Error)]
+    pub enum ImageError {
+        #[error("the file is not a valid {0}")]
+        Malformed(&'static str),
+        #[error(transparent)]
+        Image(#[from] image::ImageError),
+        #[error(transparent)]
+        Io(#[from] std::io::Error),
+    }
+
+    // Drops the metadata a camera or phone may have written into an image, GPS
+    // position included, without touching the pixels. GIFs carry none.
+    pub fn strip_metadata(content_type: &str, bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+        match content_type {
+            "image/jpeg" => strip_jpeg(bytes),
+            "image/png" => strip_png(bytes),
+            "image/webp" => strip_webp(bytes),
+            "image/gif" if bytes.starts_with(b"GIF8") => Ok(bytes.to_vec()),
+            "image/gif" => Err(ImageError::Malformed("GIF")),
+            _ => Ok(bytes.to_vec()),
+        }
+    }
+
+    // Keeps every segment up to the image data except EXIF/XMP (APP1), IPTC
+    // (APP13), APP12 and comments. The ICC profile (APP2) and Adobe colour
+    // information (APP14) stay, since they change how the image looks, and so
+    // does the EXIF orientation, in an EXIF segment of its own: phones store
+    // portrait photos sideways and rely on it.
+    fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+        let malformed = || ImageError::Malformed("JPEG");
+        if !bytes.starts_with(&[0xff, 0xd8]) {
+            return Err(malformed());
+        }
+
+        let mut out = vec![0xff, 0xd8];
+        // EXIF goes right after the JFIF segment, if there is one.
+        let mut exif_at = out.len();
+        let mut orientation = None;
+        let mut pos = 2;
+        loop {
+            // Markers may be padded with any number of 0xff bytes.
+            while bytes.get(pos) == Some(&0xff) && bytes.get(pos + 1) == Some(&0xff) {
+                pos += 1;
+            }
+            let marker = match bytes.get(pos..pos + 2) {
+                Some(&[0xff, marker]) => marker,
+                _ => return Err(malformed()),
+            };
+
+            // Start of scan: the entropy-coded data and everything after it is kept as is.
+            if marker == 0xda {
+                out.extend_from_slice(&bytes[pos..]);
+                if let Some(orientation) = orientation {
+                    out.splice(exif_at..exif_at, orientation_segment(orientation));
+                }
+                return Ok(out);
+            }
+
+            let length = bytes.get(pos + 2..pos + 4).ok_or_else(malformed)?;
+            let end = pos + 2 + usize::from(u16::from_be_bytes([length[0], length[1]]));
+            let segment = bytes.get(pos..end).ok_or_else(malformed)?;
+
+            match marker {
+                0xe1 => orientation = orientation.or_else(|| segment.get(4..).and_then(exif_orientation)),
+                0xec | 0xed | 0xfe => (),
+                _ => {
+                    let first = out.len() == 2;
+                    out.extend_from_slice(segment);
+                    if marker == 0xe0 && first {
+                        exif_at = out.len();
+                    }
+                }
+            }
+            pos = end;
+        }
+    }
+
+    // The orientation (2 to 8; 1 is upright) in the body of an APP1 segment, if
+    // it's EXIF and says.
+    fn exif_orientation(app1: &[u8]) -> Option<u16> {
+        let tiff = app1.strip_prefix(EXIF_HEADER)?;
+        let big_endian = match tiff.get(0..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        let u16_at = |at: usize| {
+            let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+            Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+        };
+        let u32_at = |at: usize| {
+            let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?, *tiff.get(at + 2)?, *tiff.get(at + 3)?];
+            Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+        };
+
+        let ifd = u32_at(4)? as usize;
+        let entries = u16_at(ifd)?;
+        (0..usize::from(entries))
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+            .and_then(|entry| u16_at(entry + 8))
+            .filter(|orientation| (2..=8).contains(orientation))
+    }
+
+    // An APP1 segment with EXIF saying `orientation` and nothing else.
+    fn orientation_segment(orientation: u16) -> Vec<u8> {
+        let mut tiff = Vec::with_capacity(26);
+        tiff.extend_from_slice(b"MM\0\x2a");
+        // IFD0 follows the header, with the one entry: a SHORT, in place.
+        tiff.extend_from_slice(&8u32.to_be_bytes());
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff.extend_from_slice(&[0, 0]);
+        // No further IFDs.
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+
+        let length = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend_from_slice(&length.to_be_bytes());
+        segment.extend_from_slice(EXIF_HEADER);
+        segment.extend_from_slice(&tiff);
+        segment
+    }
+
+    // Turns `image`, stored as EXIF `orientation` says, the right way up.
+    fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+        match orientation {
+            2 => image.fliph(),
+            3 => image.rotate180(),
+            4 => image.flipv(),
+            5 => image.rotate90().fliph(),
+            6 => image.rotate90(),
+            7 => image.rotate270().fliph(),
+            8 => image.rotate270(),
+            _ => image,
+        }
+    }
+
+    // The orientation a JPEG's EXIF gives, if any.
+    fn jpeg_orientation(bytes: &[u8]) -> Option<u16> {
+        let mut pos = 2;
+        while let Some(&[0xff, marker, high, low]) = bytes.get(pos..pos + 4) {
+            if marker == 0xda {
+                break;
+            }
+            let end = pos + 2 + usize::from(u16::from_be_bytes([high, low]));
+            if marker == 0xe1 {
+                if let Some(orientation) = bytes.get(pos + 4..end).and_then(exif_orientation) {
+                    return Some(orientation);
+                }
+            }
+            pos = end;
+        }
+        None
+    }
+
+    fn strip_png(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+        const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+        let malformed = || ImageError::Malformed("PNG");
+        if !bytes.starts_with(SIGNATURE) {
+            return Err(malformed());
+        }
+
+        let mut out = SIGNATURE.to_vec();
+        let mut pos = SIGNATURE.len();
+        while pos < bytes.len() {
+            let header = bytes.get(pos..pos + 8).ok_or_else(malformed)?;
+            let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let kind = &header[4..8];
+            // Length, type, data and CRC.
+            let end = pos + 12 + length;
+            let chunk = bytes.get(pos..end).ok_or_else(malformed)?;
+
+            if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+                out.extend_from_slice(chunk);
+            }
+            pos = end;
+        }
+
+        Ok(out)
+    }
+
+    fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+        let malformed = || ImageError::Malformed("WebP");
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+            return Err(malformed());
+        }
+
+        let mut chunks = Vec::new();
+        let mut pos = 12;
+        while pos < bytes.len() {
+            let header = bytes.get(pos..pos + 8).ok_or_else(malformed)?;
+            let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            // Chunks are padded to an even length.
+            let end = pos + 8 + length + length % 2;
+            let chunk = bytes.get(pos..end).ok_or_else(malformed)?;
+
+            match &header[0..4] {
+                b"EXIF" | b"XMP " => (),
+                b"VP8X" if chunk.len() > 8 => {
+                    // Clear the "has EXIF" and "has XMP" flags to match.
+                    let mut chunk = chunk.to_vec();
+                    chunk[8] &= !(0x08 | 0x04);
+                    chunks.push(chunk);
+                }
+                _ => chunks.push(chunk.to_vec()),
+            }
+            pos = end;
+        }
+
+        let body: Vec<u8> = chunks.concat();
+        let riff_size = u32::try_from(body.len() + 4).map_err(|_| malformed())?;
+
+        let mut out = Vec::with_capacity(body.len() + 12);
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&riff_size.to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    // Writes a thumbnail `width` pixels wide (or the original width, if smaller)
+    // next to `original`, as `<original>.<width>`. JPEGs stay JPEGs; everything
+    // else becomes a PNG so that transparency survives. Thumbnails carry no EXIF,
+    // so photos are turned the right way up first.
+    pub fn make_thumbnail(original: &Path, content_type: &str, width: u32) -> Result<(), ImageError> {
+        let bytes = std::fs::read(original)?;
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_DIMENSION);
+        limits.max_image_height = Some(MAX_DIMENSION);
+        limits.max_alloc = Some(MAX_DECODE_BYTES);
+        let mut reader = image::io::Reader::new(Cursor::new(&bytes)).with_guessed_format()?;
+        reader.limits(limits);
+
+        let mut image = reader.decode()?;
+        if content_type == "image/jpeg" {
+            if let Some(orientation) = jpeg_orientation(&bytes) {
+                image = apply_orientation(image, orientation);
+            }
+        }
+        let thumbnail = if image.width() > width {
+            let height = (u64::from(image.height()) * u64::from(width) / u64::from(image.width())).max(1) as u32;
+            image.resize(width, height, FilterType::Triangle)
+        } else {
+            image
+        };
+
+        let format = thumbnail_format(content_type);
+        let mut encoded = Cursor::new(Vec::new());
+        match format {
+            ImageFormat::Jpeg => thumbnail.to_rgb8().write_to(&mut encoded, format)?,
+            _ => thumbnail.write_to(&mut encoded, format)?,
+        }
+
+        std::fs::write(thumbnail_path(original, width), encoded.into_inner())?;
+        Ok(())
+    }
+
+    pub fn thumbnail_path(original: &Path, width: u32) -> std::path::PathBuf {
+        let mut path = original.as_os_str().to_owned();
+        path.push(format!(".{width}"));
+        path.into()
+    }
+
+    pub fn thumbnail_content_type(content_type: &str) -> &'static str {
+        match thumbnail_format(content_type) {
+            ImageFormat::Jpeg => "image/jpeg",
+            _ => "image/png",
+        }
+    }
+
+    fn thumbnail_format(content_type: &str) -> ImageFormat {
+        match content_type {
+            "image/jpeg" => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbImage};
+    use std::fs;
+
+    // A 4x2 JPEG with an EXIF segment holding `orientation` and a (made up)
+    // GPS latitude reference, little-endian as phones write it.
+    fn photo(orientation: u16) -> Vec<u8> {
+        let mut jpeg = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(4, 2))
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+
+        let mut tiff = b"II\x2a\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        for (tag, value) in [(0x0001u16, u16::from(b'N')), (ORIENTATION_TAG, orientation)] {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&3u16.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+            tiff.extend_from_slice(&[0, 0]);
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut photo = jpeg[..2].to_vec();
+        photo.extend_from_slice(&[0xff, 0xe1]);
+        photo.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        photo.extend_from_slice(EXIF_HEADER);
+        photo.extend_from_slice(&tiff);
+        photo.extend_from_slice(&jpeg[2..]);
+        photo
+    }
+
+    #[test]
+    fn stripping_keeps_only_the_orientation() {
+        let stripped = strip_metadata("image/jpeg", &photo(6)).unwrap();
+
+        assert_eq!(jpeg_orientation(&stripped), Some(6));
+        let exif = stripped.windows(EXIF_HEADER.len()).position(|window| window == EXIF_HEADER).unwrap();
+        assert_eq!(&stripped[exif - 4..exif + EXIF_HEADER.len() + 26], &orientation_segment(6)[..]);
+        assert_eq!(stripped.windows(EXIF_HEADER.len()).filter(|window| *window == EXIF_HEADER).count(), 1);
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn upright_photos_lose_their_exif_altogether() {
+        let stripped = strip_metadata("image/jpeg", &photo(1)).unwrap();
+        assert_eq!(jpeg_orientation(&stripped), None);
+        assert!(!stripped.windows(EXIF_HEADER.len()).any(|window| window == EXIF_HEADER));
+    }
+
+    #[test]
+    fn thumbnails_are_turned_the_right_way_up() {
+        let dir = std::env::temp_dir().join(format!("images-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("photo");
+        fs::write(&original, strip_metadata("image/jpeg", &photo(6)).unwrap()).unwrap();
+
+        make_thumbnail(&original, "image/jpeg", 160).unwrap();
+        let thumbnail = fs::read(thumbnail_path(&original, 160)).unwrap();
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (2, 4));
+    }
+
+    #[test]
+    fn images_claiming_to_be_huge_are_not_decoded() {
+        let dir = std::env::temp_dir().join(format!("images-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("huge");
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(1, 1))
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let mut png = png.into_inner();
+        // IHDR's width and height, then its CRC over type and data.
+        png[16..20].copy_from_slice(&(MAX_DIMENSION + 1).to_be_bytes());
+        png[20..24].copy_from_slice(&(MAX_DIMENSION + 1).to_be_bytes());
+        let mut crc = flate2::Crc::new();
+        crc.update(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.sum().to_be_bytes());
+        fs::write(&original, png).unwrap();
+
+        let made = make_thumbnail(&original, "image/png", 160);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(made, Err(ImageError::Image(image::ImageError::Limits(_)))), "{made:?}");
+    }
+}
@@ -1,7 +1,28 @@
 use cfg_if::cfg_if;
 pub mod app;
+pub mod attachments;
+pub mod bot;
+pub mod chat;
+pub mod codec;
+pub mod commands;
+pub mod csrf;
 pub mod error_template;
 pub mod fileserv;
+pub mod highlight;
+pub mod history;
+pub mod images;
+pub mod listen;
+pub mod markdown;
+pub mod mentions;
+pub mod metrics;
+pub mod notify;
+pub mod presence;
+pub mod rooms;
+pub mod search;
+pub mod security;
+pub mod tls;
+pub mod unfurl;
+pub mod webhooks;
 pub mod ws;
 
 cfg_if! { if #[cfg(feature = "hydrate")] {
@@ -0,0 +1,314 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::tls::{serve_tls, ReloadingCert};
+    use axum::{
+        extract::ConnectInfo,
+        http::{HeaderMap, Request},
+        Router,
+    };
+    use hyper::{server::conn::Http, Body};
+    use std::{
+        env, fmt, fs, io,
+        net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+        os::{
+            fd::{FromRawFd, IntoRawFd, RawFd},
+            unix::{
+                fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+                net::{UnixListener, UnixStream},
+            },
+        },
+        path::{Path, PathBuf},
+        process,
+        sync::Arc,
+        time::Duration,
+    };
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    // Where systemd's file descriptors start; 0 to 2 are stdin, stdout and
+    // stderr.
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    // How long we wait after failing to accept a connection. That's mostly for
+    // lack of file descriptors, which trying again right away won't fix.
+    pub const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+
+    // Something we accept connections on. A Unix socket we moved after binding
+    // still goes by the name it was bound to, so we keep where it is now.
+    #[derive(Debug)]
+    pub enum Listener {
+        Tcp(TcpListener),
+        Unix(UnixListener, Option<PathBuf>),
+    }
+
+    impl fmt::Display for Listener {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Listener::Tcp(listener) => match listener.local_addr() {
+                    Ok(addr) => write!(f, "{addr}"),
+                    Err(_) => write!(f, "a TCP socket"),
+                },
+                Listener::Unix(_, Some(path)) => write!(f, "unix:{}", path.display()),
+                Listener::Unix(listener, None) => match listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(Path::to_owned)) {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "an unnamed Unix socket"),
+                },
+            }
+        }
+    }
+
+    // A Unix socket to serve on as well, for a proxy on the same machine, from
+    // `CHAT_UNIX_SOCKET` (a path). `CHAT_UNIX_SOCKET_MODE` (octal, default 660)
+    // decides who may connect: by default its owner and group, which should be
+    // the proxy's.
+    #[derive(Debug, Clone)]
+    pub struct UnixSocketConfig {
+        pub path: PathBuf,
+        pub mode: u32,
+    }
+
+    impl UnixSocketConfig {
+        pub fn from_env() -> Option<Self> {
+            let path = env::var_os("CHAT_UNIX_SOCKET")?.into();
+            let mode = match env::var("CHAT_UNIX_SOCKET_MODE") {
+                Ok(mode) => u32::from_str_radix(&mode, 8).unwrap_or_else(|err| {
+                    tracing::error!("Ignoring CHAT_UNIX_SOCKET_MODE={mode:?}: {err}");
+                    0o660
+                }),
+                Err(_) => 0o660,
+            };
+
+            Some(UnixSocketConfig { path, mode })
+        }
+
+        // Binding makes the socket with whatever mode our umask leaves, and
+        // anyone that lets in could connect before we change it. So we bind in
+        // a directory only we can enter, set the mode there, and only then move
+        // the socket into place.
+        pub fn bind(&self) -> io::Result<Listener> {
+            remove_stale_socket(&self.path)?;
+
+            // Socket paths can't be long, so the detour is kept short.
+            let parent = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let private = parent.join(format!(".{}", &Uuid::new_v4().simple().to_string()[..12]));
+            fs::DirBuilder::new().mode(0o700).create(&private)?;
+            let bound = self.bind_in(&private);
+            if let Err(err) = fs::remove_dir_all(&private) {
+                tracing::warn!("Couldn't remove {}: {err}", private.display());
+            }
+
+            Ok(Listener::Unix(bound?, Some(self.path.clone())))
+        }
+
+        fn bind_in(&self, private: &Path) -> io::Result<UnixListener> {
+            let path = private.join("s");
+            let listener = UnixListener::bind(&path)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(self.mode))?;
+            fs::rename(&path, &self.path)?;
+            Ok(listener)
+        }
+    }
+
+    // A socket file left behind by a server that's gone would keep us from
+    // binding, so we remove it. We leave alone anything that isn't a socket,
+    // and sockets someone is still listening on.
+    fn remove_stale_socket(path: &Path) -> io::Result<()> {
+        let meta = match fs::symlink_metadata(path) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+
+        match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("something is already listening on {}", path.display()),
+            )),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                tracing::info!("Removing the stale socket {}", path.display());
+                fs::remove_file(path)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Listeners systemd opened for us with socket activation: `LISTEN_FDS` file
+    // descriptors from 3 on, if `LISTEN_PID` says they're for us.
+    pub fn inherited_listeners() -> Vec<Listener> {
+        let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(process::id());
+        let count: RawFd = env::var("LISTEN_FDS").ok().and_then(|count| count.parse().ok()).unwrap_or(0);
+        // Anything we start mustn't take them for its own.
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+        if !for_us {
+            return Vec::new();
+        }
+
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .filter_map(|fd| {
+                // SAFETY: systemd hands these descriptors to us alone, open, and
+                // we take each one exactly once.
+                let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+                if tcp.local_addr().is_ok() {
+                    return Some(Listener::Tcp(tcp));
+                }
+                // SAFETY: the same descriptor, which `tcp` gives up.
+                let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                match unix.local_addr() {
+                    Ok(_) => Some(Listener::Unix(unix, None)),
+                    Err(err) => {
+                        tracing::error!("Ignoring inherited file descriptor {fd}: {err}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // Serves `app` on `listener` until that fails: over TLS with `cert` for TCP,
+    // and plain HTTP on Unix sockets, which only a proxy in front of us uses.
+    pub async fn serve(listener: Listener, app: Router, cert: Option<Arc<ReloadingCert>>) {
+        let name = listener.to_string();
+        let served = match (listener, cert) {
+            (Listener::Tcp(listener), Some(cert)) => serve_tls(listener, app, cert).await,
+            (Listener::Tcp(listener), None) => serve_tcp(listener, app).await,
+            (Listener::Unix(listener, _), _) => serve_unix(listener, app).await,
+        };
+        if let Err(err) = served {
+            tracing::error!("Serving {name} failed: {err}");
+        }
+    }
+
+    async fn serve_tcp(listener: TcpListener, app: Router) -> io::Result<()> {
+        // `axum::Server` is a re-export of `hyper::Server`
+        axum::Server::from_tcp(listener)
+            .map_err(io::Error::other)?
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn serve_unix(listener: UnixListener, app: Router) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _addr)) => stream,
+                Err(err) => {
+                    tracing::warn!("Accepting a connection failed: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                    continue;
+                }
+            };
+
+            let service = app.clone().map_request(|mut req: Request<Body>| {
+                let peer = SocketAddr::new(forwarded_for(req.headers()), 0);
+                req.extensions_mut().insert(ConnectInfo(peer));
+                req
+            });
+            tokio::spawn(async move {
+                // Upgrades are how websockets get their connection.
+                if let Err(err) = Http::new().serve_connection(stream, service).with_upgrades().await {
+                    tracing::debug!("Connection on a Unix socket failed: {err}");
+                }
+            });
+        }
+    }
+
+    // Who the proxy in front of us says a request is from. Only it can reach our
+    // Unix socket, so we believe it; without a word from it, we call it local.
+    // Clients can send an `X-Forwarded-For` of their own, which the proxy adds
+    // to, so only its last entry is the proxy's.
+    fn forwarded_for(headers: &HeaderMap) -> IpAddr {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        header("x-real-ip")
+            .or_else(|| header("x-forwarded-for").and_then(|ips| ips.rsplit(',').next()))
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn the_proxy_says_who_it_is() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(forwarded_for(&headers(&[])), ip("127.0.0.1"));
+        assert_eq!(
+            forwarded_for(&headers(&[("x-real-ip", "203.0.113.7"), ("x-forwarded-for", "10.0.0.1")])),
+            ip("203.0.113.7")
+        );
+        // The first entries are whatever the client sent.
+        assert_eq!(
+            forwarded_for(&headers(&[("x-forwarded-for", "10.0.0.1, 198.51.100.2, 203.0.113.7")])),
+            ip("203.0.113.7")
+        );
+        assert_eq!(forwarded_for(&headers(&[("x-forwarded-for", "203.0.113.7, nonsense")])), ip("127.0.0.1"));
+    }
+
+    struct Dir(PathBuf);
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn dir() -> Dir {
+        let dir = env::temp_dir().join(format!("listen-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        Dir(dir)
+    }
+
+    #[test]
+    fn sockets_only_appear_with_their_mode() {
+        let dir = dir();
+        let config = UnixSocketConfig { path: dir.0.join("chat.sock"), mode: 0o600 };
+        let listener = config.bind().unwrap();
+        assert_eq!(listener.to_string(), format!("unix:{}", config.path.display()));
+
+        let meta = fs::symlink_metadata(&config.path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        UnixStream::connect(&config.path).unwrap();
+
+        // Nothing is left of the detour.
+        let names: Vec<_> = fs::read_dir(&dir.0).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["chat.sock"]);
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced_and_live_ones_kept() {
+        let dir = dir();
+        let config = UnixSocketConfig { path: dir.0.join("chat.sock"), mode: 0o660 };
+        drop(UnixListener::bind(&config.path).unwrap());
+        let listener = config.bind().unwrap();
+
+        assert_eq!(config.bind().unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+
+        fs::remove_file(&config.path).unwrap();
+        fs::write(&config.path, "not a socket").unwrap();
+        assert_eq!(config.bind().unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+}
@@ -4,9 +4,9 @@ async fn main() {
     //use axum::{routing::{post, get}, Router};
     use axum::routing::post;
     use leptos::*;
-    use leptos_axum::{generate_route_list, LeptosRoutes};
+    use leptos_axum::{generate_route_list, handle_server_fns_with_context, LeptosRoutes};
     use web_app_axum::app::*;
-    use web_app_axum::fileserv::file_and_error_handler;
+    use web_app_axum::fileserv::{compression_layer, file_and_error_handler, html_cache_control};
 
     tracing_subscriber::registry()
         .with(
@@ -26,27 +26,98 @@ async fn main() {
     let addr = leptos_options.site_addr;
     let routes = generate_route_list(App);
 
-    let user_set = Mutex::new(HashSet::new());
-    let (tx, _rx) = broadcast::channel(100);
+    let app_state = Arc::new(AppState::new());
+    spawn_webhooks(&app_state);
 
-    let app_state = Arc::new(AppState { user_set, tx });
+    // Server functions and pages rendered on the server reach the chat through
+    // the context.
+    let context_state = app_state.clone();
+    let provide_state = move || provide_context(context_state.clone());
+    let server_fn_context = provide_state.clone();
 
     // build our application with a route
     let app = Router::new()
-        .route("/api/*fn_name", post(leptos_axum::handle_server_fns))
+        .route(
+            "/api/*fn_name",
+            post(move |path, headers, query, req| async move {
+                let res = handle_server_fns_with_context(path, headers, query, server_fn_context, req).await;
+                server_fn_error_status(res.into_response()).await
+            }),
+        )
         .route("/websocket", get(websocket_handler))
-        .with_state(app_state)
-        .leptos_routes(&leptos_options, routes, App)
+        .route("/events", get(events_handler))
+        .route("/events/:id", post(post_event_handler))
+        .route("/metrics", get(metrics_handler))
+        .route(
+            CSP_REPORT_PATH,
+            post(csp_report_handler).layer(DefaultBodyLimit::max(MAX_CSP_REPORT_BYTES)),
+        )
+        .route(
+            "/attachments",
+            post(upload_handler).layer(DefaultBodyLimit::max(MAX_ATTACHMENT_BYTES + 64 * 1024)),
+        )
+        .route("/attachments/:id", get(download_handler))
+        .route("/attachments/:id/thumbnail/:width", get(thumbnail_handler))
+        .route("/bot/rooms", get(list_rooms_handler))
+        .route("/bot/rooms/:room/messages", get(history_handler).post(post_message_handler))
+        .with_state(app_state.clone())
+        .leptos_routes_with_context(&leptos_options, routes, provide_state, App)
         .fallback(file_and_error_handler)
-        .with_state(leptos_options);
+        .with_state(leptos_options)
+        .layer(SetResponseHeaderLayer::if_not_present(header::CACHE_CONTROL, html_cache_control))
+        .layer(compression_layer())
+        .layer(middleware::from_fn_with_state(app_state, csrf_protection))
+        .layer(middleware::from_fn_with_state(CspMode::from_env(), security_headers))
+        // Every request gets an `x-request-id`, sent back with the response and
+        // shown on error pages, so people can tell us which request went wrong.
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    // With a certificate we serve HTTPS ourselves. Without one it's plain HTTP,
+    // as behind a proxy that takes care of HTTPS.
+    let tls = TlsConfig::from_env();
+    let cert = tls.as_ref().map(|tls| {
+        let cert = ReloadingCert::load(&tls.cert, &tls.key).expect("Failed to load the TLS certificate");
+        let cert = Arc::new(cert);
+        cert.spawn_reload(RELOAD_INTERVAL);
+        cert
+    });
+    if let Some(redirect_addr) = tls.and_then(|tls| tls.redirect_addr) {
+        let listener = TcpListener::bind(redirect_addr).expect("Failed to bind the HTTP redirect");
+        logging::log!("redirecting http://{} to https", &redirect_addr);
+        tokio::spawn(async move {
+            if let Err(err) = serve_redirect(listener, addr.port()).await {
+                tracing::error!("Redirecting HTTP to HTTPS failed: {err}");
+            }
+        });
+    }
+
+    // Next to `site_addr` we serve on the sockets systemd opened for us, one of
+    // which may be `site_addr` itself, and on our own Unix socket.
+    let mut listeners = inherited_listeners();
+    if let Some(unix_socket) = UnixSocketConfig::from_env() {
+        listeners.push(unix_socket.bind().expect("Failed to bind the Unix socket"));
+    }
+    let inherited_addr = listeners.iter().any(|listener| match listener {
+        Listener::Tcp(listener) => listener.local_addr().ok() == Some(addr),
+        Listener::Unix(..) => false,
+    });
+    if !inherited_addr {
+        listeners.push(Listener::Tcp(TcpListener::bind(addr).expect("Failed to bind")));
+    }
 
-    // run our app with hyper
-    // `axum::Server` is a re-export of `hyper::Server`
-    logging::log!("listening on http://{}", &addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .expect("Failed to bind");
+    let scheme = if cert.is_some() { "https" } else { "http" };
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            match listener {
+                Listener::Tcp(_) => logging::log!("listening on {scheme}://{listener}"),
+                Listener::Unix(..) => logging::log!("listening on {listener}"),
+            }
+            tokio::spawn(serve(listener, app.clone(), cert.clone()))
+        })
+        .collect();
+    future::join_all(servers).await;
 }
 
 #[cfg(not(feature = "ssr"))]
@@ -59,122 +130,24 @@ pub fn main() {
 use cfg_if::cfg_if;
 
 cfg_if! { if #[cfg(feature = "ssr")] {
-    use axum::{
-        extract::{
-            ws::{Message, WebSocket, WebSocketUpgrade},
-            State,
-        },
-        response::IntoResponse,
-        routing::get,
-        Router,
+    use axum::{extract::DefaultBodyLimit, http::header, middleware, response::IntoResponse, routing::get, Router};
+    use futures::future;
+    use std::{net::TcpListener, sync::Arc};
+    use tower_http::{
+        request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+        set_header::SetResponseHeaderLayer,
     };
-    use futures::{sink::SinkExt, stream::StreamExt};
-    use std::{
-        collections::HashSet,
-        sync::{Arc, Mutex},
-    };
-    use tokio::sync::broadcast;
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-    
-    // Our shared state
-    struct AppState {
-        // We require unique usernames. This tracks which usernames have been taken.
-        user_set: Mutex<HashSet<String>>,
-        // Channel used to send messages to all connected clients.
-        tx: broadcast::Sender<String>,
-    }
-    
-    async fn websocket_handler(
-        ws: WebSocketUpgrade,
-        State(state): State<Arc<AppState>>,
-    ) -> impl IntoResponse {
-        ws.on_upgrade(|socket| websocket(socket, state))
-    }
-    
-    // This function deals with a single websocket connection, i.e., a single
-    // connected client / user, for which we will spawn two independent tasks (for
-    // receiving / sending chat messages).
-    async fn websocket(stream: WebSocket, state: Arc<AppState>) {
-        // By splitting, we can send and receive at the same time.
-        let (mut sender, mut receiver) = stream.split();
-
-        // Username gets set in the receive loop, if it's valid.
-        let mut username = String::new();
-        // Loop until a text message is found.
-        while let Some(Ok(message)) = receiver.next().await {
-            if let Message::Text(name) = message {
-                // If username that is sent by client is not taken, fill username string.
-                check_username(&state, &mut username, &name);
-
-                // If not empty we want to quit the loop else we want to quit function.
-                if !username.is_empty() {
-                    break;
-                } else {
-                    // Only send our client that username is taken.
-                    let _ = sender
-                        .send(Message::Text(String::from("Username already taken.")))
-                        .await;
-
-                    return;
-                }
-            }
-        }
-
-        // We subscribe *before* sending the "joined" message, so that we will also
-        // display it to our client.
-        let mut rx = state.tx.subscribe();
-
-        // Now send the "joined" message to all subscribers.
-        let msg = format!("{username} joined.");
-        tracing::debug!("{msg}");
-        let _ = state.tx.send(msg);
-
-        // Spawn the first task that will receive broadcast messages and send text
-        // messages over the websocket to our client.
-        let mut send_task = tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                // In any websocket error, break loop.
-                if sender.send(Message::Text(msg)).await.is_err() {
-                    break;
-                }
-            }
-        });
-
-        // Clone things we want to pass (move) to the receiving task.
-        let tx = state.tx.clone();
-        let name = username.clone();
-
-        // Spawn a task that takes messages from the websocket, prepends the user
-        // name, and sends them to all broadcast subscribers.
-        let mut recv_task = tokio::spawn(async move {
-            while let Some(Ok(Message::Text(text))) = receiver.next().await {
-                // Add username before message.
-                let _ = tx.send(format!("{{\"sender\": {name}, \"msg\": {text}}}"));
-            }
-        });
-
-        // If any one of the tasks run to completion, we abort the other.
-        tokio::select! {
-            _ = (&mut send_task) => recv_task.abort(),
-            _ = (&mut recv_task) => send_task.abort(),
-        };
-
-        // Send "user left" message (similar to "joined" above).
-        let msg = format!("{username} left.");
-        tracing::debug!("{msg}");
-        let _ = state.tx.send(msg);
-
-        // Remove username from map so new clients can take it again.
-        state.user_set.lock().unwrap().remove(&username);
-    }
-    
-    fn check_username(state: &AppState, string: &mut String, name: &str) {
-        let mut user_set = state.user_set.lock().unwrap();
-    
-        if !user_set.contains(name) {
-            user_set.insert(name.to_owned());
-    
-            string.push_str(name);
-        }
-    }  
-}}
\ No newline at end of file
+    use web_app_axum::{
+        attachments::{download_handler, thumbnail_handler, upload_handler, MAX_ATTACHMENT_BYTES},
+        bot::{history_handler, list_rooms_handler, post_message_handler},
+        chat::{events_handler, post_event_handler, websocket_handler, AppState},
+        csrf::csrf_protection,
+        error_template::server_fn_error_status,
+        listen::{inherited_listeners, serve, Listener, UnixSocketConfig},
+        metrics::metrics_handler,
+        security::{csp_report_handler, security_headers, CspMode, CSP_REPORT_PATH, MAX_CSP_REPORT_BYTES},
+        tls::{serve_redirect, ReloadingCert, TlsConfig, RELOAD_INTERVAL},
+        webhooks::spawn_webhooks,
+    };
+}}
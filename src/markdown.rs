@@ -0,0 +1,442 @@
+use crate::{highlight::highlight, mentions::is_name_char};
+use leptos::*;
+
+// The small Markdown dialect chat messages are written in: **bold**, *italics*
+// (or _italics_), `code`, fenced code blocks, [links](https://…) and > quotes.
+// Anything else is shown as the text it is, except that `@name`s the server
+// confirmed as mentions stand out. Messages are turned into views
+// rather than HTML strings, so what someone types can never become markup.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Block {
+    Paragraph(Vec<Inline>),
+    Quote(Vec<Block>),
+    Code { lang: Option<String>, code: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Inline {
+    Text(String),
+    Strong(Vec<Inline>),
+    Emphasis(Vec<Inline>),
+    Code(String),
+    Link { text: Vec<Inline>, href: String },
+}
+
+const FENCE: &str = "```";
+
+// Quotes nest at most this deep, and so do emphasis and links; anything deeper
+// is shown as written. Every client parses every message, so no message may
+// recurse until the stack runs out.
+pub const MAX_DEPTH: usize = 8;
+
+// Anything longer is shown as the text it is, without being parsed.
+pub const MAX_SOURCE_BYTES: usize = 32 * 1024;
+
+pub fn parse(source: &str) -> Vec<Block> {
+    if source.len() > MAX_SOURCE_BYTES {
+        return vec![Block::Paragraph(vec![Inline::Text(source.to_owned())])];
+    }
+
+    let lines: Vec<&str> = source.lines().collect();
+    parse_blocks(&lines, 0)
+}
+
+fn parse_blocks(lines: &[&str], depth: usize) -> Vec<Block> {
+    // Past the deepest quote, `>` is just a character.
+    let is_quote = |line: &str| depth < MAX_DEPTH && line.starts_with('>');
+    let mut blocks = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+
+        if let Some(lang) = line.trim_start().strip_prefix(FENCE) {
+            // Everything up to the closing fence, or the end of the message.
+            let lang = Some(lang.trim().to_owned()).filter(|lang| !lang.is_empty());
+            let end = lines[i + 1..]
+                .iter()
+                .position(|line| line.trim_start().starts_with(FENCE))
+                .map_or(lines.len(), |end| i + 1 + end);
+            let code = lines[i + 1..end].join("\n");
+            blocks.push(Block::Code { lang, code });
+            i = end + 1;
+        } else if is_quote(line) {
+            let end = lines[i..]
+                .iter()
+                .position(|line| !line.starts_with('>'))
+                .map_or(lines.len(), |end| i + end);
+            let quoted: Vec<&str> = lines[i..end]
+                .iter()
+                .map(|line| {
+                    let line = &line[1..];
+                    line.strip_prefix(' ').unwrap_or(line)
+                })
+                .collect();
+            blocks.push(Block::Quote(parse_blocks(&quoted, depth + 1)));
+            i = end;
+        } else if line.trim().is_empty() {
+            i += 1;
+        } else {
+            // A paragraph runs until a blank line or the start of another block.
+            let end = lines[i + 1..]
+                .iter()
+                .position(|line| line.trim().is_empty() || is_quote(line) || line.trim_start().starts_with(FENCE))
+                .map_or(lines.len(), |end| i + 1 + end);
+            blocks.push(Block::Paragraph(parse_inlines(&lines[i..end].join("\n"))));
+            i = end;
+        }
+    }
+
+    blocks
+}
+
+pub fn parse_inlines(text: &str) -> Vec<Inline> {
+    parse_nested_inlines(text, 0)
+}
+
+fn parse_nested_inlines(text: &str, depth: usize) -> Vec<Inline> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    if depth >= MAX_DEPTH {
+        return vec![Inline::Text(text.to_owned())];
+    }
+
+    let markers = Markers::new(text);
+    let mut inlines = Vec::new();
+    let mut plain = String::new();
+    let mut pos = 0;
+
+    while let Some(c) = text[pos..].chars().next() {
+        let parsed = match c {
+            '\\' => {
+                // A backslash keeps the next punctuation character literal.
+                match text[pos + 1..].chars().next() {
+                    Some(next) if next.is_ascii_punctuation() => {
+                        plain.push(next);
+                        pos += 1 + next.len_utf8();
+                        continue;
+                    }
+                    _ => None,
+                }
+            }
+            '`' => code_span(text, pos),
+            '*' | '_' => markers.emphasis(text, pos, &plain, depth),
+            '[' => markers.link(text, pos, depth),
+            _ => None,
+        };
+
+        match parsed {
+            Some((inline, end)) => {
+                if !plain.is_empty() {
+                    inlines.push(Inline::Text(std::mem::take(&mut plain)));
+                }
+                inlines.push(inline);
+                pos = end;
+            }
+            None => {
+                plain.push(c);
+                pos += c.len_utf8();
+            }
+        }
+    }
+
+    if !plain.is_empty() {
+        inlines.push(Inline::Text(plain));
+    }
+    inlines
+}
+
+// The code span starting at `start`, and where it ends. Only the first
+// backtick without a match looks to the end of the text, since there are no
+// more after it.
+fn code_span(text: &str, start: usize) -> Option<(Inline, usize)> {
+    let end = start + 1 + text[start + 1..].find('`')?;
+    let code = &text[start + 1..end];
+    if code.is_empty() {
+        return None;
+    }
+
+    Some((Inline::Code(code.to_owned()), end + 1))
+}
+
+// Emphasis delimiters, with their index in `Markers::closers`.
+const DELIMITERS: [&str; 4] = ["*", "**", "_", "__"];
+
+// Where everything that can close an emphasis or a link is, found in one pass
+// over the text. Each opening marker then finds its close by a binary search,
+// rather than by searching the rest of the text again.
+struct Markers {
+    // Starts of `](`, and of each `)` and `[`.
+    link_middles: Vec<usize>,
+    close_parens: Vec<usize>,
+    open_brackets: Vec<usize>,
+    // Where each of `DELIMITERS` could close emphasis, going by the text
+    // around it.
+    closers: [Vec<usize>; 4],
+}
+
+impl Markers {
+    fn new(text: &str) -> Self {
+        let bytes = text.as_bytes();
+        let mut markers = Markers {
+            link_middles: Vec::new(),
+            close_parens: Vec::new(),
+            open_brackets: Vec::new(),
+            closers: Default::default(),
+        };
+
+        for (pos, &byte) in bytes.iter().enumerate() {
+            match byte {
+                b'[' => markers.open_brackets.push(pos),
+                b')' => markers.close_parens.push(pos),
+                b']' if bytes.get(pos + 1) == Some(&b'(') => markers.link_middles.push(pos),
+                b'*' | b'_' => {
+                    for (index, delimiter) in DELIMITERS.iter().enumerate() {
+                        if text[pos..].starts_with(delimiter) && closes(text, pos, delimiter) {
+                            markers.closers[index].push(pos);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        markers
+    }
+
+    // `**bold**`, `__bold__`, `*italics*` or `_italics_` at `start`. The text
+    // inside can't start or end with a space, and underscores only count at
+    // word boundaries so that snake_case stays as written.
+    fn emphasis(&self, text: &str, start: usize, before: &str, depth: usize) -> Option<(Inline, usize)> {
+        let rest = &text[start..];
+        let marker = rest.chars().next()?;
+        let double = rest[1..].starts_with(marker);
+        let delimiter = if double { &rest[..2] } else { &rest[..1] };
+
+        if marker == '_' && before.chars().last().is_some_and(char::is_alphanumeric) {
+            return None;
+        }
+        let inner_start = start + delimiter.len();
+        if text[inner_start..].starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        let index = DELIMITERS.iter().position(|known| *known == delimiter)?;
+        let end = first_from(&self.closers[index], inner_start + 1)?;
+        let children = parse_nested_inlines(&text[inner_start..end], depth + 1);
+        let inline = if double {
+            Inline::Strong(children)
+        } else {
+            Inline::Emphasis(children)
+        };
+        Some((inline, end + delimiter.len()))
+    }
+
+    // `[text](href)` at `start`, as long as `href` is somewhere it's safe to
+    // send people.
+    fn link(&self, text: &str, start: usize, depth: usize) -> Option<(Inline, usize)> {
+        let text_end = first_from(&self.link_middles, start + 1)?;
+        let href_start = text_end + 2;
+        let href_end = first_from(&self.close_parens, href_start)?;
+
+        let nested = first_from(&self.open_brackets, start + 1).is_some_and(|open| open < text_end);
+        let href = text[href_start..href_end].trim();
+        if text_end == start + 1 || nested || !is_safe_href(href) {
+            return None;
+        }
+
+        Some((
+            Inline::Link {
+                text: parse_nested_inlines(&text[start + 1..text_end], depth + 1),
+                href: href.to_owned(),
+            },
+            href_end + 1,
+        ))
+    }
+}
+
+// Whether `delimiter` at `pos` can close emphasis opened before it: it has
+// to follow something other than a space, and a single marker next to another
+// one belongs to a double.
+fn closes(text: &str, pos: usize, delimiter: &str) -> bool {
+    let marker = delimiter.chars().next().unwrap_or_default();
+    let double = delimiter.len() == 2;
+    let last = text[..pos].chars().last();
+    let after = &text[pos + delimiter.len()..];
+
+    last.is_some_and(|last| !last.is_whitespace())
+        && (double || !(after.starts_with(marker) || last == Some(marker)))
+        && (marker != '_' || !after.starts_with(char::is_alphanumeric))
+}
+
+// The first of `positions`, which are sorted, at or after `from`.
+fn first_from(positions: &[usize], from: usize) -> Option<usize> {
+    positions.get(positions.partition_point(|&pos| pos < from)).copied()
+}
+
+// Only links that leave for another page or a mail client; `javascript:` and
+// friends would run in our page.
+pub fn is_safe_href(href: &str) -> bool {
+    let lower = href.to_ascii_lowercase();
+    ["http://", "https://", "mailto:"]
+        .iter()
+        .any(|scheme| lower.starts_with(scheme))
+        && !href.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+#[component]
+pub fn Markdown(
+    #[prop(into)] source: String,
+    // Names to show as mentions.
+    #[prop(optional)] mentions: Vec<String>,
+) -> impl IntoView {
+    view_blocks(parse(&source), &mentions)
+}
+
+fn view_blocks(blocks: Vec<Block>, mentions: &[String]) -> View {
+    blocks
+        .into_iter()
+        .map(|block| match block {
+            Block::Paragraph(inlines) => view! { <p>{view_inlines(inlines, mentions)}</p> }.into_view(),
+            Block::Quote(blocks) => {
+                view! { <blockquote>{view_blocks(blocks, mentions)}</blockquote> }.into_view()
+            }
+            Block::Code { lang, code } => {
+                let tokens = highlight(lang.as_deref(), &code)
+                    .into_iter()
+                    .map(|(kind, text)| view! { <span class=kind.class()>{text}</span> })
+                    .collect_view();
+                view! { <pre><code>{tokens}</code></pre> }.into_view()
+            }
+        })
+        .collect_view()
+}
+
+fn view_inlines(inlines: Vec<Inline>, mentions: &[String]) -> View {
+    inlines
+        .into_iter()
+        .map(|inline| match inline {
+            Inline::Text(text) => view_text(text, mentions),
+            Inline::Strong(children) => {
+                view! { <strong>{view_inlines(children, mentions)}</strong> }.into_view()
+            }
+            Inline::Emphasis(children) => view! { <em>{view_inlines(children, mentions)}</em> }.into_view(),
+            Inline::Code(code) => view! { <code>{code}</code> }.into_view(),
+            Inline::Link { text, href } => view! {
+                <a href=href target="_blank" rel="noopener noreferrer nofollow">{view_inlines(text, mentions)}</a>
+            }
+            .into_view(),
+        })
+        .collect_view()
+}
+
+// Plain text, with each `@name` from `mentions` in its own span.
+fn view_text(text: String, mentions: &[String]) -> View {
+    if mentions.is_empty() {
+        return text.into_view();
+    }
+
+    let mut views = Vec::new();
+    let mut plain_start = 0;
+    let mut pos = 0;
+    while let Some(at) = text[pos..].find('@').map(|at| pos + at) {
+        let rest = &text[at + 1..];
+        let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        let name = rest[..end].trim_end_matches('.');
+        let follows_word = text[..at].chars().last().is_some_and(char::is_alphanumeric);
+
+        if !follows_word && mentions.iter().any(|mention| mention == name) {
+            views.push(text[plain_start..at].to_owned().into_view());
+            let mention = text[at..at + 1 + name.len()].to_owned();
+            views.push(view! { <span class="mention">{mention}</span> }.into_view());
+            plain_start = at + 1 + name.len();
+        }
+        pos = at + 1;
+    }
+    views.push(text[plain_start..].to_owned().into_view());
+
+    views.into_view()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Inline {
+        Inline::Text(text.to_owned())
+    }
+
+    #[test]
+    fn markup_stays_text() {
+        let source = "<script>alert(1)</script> <img src=x onerror=alert(1)>";
+        assert_eq!(parse(source), vec![Block::Paragraph(vec![text(source)])]);
+    }
+
+    #[test]
+    fn links_only_go_to_other_pages() {
+        assert_eq!(
+            parse_inlines("[docs](https://example.com/a)"),
+            vec![Inline::Link { text: vec![text("docs")], href: "https://example.com/a".to_owned() }]
+        );
+        assert!(is_safe_href("mailto:someone@example.com"));
+
+        for href in ["javascript:alert(1)", "JaVaScRiPt:alert(1)", "data:text/html,hi", "/relative", "https://a b"] {
+            let source = format!("[click]({href})");
+            assert_eq!(parse_inlines(&source), vec![text(&source)], "{href}");
+        }
+    }
+
+    #[test]
+    fn emphasis_and_snake_case() {
+        assert_eq!(
+            parse_inlines("**bold** and *it*"),
+            vec![Inline::Strong(vec![text("bold")]), text(" and "), Inline::Emphasis(vec![text("it")])]
+        );
+        assert_eq!(parse_inlines("snake_case_name"), vec![text("snake_case_name")]);
+        assert_eq!(parse_inlines("* not emphasis *"), vec![text("* not emphasis *")]);
+    }
+
+    #[test]
+    fn quotes_nest_only_so_deep() {
+        let mut blocks = parse(&">".repeat(10_000));
+        let mut depth = 0;
+        while let [Block::Quote(inner)] = blocks.as_slice() {
+            blocks = inner.clone();
+            depth += 1;
+        }
+        assert_eq!(depth, MAX_DEPTH);
+        assert!(matches!(blocks.as_slice(), [Block::Paragraph(_)]));
+    }
+
+    #[test]
+    fn emphasis_nests_only_so_deep() {
+        let source = "*_**__x__**_*";
+        let strong = Inline::Strong(vec![Inline::Strong(vec![text("x")])]);
+        assert_eq!(
+            parse_inlines(source),
+            vec![Inline::Emphasis(vec![Inline::Emphasis(vec![strong])])]
+        );
+        // Two levels from the limit, the rest stays as written.
+        assert_eq!(
+            parse_nested_inlines(source, MAX_DEPTH - 2),
+            vec![Inline::Emphasis(vec![Inline::Emphasis(vec![text("**__x__**")])])]
+        );
+    }
+
+    #[test]
+    fn unclosed_markers_take_linear_time() {
+        // With a search to the end of the text for each of these, they'd
+        // take billions of steps.
+        for source in ["[".repeat(30_000), "[a](".repeat(7_000), "* ".repeat(15_000), "*a".repeat(15_000)] {
+            let inlines = parse_inlines(&source);
+            assert!(!inlines.is_empty());
+        }
+    }
+
+    #[test]
+    fn long_messages_are_not_parsed() {
+        let source = "**a** ".repeat(MAX_SOURCE_BYTES);
+        assert_eq!(parse(&source), vec![Block::Paragraph(vec![text(&source)])]);
+    }
+}
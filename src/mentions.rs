@@ -0,0 +1,201 @@
+use cfg_if::cfg_if;
+
+// `@name` in a message mentions `name`. Names are letters, digits, `_`, `-` and
+// `.`, and the `@` can't follow a letter or digit, so e-mail addresses aren't
+// mentions. A trailing `.` ends the sentence rather than the name.
+pub fn mentioned_names(msg: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut previous = None;
+
+    for (start, c) in msg.char_indices() {
+        let follows_word = previous.is_some_and(|previous: char| previous.is_alphanumeric());
+        previous = Some(c);
+        if c != '@' || follows_word {
+            continue;
+        }
+
+        let rest = &msg[start + 1..];
+        let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        let name = rest[..end].trim_end_matches('.');
+        if !name.is_empty() && !names.iter().any(|seen| seen == name) {
+            names.push(name.to_owned());
+        }
+    }
+
+    names
+}
+
+pub fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+// How long a name can be.
+pub const MAX_NAME_CHARS: usize = 32;
+
+// What `valid_name` asks of a name, for whoever picked another.
+pub const NAME_RULES: &str = "Names are 1 to 32 letters, digits, _, - or ., and don't end in a dot.";
+
+// Whether people can go by `name`. It has to be something `@name` mentions as
+// a whole.
+pub fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_CHARS).contains(&name.chars().count()) && name.chars().all(is_name_char) && !name.ends_with('.')
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{
+        rooms::Member,
+        ws::{Mention, ServerMessage},
+    };
+    use dashmap::DashMap;
+    use std::collections::VecDeque;
+
+    // How many mentions each inbox keeps.
+    const INBOX_LENGTH: usize = 100;
+
+    #[derive(Debug, Default)]
+    struct Inbox {
+        // Oldest first.
+        mentions: VecDeque<Mention>,
+        unread: usize,
+    }
+
+    // The mentions of everyone who has joined since the server started, by
+    // identity, so whoever takes a name next doesn't get them. Only names
+    // someone has had can be mentioned, and they mention whoever had them last.
+    #[derive(Debug, Default)]
+    pub struct Mentions {
+        names: DashMap<String, String>,
+        inboxes: DashMap<String, Inbox>,
+    }
+
+    impl Mentions {
+        pub fn new() -> Self {
+            Mentions::default()
+        }
+
+        // Makes `member` someone others can mention by their name.
+        pub fn register(&self, member: &Member) {
+            self.names.insert(member.name.clone(), member.id.clone());
+            self.inboxes.entry(member.id.clone()).or_default();
+        }
+
+        // Who the names mentioned in `msg` belong to, of those we know.
+        pub fn resolve(&self, msg: &str) -> Vec<Member> {
+            mentioned_names(msg)
+                .into_iter()
+                .filter_map(|name| {
+                    let id = self.names.get(&name)?.clone();
+                    Some(Member { id, name })
+                })
+                .collect()
+        }
+
+        // Puts `message` in the inbox of each of `mentioned` but its sender,
+        // and returns who that was along with their new unread counts.
+        pub fn deliver(&self, message: &ServerMessage, mentioned: &[Member]) -> Vec<(String, Mention, usize)> {
+            mentioned
+                .iter()
+                .filter(|member| member.name != message.sender)
+                .filter_map(|member| {
+                    let mut inbox = self.inboxes.get_mut(&member.id)?;
+                    let mention = Mention {
+                        id: message.id,
+                        room: message.room.clone(),
+                        sender: message.sender.clone(),
+                        msg: message.msg.clone(),
+                    };
+
+                    if inbox.mentions.len() == INBOX_LENGTH {
+                        inbox.mentions.pop_front();
+                    }
+                    inbox.mentions.push_back(mention.clone());
+                    inbox.unread += 1;
+                    Some((member.id.clone(), mention, inbox.unread))
+                })
+                .collect()
+        }
+
+        // The mentions of identity `id`, oldest first, and how many they
+        // haven't seen.
+        pub fn inbox(&self, id: &str) -> (Vec<Mention>, usize) {
+            self.inboxes
+                .get(id)
+                .map(|inbox| (inbox.mentions.iter().cloned().collect(), inbox.unread))
+                .unwrap_or_default()
+        }
+
+        pub fn mark_read(&self, id: &str) {
+            if let Some(mut inbox) = self.inboxes.get_mut(id) {
+                inbox.unread = 0;
+            }
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn names_can_be_mentioned() {
+        for name in ["ann", "Zoë", "j.doe", "x_y-z", "a".repeat(MAX_NAME_CHARS).as_str()] {
+            assert!(valid_name(name), "{name:?}");
+            assert_eq!(mentioned_names(&format!("hi @{name}.")), [name], "{name:?}");
+        }
+        for name in ["", " ", "ann lee", "ann.", "a\u{7}b", "@ann", "a".repeat(MAX_NAME_CHARS + 1).as_str()] {
+            assert!(!valid_name(name), "{name:?}");
+        }
+    }
+
+    fn member(id: &str, name: &str) -> Member {
+        Member { id: id.to_owned(), name: name.to_owned() }
+    }
+
+    fn message(sender: &str, msg: &str) -> ServerMessage {
+        ServerMessage {
+            id: Uuid::new_v4(),
+            room: String::from("lobby"),
+            sender: sender.to_owned(),
+            mentions: Vec::new(),
+            msg: msg.to_owned(),
+            attachment: None,
+            preview: None,
+        }
+    }
+
+    #[test]
+    fn inboxes_stay_with_their_identity() {
+        let mentions = Mentions::new();
+        mentions.register(&member("a1", "ann"));
+        let msg = message("bob", "hi @ann and @nobody");
+        let mentioned = mentions.resolve(&msg.msg);
+        assert_eq!(mentioned, [member("a1", "ann")]);
+        assert_eq!(mentions.deliver(&msg, &mentioned).len(), 1);
+
+        // Someone else takes the name while ann is away, or ann takes another.
+        mentions.register(&member("c3", "ann"));
+        mentions.register(&member("a1", "anne"));
+        assert_eq!(mentions.inbox("c3"), (Vec::new(), 0));
+        assert_eq!(mentions.inbox("a1").1, 1);
+
+        let msg = message("bob", "@ann?");
+        let mentioned = mentions.resolve(&msg.msg);
+        assert_eq!(mentioned, [member("c3", "ann")]);
+        mentions.deliver(&msg, &mentioned);
+        assert_eq!(mentions.inbox("a1").1, 1);
+        assert_eq!(mentions.inbox("c3").1, 1);
+
+        mentions.mark_read("a1");
+        assert_eq!(mentions.inbox("a1").1, 0);
+    }
+
+    #[test]
+    fn nobody_mentions_themselves() {
+        let mentions = Mentions::new();
+        mentions.register(&member("a1", "ann"));
+        let msg = message("ann", "note to self, @ann");
+        let mentioned = mentions.resolve(&msg.msg);
+        assert!(mentions.deliver(&msg, &mentioned).is_empty());
+    }
+}
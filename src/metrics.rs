@@ -0,0 +1,71 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::chat::AppState;
+    use axum::extract::State;
+    use std::{
+        fmt::Write,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    // Counters scraped from `/metrics` in the Prometheus text format.
+    #[derive(Debug, Default)]
+    pub struct Metrics {
+        frames_compressed: AtomicU64,
+        bytes_before_compression: AtomicU64,
+        bytes_after_compression: AtomicU64,
+    }
+
+    impl Metrics {
+        pub fn record_compression(&self, before: usize, after: usize) {
+            self.frames_compressed.fetch_add(1, Ordering::Relaxed);
+            self.bytes_before_compression.fetch_add(before as u64, Ordering::Relaxed);
+            self.bytes_after_compression.fetch_add(after as u64, Ordering::Relaxed);
+        }
+
+        pub fn render(&self) -> String {
+            let before = self.bytes_before_compression.load(Ordering::Relaxed);
+            let after = self.bytes_after_compression.load(Ordering::Relaxed);
+
+            let mut out = String::new();
+            counter(
+                &mut out,
+                "chat_frames_compressed_total",
+                "Frames sent compressed.",
+                self.frames_compressed.load(Ordering::Relaxed),
+            );
+            counter(
+                &mut out,
+                "chat_compression_bytes_in_total",
+                "Size of compressed frames before compression.",
+                before,
+            );
+            counter(
+                &mut out,
+                "chat_compression_bytes_out_total",
+                "Size of compressed frames on the wire.",
+                after,
+            );
+            counter(
+                &mut out,
+                "chat_compression_bytes_saved_total",
+                "Bytes compression kept off the wire.",
+                before.saturating_sub(after),
+            );
+            out
+        }
+    }
+
+    fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> String {
+        state.metrics.render()
+    }
+}}
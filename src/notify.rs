@@ -0,0 +1,72 @@
+use leptos::*;
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use web_sys::{Notification, NotificationOptions, NotificationPermission};
+
+// Desktop notifications for when the tab is in the background, and the
+// per-room preferences that silence them.
+
+// Older browsers, and some embedded ones, have no notifications at all.
+fn supported() -> bool {
+    js_sys::Reflect::has(&window(), &JsValue::from_str("Notification")).unwrap_or(false)
+}
+
+// Asks to show notifications, unless we already asked. Browsers only show the
+// prompt in response to something the user did, such as joining.
+pub fn request_permission() {
+    if supported() && Notification::permission() == NotificationPermission::Default {
+        let _ = Notification::request_permission();
+    }
+}
+
+// Shows a notification if the user can't see the page and has allowed them.
+// Notifications with the same `tag` replace each other rather than pile up.
+pub fn notify(title: &str, body: &str, tag: &str) {
+    if !document().hidden() || !supported() || Notification::permission() != NotificationPermission::Granted {
+        return;
+    }
+
+    let mut options = NotificationOptions::new();
+    options.body(body).tag(tag);
+    let Ok(notification) = Notification::new_with_options(title, &options) else {
+        return;
+    };
+
+    // Clicking it brings the chat back.
+    let on_click = Closure::wrap(Box::new(move || {
+        let _ = window().focus();
+    }) as Box<dyn FnMut()>);
+    notification.set_onclick(Some(on_click.as_ref().unchecked_ref()));
+    on_click.forget();
+}
+
+// Calls `f` whenever the tab comes back into view.
+pub fn on_visible(f: impl Fn() + 'static) {
+    let on_change = Closure::wrap(Box::new(move || {
+        if !document().hidden() {
+            f();
+        }
+    }) as Box<dyn FnMut()>);
+    let _ = document().add_event_listener_with_callback("visibilitychange", on_change.as_ref().unchecked_ref());
+    on_change.forget();
+}
+
+fn mute_key(room: &str) -> String {
+    format!("muted-room:{room}")
+}
+
+// Muted rooms don't notify or count towards the unread badge. Kept per
+// browser, like the session tokens.
+pub fn is_muted(room: &str) -> bool {
+    let muted = || -> Option<String> { window().local_storage().ok()??.get_item(&mute_key(room)).ok()? };
+    muted().is_some()
+}
+
+pub fn set_muted(room: &str, muted: bool) {
+    if let Ok(Some(storage)) = window().local_storage() {
+        let _ = if muted {
+            storage.set_item(&mute_key(room), "1")
+        } else {
+            storage.remove_item(&mute_key(room))
+        };
+    }
+}
@@ -0,0 +1,477 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{rooms::Member, ws::ServerFrame};
+    use dashmap::{mapref::entry::Entry, DashMap};
+    use sha2::{Digest, Sha256};
+    use std::{
+        collections::{HashMap, HashSet},
+        net::SocketAddr,
+        time::SystemTime,
+    };
+    use subtle::ConstantTimeEq;
+    use thiserror::Error;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    // Everything we know about a single live connection.
+    #[derive(Debug, Clone)]
+    pub struct Connection {
+        pub id: Uuid,
+        pub connected_at: SystemTime,
+        pub remote_addr: SocketAddr,
+        // Lets the rest of the server push frames to this client only.
+        pub sender: mpsc::UnboundedSender<ServerFrame>,
+    }
+
+    impl Connection {
+        pub fn new(remote_addr: SocketAddr, sender: mpsc::UnboundedSender<ServerFrame>) -> Self {
+            Connection {
+                id: Uuid::new_v4(),
+                connected_at: SystemTime::now(),
+                remote_addr,
+                sender,
+            }
+        }
+    }
+
+    #[derive(Debug, Error, PartialEq, Eq)]
+    pub enum RenameError {
+        #[error("{0} is taken.")]
+        Taken(String),
+        #[error("{0} isn't online.")]
+        NotOnline(String),
+    }
+
+    // Who presents `token`, as rooms and admins know them. Only someone who
+    // has the token can be them, whatever they are called.
+    pub fn identity(token: &str) -> String {
+        hex::encode(&Sha256::digest(token.as_bytes())[..16])
+    }
+
+    // A user is online for as long as at least one of their connections is.
+    #[derive(Debug)]
+    struct User {
+        name: String,
+        // Other tabs and devices present this to join as the same user, and
+        // clients keep it to come back as the same identity.
+        token: String,
+        id: String,
+        connections: HashMap<Uuid, Connection>,
+        // The newest message any of their devices has shown them.
+        last_read: Option<Uuid>,
+    }
+
+    // What a connection gets back after a successful join.
+    #[derive(Debug, Clone)]
+    pub struct Session {
+        pub token: String,
+        pub id: String,
+        // True if nobody was online under this name before.
+        pub first: bool,
+        pub last_read: Option<Uuid>,
+    }
+
+    // Tracks which usernames are online. The maps are sharded, so joins and
+    // leaves from different sockets rarely contend, and there is no lock for a
+    // panicking task to poison.
+    //
+    // Names point at users rather than holding them, so a user can take a new
+    // name before giving up the old one and is never without either.
+    #[derive(Debug, Default)]
+    pub struct Presence {
+        // Who holds each name. Always locked before `users` when both are.
+        names: DashMap<String, Uuid>,
+        users: DashMap<Uuid, User>,
+        // Whose each connection is.
+        connections: DashMap<Uuid, Uuid>,
+        // Which room each connection is in.
+        rooms: DashMap<Uuid, String>,
+    }
+
+    impl User {
+        // Whether `token` is theirs. How long finding out takes says nothing
+        // about how close a guess came.
+        fn holds(&self, token: &str) -> bool {
+            self.token.as_bytes().ct_eq(token.as_bytes()).into()
+        }
+
+        fn member(&self) -> Member {
+            Member {
+                id: self.id.clone(),
+                name: self.name.clone(),
+            }
+        }
+    }
+
+    impl Presence {
+        pub fn new() -> Self {
+            Presence::default()
+        }
+
+        // Adds `connection` under `name`. A name that is already online can only
+        // be joined again with the token handed out to its first connection. A
+        // free name is taken with the token the client brings, if it kept one,
+        // so that it keeps its identity.
+        pub fn join(&self, name: &str, token: Option<&str>, connection: Connection) -> Option<Session> {
+            let connection_id = connection.id;
+            let (user_id, session) = match self.names.entry(name.to_owned()) {
+                Entry::Occupied(mut entry) => match self.users.get_mut(entry.get()) {
+                    Some(mut user) => {
+                        if !token.is_some_and(|token| user.holds(token)) {
+                            return None;
+                        }
+
+                        user.connections.insert(connection.id, connection);
+                        let session = Session {
+                            token: user.token.clone(),
+                            id: user.id.clone(),
+                            first: false,
+                            last_read: user.last_read,
+                        };
+                        (*entry.get(), session)
+                    }
+                    // Its last holder just left and is about to let go of it.
+                    None => {
+                        let (user_id, session) = self.add_user(name, token, connection);
+                        entry.insert(user_id);
+                        (user_id, session)
+                    }
+                },
+                Entry::Vacant(entry) => {
+                    let (user_id, session) = self.add_user(name, token, connection);
+                    entry.insert(user_id);
+                    (user_id, session)
+                }
+            };
+
+            self.connections.insert(connection_id, user_id);
+            Some(session)
+        }
+
+        fn add_user(&self, name: &str, token: Option<&str>, connection: Connection) -> (Uuid, Session) {
+            // Only tokens we could have handed out, so nobody picks an easy one.
+            let token = match token.map(Uuid::try_parse) {
+                Some(Ok(token)) => token.to_string(),
+                _ => Uuid::new_v4().to_string(),
+            };
+            let (user_id, id) = (Uuid::new_v4(), identity(&token));
+            self.users.insert(
+                user_id,
+                User {
+                    name: name.to_owned(),
+                    token: token.clone(),
+                    id: id.clone(),
+                    connections: HashMap::from([(connection.id, connection)]),
+                    last_read: None,
+                },
+            );
+            let session = Session {
+                token,
+                id,
+                first: true,
+                last_read: None,
+            };
+            (user_id, session)
+        }
+
+        // The token `name`'s devices join with.
+        pub fn token(&self, name: &str) -> Option<String> {
+            self.with_user(name, |user| user.token.clone())
+        }
+
+        // Whether `token` is the one `name`'s devices join with.
+        pub fn holds(&self, name: &str, token: &str) -> bool {
+            self.member_holding(name, token).is_some()
+        }
+
+        // Who `name` is, if `token` is the one their devices join with.
+        pub fn member_holding(&self, name: &str, token: &str) -> Option<Member> {
+            self.with_user(name, |user| user.holds(token).then(|| user.member())).flatten()
+        }
+
+        // Who is online as `name`.
+        pub fn member(&self, name: &str) -> Option<Member> {
+            self.with_user(name, User::member)
+        }
+
+        // The name of whoever is on connection `id`.
+        pub fn name_of(&self, id: Uuid) -> Option<String> {
+            self.member_of(id).map(|member| member.name)
+        }
+
+        // Whoever is on connection `id`.
+        pub fn member_of(&self, id: Uuid) -> Option<Member> {
+            let user_id = *self.connections.get(&id)?;
+            self.users.get(&user_id).map(|user| user.member())
+        }
+
+        // Puts connection `id` in `room`, out of whichever it was in.
+        pub fn enter(&self, id: Uuid, room: &str) {
+            self.rooms.insert(id, room.to_owned());
+        }
+
+        // The room connection `id` is in.
+        pub fn room_of(&self, id: Uuid) -> Option<String> {
+            self.rooms.get(&id).map(|room| room.clone())
+        }
+
+        // Everyone with a connection in `room`, and that connection.
+        pub fn connections_in(&self, room: &str) -> Vec<(Member, Connection)> {
+            let ids: Vec<Uuid> = self
+                .rooms
+                .iter()
+                .filter(|entry| entry.value() == room)
+                .map(|entry| *entry.key())
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| {
+                    let user_id = *self.connections.get(&id)?;
+                    let user = self.users.get(&user_id)?;
+                    let connection = user.connections.get(&id)?.clone();
+                    Some((user.member(), connection))
+                })
+                .collect()
+        }
+
+        // How many users have a connection in `room`.
+        pub fn in_room(&self, room: &str) -> usize {
+            self.rooms
+                .iter()
+                .filter(|entry| entry.value() == room)
+                .filter_map(|entry| self.connections.get(entry.key()).map(|user_id| *user_id))
+                .collect::<HashSet<_>>()
+                .len()
+        }
+
+        // Moves `old`, with all their connections, to the name `new`. `new` is
+        // claimed before `old` is let go, so the user holds one or the other
+        // throughout.
+        pub fn rename(&self, old: &str, new: &str) -> Result<(), RenameError> {
+            let user_id = *self.names.get(old).ok_or_else(|| RenameError::NotOnline(old.to_owned()))?;
+
+            match self.names.entry(new.to_owned()) {
+                Entry::Occupied(_) => return Err(RenameError::Taken(new.to_owned())),
+                Entry::Vacant(entry) => {
+                    let mut user = self
+                        .users
+                        .get_mut(&user_id)
+                        .ok_or_else(|| RenameError::NotOnline(old.to_owned()))?;
+                    user.name = new.to_owned();
+                    entry.insert(user_id);
+                }
+            }
+
+            self.names.remove_if(old, |_, holder| *holder == user_id);
+            Ok(())
+        }
+
+        // Drops one connection. Returns the user's name if that was their last
+        // one, which frees it so new clients can take it again.
+        pub fn leave(&self, connection_id: Uuid) -> Option<String> {
+            self.rooms.remove(&connection_id);
+            let (_, user_id) = self.connections.remove(&connection_id)?;
+            let (_, user) = self.users.remove_if_mut(&user_id, |_, user| {
+                user.connections.remove(&connection_id);
+                user.connections.is_empty()
+            })?;
+
+            self.names.remove_if(&user.name, |_, holder| *holder == user_id);
+            Some(user.name)
+        }
+
+        pub fn connections(&self, name: &str) -> Vec<Connection> {
+            self.with_user(name, |user| user.connections.values().cloned().collect())
+                .unwrap_or_default()
+        }
+
+        pub fn is_online(&self, name: &str) -> bool {
+            self.names.contains_key(name)
+        }
+
+        pub fn online_users(&self) -> Vec<String> {
+            self.users.iter().map(|user| user.name.clone()).collect()
+        }
+
+        pub fn online_members(&self) -> Vec<Member> {
+            self.users.iter().map(|user| user.member()).collect()
+        }
+
+        pub fn len(&self) -> usize {
+            self.users.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.users.is_empty()
+        }
+
+        // Sends `frame` to every connection `name` has open. Returns false if they
+        // aren't connected.
+        pub fn send_to(&self, name: &str, frame: ServerFrame) -> bool {
+            self.with_user(name, |user| {
+                for connection in user.connections.values() {
+                    let _ = connection.sender.send(frame.clone());
+                }
+            })
+            .is_some()
+        }
+
+        // Sends `frame` to every connection of whoever has identity `id`, under
+        // any name.
+        pub fn send_to_identity(&self, id: &str, frame: ServerFrame) {
+            for user in self.users.iter().filter(|user| user.id == id) {
+                for connection in user.connections.values() {
+                    let _ = connection.sender.send(frame.clone());
+                }
+            }
+        }
+
+        // Records that one of `name`'s devices has shown them message `id` and
+        // tells their other devices to catch up.
+        pub fn mark_read(&self, name: &str, from: Uuid, id: Uuid) {
+            let Some(user_id) = self.names.get(name).map(|user_id| *user_id) else {
+                return;
+            };
+            if let Some(mut user) = self.users.get_mut(&user_id) {
+                user.last_read = Some(id);
+                for connection in user.connections.values().filter(|c| c.id != from) {
+                    let _ = connection.sender.send(ServerFrame::Read { id });
+                }
+            }
+        }
+
+        fn with_user<T>(&self, name: &str, f: impl FnOnce(&User) -> T) -> Option<T> {
+            let user_id = *self.names.get(name)?;
+            self.users.get(&user_id).map(|user| f(&user))
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        thread,
+    };
+    use tokio::sync::mpsc;
+
+    fn connection() -> Connection {
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        Connection::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 3000)), sender)
+    }
+
+    #[test]
+    fn joining_a_name_that_is_online_takes_its_token() {
+        let presence = Presence::new();
+        let session = presence.join("alice", None, connection()).unwrap();
+        assert!(session.first);
+
+        assert!(presence.join("alice", None, connection()).is_none());
+        assert!(presence.join("alice", Some("guess"), connection()).is_none());
+        let again = presence.join("alice", Some(&session.token), connection()).unwrap();
+        assert!(!again.first);
+        assert_eq!(presence.connections("alice").len(), 2);
+        assert_eq!(presence.len(), 1);
+    }
+
+    #[test]
+    fn the_name_is_free_once_the_last_connection_leaves() {
+        let presence = Presence::new();
+        let (first, second) = (connection(), connection());
+        let (first_id, second_id) = (first.id, second.id);
+        let session = presence.join("alice", None, first).unwrap();
+        presence.join("alice", Some(&session.token), second).unwrap();
+
+        assert_eq!(presence.leave(first_id), None);
+        assert!(presence.is_online("alice"));
+        assert_eq!(presence.leave(second_id).as_deref(), Some("alice"));
+        assert!(!presence.is_online("alice"));
+        assert!(presence.join("alice", None, connection()).unwrap().first);
+    }
+
+    #[test]
+    fn a_kept_token_keeps_its_identity_under_any_name() {
+        let presence = Presence::new();
+        let first = connection();
+        let first_id = first.id;
+        let session = presence.join("alice", None, first).unwrap();
+        assert_eq!(session.id, identity(&session.token));
+        presence.leave(first_id);
+
+        let again = presence.join("alicia", Some(&session.token), connection()).unwrap();
+        assert_eq!(again.id, session.id);
+        assert_eq!(presence.member_holding("alicia", &session.token).unwrap().id, session.id);
+        assert!(presence.member_holding("alicia", "guess").is_none());
+
+        // Tokens we couldn't have handed out aren't kept.
+        let picked = presence.join("bob", Some("bob"), connection()).unwrap();
+        assert_ne!(picked.token, "bob");
+    }
+
+    #[test]
+    fn renaming_moves_every_connection() {
+        let presence = Presence::new();
+        let first = connection();
+        let first_id = first.id;
+        let session = presence.join("alice", None, first).unwrap();
+        presence.join("alice", Some(&session.token), connection()).unwrap();
+        presence.enter(first_id, "general");
+
+        assert_eq!(presence.rename("alice", "alicia"), Ok(()));
+        assert!(!presence.is_online("alice"));
+        assert_eq!(presence.name_of(first_id).as_deref(), Some("alicia"));
+        assert_eq!(presence.connections("alicia").len(), 2);
+        assert_eq!(presence.token("alicia"), Some(session.token));
+        assert_eq!(presence.connections_in("general")[0].0.name, "alicia");
+        assert_eq!(presence.leave(first_id), None);
+    }
+
+    #[test]
+    fn renaming_to_a_taken_name_keeps_the_old_one() {
+        let presence = Presence::new();
+        presence.join("alice", None, connection()).unwrap();
+        presence.join("bob", None, connection()).unwrap();
+
+        assert_eq!(presence.rename("alice", "bob"), Err(RenameError::Taken("bob".to_owned())));
+        assert!(presence.is_online("alice"));
+        assert_eq!(
+            presence.rename("carol", "dave"),
+            Err(RenameError::NotOnline("carol".to_owned()))
+        );
+    }
+
+    #[test]
+    fn racing_renames_never_lose_anyone() {
+        let presence = Arc::new(Presence::new());
+        for i in 0..8 {
+            presence.join(&format!("user-{i}"), None, connection()).unwrap();
+        }
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let presence = presence.clone();
+                thread::spawn(move || {
+                    let mut name = format!("user-{i}");
+                    for round in 0..200 {
+                        // Everyone goes for the same few names.
+                        let new = format!("name-{}", (i + round) % 4);
+                        if presence.rename(&name, &new).is_ok() {
+                            name = new;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut online = presence.online_users();
+        online.sort();
+        online.dedup();
+        assert_eq!(online.len(), 8);
+        assert!(online.iter().all(|name| presence.is_online(name)));
+    }
+}
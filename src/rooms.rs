@@ -0,0 +1,937 @@
+use crate::{
+    error_template::error_message,
+    ws::{send_msg, ClientMessage, JoinedAs},
+};
+use cfg_if::cfg_if;
+use leptos::*;
+use leptos_router::{use_navigate, use_params_map, A};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+// Longest topic and description a room can have, in characters.
+pub const MAX_TOPIC_CHARS: usize = 200;
+pub const MAX_DESCRIPTION_CHARS: usize = 1000;
+// Slow mode can make people wait up to an hour between messages.
+pub const MAX_SLOW_MODE_SECS: u32 = 60 * 60;
+// Longest message anyone can send, in any room.
+pub const MAX_MESSAGE_CHARS: u32 = 4000;
+
+// Who can find and enter a room.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    // Listed, and anyone can come in.
+    #[default]
+    Public,
+    // Only for its members, and not listed for anyone else.
+    Private,
+    // Listed, but only those invited can come in.
+    InviteOnly,
+}
+
+impl Visibility {
+    pub const ALL: [Visibility; 3] = [Visibility::Public, Visibility::Private, Visibility::InviteOnly];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+            Visibility::InviteOnly => "invite_only",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Visibility::Public => "Public",
+            Visibility::Private => "Private",
+            Visibility::InviteOnly => "Invite only",
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Visibility::ALL
+            .into_iter()
+            .find(|visibility| visibility.as_str() == s)
+            .ok_or_else(|| format!("{s:?} isn't a room visibility."))
+    }
+}
+
+// What a room's admins can change about it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomSettings {
+    // What's being talked about right now. Empty if nobody said.
+    pub topic: String,
+    // What the room is for.
+    pub description: String,
+    pub visibility: Visibility,
+    // Seconds everyone but admins waits between messages. 0 turns it off.
+    pub slow_mode: u32,
+    // Longest message allowed, in characters. 0 for `MAX_MESSAGE_CHARS`.
+    pub max_length: u32,
+}
+
+impl RoomSettings {
+    // Longest message allowed, in characters.
+    pub fn message_limit(&self) -> u32 {
+        match self.max_length {
+            0 => MAX_MESSAGE_CHARS,
+            max_length => max_length.min(MAX_MESSAGE_CHARS),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.topic.chars().count() > MAX_TOPIC_CHARS {
+            return Err(format!("Topics can be at most {MAX_TOPIC_CHARS} characters."));
+        }
+        if self.description.chars().count() > MAX_DESCRIPTION_CHARS {
+            return Err(format!("Descriptions can be at most {MAX_DESCRIPTION_CHARS} characters."));
+        }
+        if self.slow_mode > MAX_SLOW_MODE_SECS {
+            return Err(format!("Slow mode can be at most {MAX_SLOW_MODE_SECS} seconds."));
+        }
+        if self.max_length > MAX_MESSAGE_CHARS {
+            return Err(format!("Messages can be at most {MAX_MESSAGE_CHARS} characters."));
+        }
+        Ok(())
+    }
+}
+
+// Someone a room lets in. Rooms go by `id`, which only whoever holds the
+// session token it comes from can have; `name` is what they were called when
+// they were let in, for showing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Member {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Room {
+    pub name: String,
+    pub settings: RoomSettings,
+    // Who may change the settings, besides the server's admins.
+    pub admins: Vec<Member>,
+    // Who else may come in when the room isn't public.
+    #[serde(default)]
+    pub members: Vec<Member>,
+}
+
+impl Room {
+    pub fn new(name: &str, admins: Vec<Member>) -> Self {
+        Room {
+            name: name.to_owned(),
+            settings: RoomSettings::default(),
+            admins,
+            members: Vec::new(),
+        }
+    }
+
+    // Whether identity `id` may see what's said here and come in. The server's
+    // admins always may, which only the server knows.
+    pub fn allows(&self, id: &str) -> bool {
+        self.settings.visibility == Visibility::Public
+            || self.admins.iter().chain(&self.members).any(|allowed| allowed.id == id)
+    }
+
+    // Whether identity `id` may change the settings.
+    pub fn is_admin(&self, id: &str) -> bool {
+        self.admins.iter().any(|admin| admin.id == id)
+    }
+
+    // Whether those who aren't allowed in can still see that it's there.
+    pub fn is_listed(&self) -> bool {
+        self.settings.visibility != Visibility::Private
+    }
+}
+
+// A link into a room that isn't public. It can run out after a while, or after
+// so many people used it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub code: String,
+    pub room: String,
+    // The identity of whoever made it.
+    pub created_by: String,
+    // Milliseconds since the Unix epoch, if it expires.
+    pub expires_at: Option<i64>,
+    // How many people can use it, if there's a limit.
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+impl Invite {
+    pub fn path(&self) -> String {
+        format!("/invite/{}", self.code)
+    }
+}
+
+// Room names are short and URL-safe: lowercase letters, digits, `-` and `_`.
+pub fn is_room_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// The rooms `user` can see, by name. Without a session that's only those that
+// aren't private.
+#[server(ListRooms, "/api")]
+pub async fn list_rooms(user: Option<String>, token: Option<String>) -> Result<Vec<Room>, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let viewer = match (user, token) {
+        (Some(user), Some(token)) => check_session(&state, &user, &token).ok(),
+        _ => None,
+    };
+
+    Ok(state
+        .rooms
+        .list()
+        .into_iter()
+        .filter(|room| room.is_listed() || viewer.as_ref().is_some_and(|viewer| state.can_enter(room, viewer)))
+        .collect())
+}
+
+// Opens a new room, with `user` as its admin. `token` is the one their session
+// was handed.
+#[server(CreateRoom, "/api")]
+pub async fn create_room(user: String, token: String, name: String) -> Result<Room, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let me = check_session(&state, &user, &token)?;
+
+    let room = state.rooms.create(&name, me).map_err(ErrorBody::from)?;
+    state.room_updated(room.clone());
+    Ok(room)
+}
+
+// Changes `room`'s settings. Only its admins and the server's may.
+#[server(UpdateRoom, "/api")]
+pub async fn update_room(
+    user: String,
+    token: String,
+    room: String,
+    settings: RoomSettings,
+) -> Result<Room, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let me = check_session(&state, &user, &token)?;
+
+    check_room_admin(&state, &room, &me)?;
+    settings.validate().map_err(|msg| AppError::Invalid.with_message(msg))?;
+    // Everyone starts out in it, so everyone must be able to.
+    if room == DEFAULT_ROOM && settings.visibility != Visibility::Public {
+        return Err(AppError::Invalid.with_message(format!("#{DEFAULT_ROOM} has to stay public.")).into());
+    }
+
+    let room = state
+        .rooms
+        .update(&room, |changed| changed.settings = settings)
+        .map_err(ErrorBody::from)?;
+    state.room_updated(room.clone());
+    Ok(room)
+}
+
+// Lets whoever is online as `name`, or the bot called that, into `room` from
+// now on, whatever they call themselves later. Only its admins and the
+// server's may.
+#[server(InviteMember, "/api")]
+pub async fn invite_member(user: String, token: String, room: String, name: String) -> Result<Room, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let me = check_session(&state, &user, &token)?;
+    check_room_admin(&state, &room, &me)?;
+
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(AppError::Invalid.with_message("Invite someone by their name.").into());
+    }
+    // Whoever has a name while they're away could be anyone, so only those
+    // here now can be told apart.
+    let Some(member) = state.presence.member(name).or_else(|| state.bot_tokens.member(name)) else {
+        return Err(AppError::NotFound
+            .with_message(format!("{name} isn't online. Send them an invitation link instead."))
+            .into());
+    };
+
+    let room = state
+        .rooms
+        .update(&room, |room| {
+            if !room.members.iter().any(|existing| existing.id == member.id) {
+                room.members.push(member);
+            }
+        })
+        .map_err(ErrorBody::from)?;
+    state.room_updated(room.clone());
+    Ok(room)
+}
+
+// Takes the member with identity `id` off `room`'s members. If they are in it,
+// they are sent back to the room everyone starts in.
+#[server(RevokeMember, "/api")]
+pub async fn revoke_member(user: String, token: String, room: String, id: String) -> Result<Room, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let me = check_session(&state, &user, &token)?;
+    check_room_admin(&state, &room, &me)?;
+
+    let room = state
+        .rooms
+        .update(&room, |room| room.members.retain(|member| member.id != id))
+        .map_err(ErrorBody::from)?;
+    state.room_updated(room.clone());
+    Ok(room)
+}
+
+// A new invitation link to `room`, good for `expires_in` seconds and `max_uses`
+// people, or forever and anyone if left out.
+#[server(CreateInvite, "/api")]
+pub async fn create_invite(
+    user: String,
+    token: String,
+    room: String,
+    expires_in: Option<u32>,
+    max_uses: Option<u32>,
+) -> Result<Invite, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::{sync::Arc, time::Duration};
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let me = check_session(&state, &user, &token)?;
+    check_room_admin(&state, &room, &me)?;
+
+    let expires_in = expires_in.map(|secs| Duration::from_secs(secs.into()));
+    let invite = state
+        .rooms
+        .create_invite(&room, &me.id, expires_in, max_uses)
+        .map_err(ErrorBody::from)?;
+    Ok(invite)
+}
+
+// Makes `user` a member of the room invitation `code` is for.
+#[server(AcceptInvite, "/api")]
+pub async fn accept_invite(user: String, token: String, code: String) -> Result<Room, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    let me = check_session(&state, &user, &token)?;
+
+    let room = state
+        .rooms
+        .redeem_invite(&code, me)
+        .map_err(ErrorBody::from)?;
+    state.room_updated(room.clone());
+    Ok(room)
+}
+
+// Where invitation links lead. Accepting makes us a member of the room and takes
+// us into it, so we have to have joined the chat first.
+#[component]
+pub fn InvitePage() -> impl IntoView {
+    let params = use_params_map();
+    let code = move || params.with(|params| params.get("code").cloned().unwrap_or_default());
+    let joined_as = use_context::<JoinedAs>();
+    let joined = move || joined_as.is_some_and(|joined_as| joined_as.0.get().is_some());
+
+    let accept = create_action(move |code: &String| {
+        let code = code.clone();
+        let (user, token) = joined_as.map(JoinedAs::credentials).unwrap_or_default();
+        async move { accept_invite(user.unwrap_or_default(), token.unwrap_or_default(), code).await }
+    });
+    let navigate = use_navigate();
+    create_effect(move |_| {
+        if let Some(Ok(room)) = accept.value().get() {
+            let _ = send_msg(&ClientMessage::JoinRoom { room: room.name });
+            navigate("/", Default::default());
+        }
+    });
+
+    view! {
+        <section class="invite">
+            <p>"You have been invited to a room."</p>
+            <Show when=joined fallback=|| view! { <p>"Pick a name to accept."</p> }>
+                <button type="button" disabled=move || accept.pending().get() on:click=move |_| accept.dispatch(code())>
+                    "accept"
+                </button>
+            </Show>
+            {move || match accept.value().get() {
+                Some(Err(err)) => Some(view! { <p class="invite__error">{error_message(&err)}</p> }),
+                _ => None,
+            }}
+            <A href="/">"close"</A>
+        </section>
+    }
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}, search::database_path, ws::DEFAULT_ROOM};
+    use dashmap::{mapref::entry::Entry, DashMap};
+    use rusqlite::{params, Connection};
+    use std::{
+        io,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+    use thiserror::Error;
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS rooms (
+            name TEXT PRIMARY KEY,
+            topic TEXT NOT NULL,
+            description TEXT NOT NULL,
+            visibility TEXT NOT NULL,
+            slow_mode INTEGER NOT NULL,
+            max_length INTEGER NOT NULL,
+            admins TEXT NOT NULL,
+            members TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS invites (
+            code TEXT PRIMARY KEY,
+            room TEXT NOT NULL,
+            created_by TEXT NOT NULL,
+            expires_at INTEGER,
+            max_uses INTEGER,
+            uses INTEGER NOT NULL
+        );
+    ";
+
+    #[derive(Debug, Error)]
+    pub enum RoomError {
+        #[error("Room names are 1 to 32 lowercase letters, digits, - or _.")]
+        InvalidName,
+        #[error("There is already a room called #{0}.")]
+        Exists(String),
+        #[error("There is no room called #{0}.")]
+        NotFound(String),
+        #[error("This invitation doesn't exist, has expired or has been used up.")]
+        InvalidInvite,
+        #[error("room database: {0}")]
+        Database(#[from] rusqlite::Error),
+        #[error("room database writer: {0}")]
+        Writer(#[from] io::Error),
+    }
+
+    impl From<&RoomError> for AppError {
+        fn from(err: &RoomError) -> Self {
+            match err {
+                RoomError::InvalidName => AppError::Invalid,
+                RoomError::Exists(_) => AppError::Conflict,
+                RoomError::NotFound(_) | RoomError::InvalidInvite => AppError::NotFound,
+                RoomError::Database(_) | RoomError::Writer(_) => AppError::Internal,
+            }
+        }
+    }
+
+    impl From<RoomError> for AppError {
+        fn from(err: RoomError) -> Self {
+            AppError::from(&err)
+        }
+    }
+
+    // Says what was wrong, unless it was on our side; that's only for the log.
+    impl From<RoomError> for ErrorBody {
+        fn from(err: RoomError) -> Self {
+            match AppError::from(&err) {
+                AppError::Internal => {
+                    tracing::error!("{err}");
+                    ErrorBody::from(AppError::Internal)
+                }
+                app_error => app_error.with_message(err.to_string()),
+            }
+        }
+    }
+
+    // Makes sure whoever calls a server function as `user` holds their session,
+    // and says who they are.
+    pub fn check_session(state: &AppState, user: &str, token: &str) -> Result<Member, ServerFnError> {
+        state
+            .presence
+            .member_holding(user, token)
+            .ok_or_else(|| ErrorBody::from(AppError::Unauthorized).into())
+    }
+
+    fn check_room_admin(state: &AppState, room: &str, user: &Member) -> Result<(), ServerFnError> {
+        if state.is_room_admin(room, user) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden.with_message(format!("Only admins of #{room} can change it.")).into())
+        }
+    }
+
+    // Column `index` of a row, which holds JSON.
+    fn from_json<T: serde::de::DeserializeOwned>(index: usize, json: &str) -> rusqlite::Result<T> {
+        serde_json::from_str(json)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(err)))
+    }
+
+    fn now_millis() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as i64)
+    }
+
+    // How many people may have spoken in slow rooms before we first forget
+    // those who can speak again anyway.
+    const TRIM_LAST_POSTS_AT: usize = 1000;
+
+    // A change for the database, and what it's of for the log if it fails.
+    type Write = (String, Box<dyn FnOnce(&Connection) -> Result<usize, rusqlite::Error> + Send>);
+
+    // All rooms and their settings. Reads come from memory; changes are written
+    // through to SQLite in the background, so rooms survive restarts.
+    pub struct RoomStore {
+        rooms: DashMap<String, Room>,
+        invites: DashMap<String, Invite>,
+        // To the one thread that writes to the database, in the order the
+        // changes were made. Each is queued while the room or invite it's of is
+        // still locked, so a later change can't be written before an earlier one.
+        writes: mpsc::Sender<Write>,
+        // When each identity last spoke in each room, for slow mode. Only as
+        // long as that slow mode lasts, once there are `trim_last_posts_at`.
+        last_posts: DashMap<(String, String), Instant>,
+        // Twice as many as were left after the last trim, so that going through
+        // them all only happens after as many more posts.
+        trim_last_posts_at: AtomicUsize,
+    }
+
+    impl RoomStore {
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, RoomError> {
+            RoomStore::with_connection(Connection::open(path)?)
+        }
+
+        pub fn in_memory() -> Result<Self, RoomError> {
+            RoomStore::with_connection(Connection::open_in_memory()?)
+        }
+
+        fn with_connection(db: Connection) -> Result<Self, RoomError> {
+            db.execute_batch(SCHEMA)?;
+
+            let rooms = DashMap::new();
+            {
+                let mut statement = db.prepare(
+                    "SELECT name, topic, description, visibility, slow_mode, max_length, admins, members FROM rooms",
+                )?;
+                let loaded = statement.query_map([], |row| {
+                    let visibility: String = row.get(3)?;
+                    let admins: String = row.get(6)?;
+                    let members: String = row.get(7)?;
+                    Ok(Room {
+                        name: row.get(0)?,
+                        settings: RoomSettings {
+                            topic: row.get(1)?,
+                            description: row.get(2)?,
+                            visibility: visibility.parse().unwrap_or_default(),
+                            slow_mode: row.get(4)?,
+                            max_length: row.get(5)?,
+                        },
+                        admins: from_json(6, &admins)?,
+                        members: from_json(7, &members)?,
+                    })
+                })?;
+                for room in loaded {
+                    let room = room?;
+                    rooms.insert(room.name.clone(), room);
+                }
+            }
+
+            let invites = DashMap::new();
+            {
+                let mut statement =
+                    db.prepare("SELECT code, room, created_by, expires_at, max_uses, uses FROM invites")?;
+                let loaded = statement.query_map([], |row| {
+                    Ok(Invite {
+                        code: row.get(0)?,
+                        room: row.get(1)?,
+                        created_by: row.get(2)?,
+                        expires_at: row.get(3)?,
+                        max_uses: row.get(4)?,
+                        uses: row.get(5)?,
+                    })
+                })?;
+                for invite in loaded {
+                    let invite = invite?;
+                    invites.insert(invite.code.clone(), invite);
+                }
+            }
+
+            let (writes, queued) = mpsc::channel::<Write>();
+            thread::Builder::new().name(String::from("room-store")).spawn(move || {
+                for (what, write) in queued {
+                    if let Err(err) = write(&db) {
+                        tracing::error!("Couldn't save {what}: {err}");
+                    }
+                }
+            })?;
+
+            let store = RoomStore {
+                rooms,
+                invites,
+                writes,
+                last_posts: DashMap::new(),
+                trim_last_posts_at: AtomicUsize::new(TRIM_LAST_POSTS_AT),
+            };
+            if let Entry::Vacant(entry) = store.rooms.entry(DEFAULT_ROOM.to_owned()) {
+                store.save(&entry.insert(Room::new(DEFAULT_ROOM, Vec::new())));
+            }
+            Ok(store)
+        }
+
+        // Uses `CHAT_DATABASE`, like the search index. If that can't be opened,
+        // rooms last until the server stops.
+        pub fn from_env() -> Self {
+            let path = database_path();
+            RoomStore::open(&path)
+                .or_else(|err| {
+                    tracing::error!("Couldn't open {path}, keeping rooms in memory: {err}");
+                    RoomStore::in_memory()
+                })
+                .expect("SQLite can always open an in-memory database")
+        }
+
+        pub fn get(&self, name: &str) -> Option<Room> {
+            self.rooms.get(name).map(|room| room.clone())
+        }
+
+        pub fn contains(&self, name: &str) -> bool {
+            self.rooms.contains_key(name)
+        }
+
+        // By name.
+        pub fn list(&self) -> Vec<Room> {
+            let mut rooms: Vec<Room> = self.rooms.iter().map(|room| room.clone()).collect();
+            rooms.sort_by(|a, b| a.name.cmp(&b.name));
+            rooms
+        }
+
+        pub fn create(&self, name: &str, admin: Member) -> Result<Room, RoomError> {
+            if !is_room_name(name) {
+                return Err(RoomError::InvalidName);
+            }
+
+            match self.rooms.entry(name.to_owned()) {
+                Entry::Occupied(_) => Err(RoomError::Exists(name.to_owned())),
+                Entry::Vacant(entry) => {
+                    let room = entry.insert(Room::new(name, vec![admin]));
+                    self.save(&room);
+                    Ok(room.clone())
+                }
+            }
+        }
+
+        // Applies `change` to room `name` and returns how it looks now. Nobody
+        // else can change the room in between, so no change is lost.
+        pub fn update(&self, name: &str, change: impl FnOnce(&mut Room)) -> Result<Room, RoomError> {
+            let mut room = self.rooms.get_mut(name).ok_or_else(|| RoomError::NotFound(name.to_owned()))?;
+            change(&mut room);
+            self.save(&room);
+            Ok(room.clone())
+        }
+
+        // A new invitation to `room` from the identity `created_by`.
+        pub fn create_invite(
+            &self,
+            room: &str,
+            created_by: &str,
+            expires_in: Option<Duration>,
+            max_uses: Option<u32>,
+        ) -> Result<Invite, RoomError> {
+            if !self.contains(room) {
+                return Err(RoomError::NotFound(room.to_owned()));
+            }
+
+            let invite = Invite {
+                code: hex::encode(rand::random::<[u8; 16]>()),
+                room: room.to_owned(),
+                created_by: created_by.to_owned(),
+                expires_at: expires_in.map(|expires_in| now_millis() + expires_in.as_millis() as i64),
+                max_uses,
+                uses: 0,
+            };
+            let invite = self.invites.entry(invite.code.clone()).or_insert(invite);
+            self.save_invite(&invite);
+            Ok(invite.clone())
+        }
+
+        // Uses up one go of invitation `code` to make `member` a member of its
+        // room, unless they already were.
+        pub fn redeem_invite(&self, code: &str, member: Member) -> Result<Room, RoomError> {
+            let room = {
+                let mut invite = self.invites.get_mut(code).ok_or(RoomError::InvalidInvite)?;
+                let room = self.get(&invite.room).ok_or(RoomError::InvalidInvite)?;
+                if room.allows(&member.id) {
+                    return Ok(room);
+                }
+
+                let expired = invite.expires_at.is_some_and(|expires_at| expires_at <= now_millis());
+                let used_up = invite.max_uses.is_some_and(|max_uses| invite.uses >= max_uses);
+                if expired || used_up {
+                    return Err(RoomError::InvalidInvite);
+                }
+                invite.uses += 1;
+                self.save_invite(&invite);
+                room.name
+            };
+
+            self.update(&room, |room| {
+                if !room.allows(&member.id) {
+                    room.members.push(member);
+                }
+            })
+        }
+
+        // Writes `room`, as it is in memory, out without holding anyone up.
+        fn save(&self, room: &Room) {
+            let room = room.clone();
+            self.write(format!("room {}", room.name), move |db| {
+                let settings = &room.settings;
+                db.execute(
+                    "INSERT OR REPLACE INTO rooms (name, topic, description, visibility, slow_mode, max_length, admins, members)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        room.name,
+                        settings.topic,
+                        settings.description,
+                        settings.visibility.as_str(),
+                        settings.slow_mode,
+                        settings.max_length,
+                        serde_json::to_string(&room.admins).unwrap_or_default(),
+                        serde_json::to_string(&room.members).unwrap_or_default(),
+                    ],
+                )
+            });
+        }
+
+        fn save_invite(&self, invite: &Invite) {
+            let invite = invite.clone();
+            self.write(format!("invite to {}", invite.room), move |db| {
+                db.execute(
+                    "INSERT OR REPLACE INTO invites (code, room, created_by, expires_at, max_uses, uses)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        invite.code,
+                        invite.room,
+                        invite.created_by,
+                        invite.expires_at,
+                        invite.max_uses,
+                        invite.uses,
+                    ],
+                )
+            });
+        }
+
+        // Queues `write` for the database thread, which logs if it fails.
+        fn write<F>(&self, what: String, write: F)
+        where
+            F: FnOnce(&Connection) -> Result<usize, rusqlite::Error> + Send + 'static,
+        {
+            if self.writes.send((what, Box::new(write))).is_err() {
+                tracing::error!("The room database writer has stopped");
+            }
+        }
+
+        // Whether identity `id` may say `msg` in `room` now. Counts as them having
+        // said it if so. `exempt` skips slow mode, for admins.
+        pub fn check_post(&self, room: &str, id: &str, msg: &str, exempt: bool) -> Result<(), String> {
+            let Some(settings) = self.rooms.get(room).map(|room| room.settings.clone()) else {
+                return Err(format!("There is no room called #{room}."));
+            };
+
+            let max_length = settings.message_limit() as usize;
+            if msg.chars().count() > max_length {
+                return Err(format!("Messages in #{room} can be at most {max_length} characters."));
+            }
+
+            if settings.slow_mode > 0 && !exempt {
+                let slow_mode = Duration::from_secs(settings.slow_mode.into());
+                let now = Instant::now();
+                match self.last_posts.entry((room.to_owned(), id.to_owned())) {
+                    Entry::Occupied(mut entry) => {
+                        let since = now.duration_since(*entry.get());
+                        if since < slow_mode {
+                            let wait = (slow_mode - since).as_secs().max(1);
+                            return Err(format!("#{room} is in slow mode. You can send another message in {wait}s."));
+                        }
+                        entry.insert(now);
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(now);
+                    }
+                }
+
+                if self.last_posts.len() > self.trim_last_posts_at.load(Ordering::Relaxed) {
+                    self.last_posts.retain(|(room, _), spoke| {
+                        self.rooms.get(room).is_some_and(|room| {
+                            now.duration_since(*spoke) < Duration::from_secs(room.settings.slow_mode.into())
+                        })
+                    });
+                    let next = (2 * self.last_posts.len()).max(TRIM_LAST_POSTS_AT);
+                    self.trim_last_posts_at.store(next, Ordering::Relaxed);
+                }
+            }
+            Ok(())
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    fn member(id: &str, name: &str) -> Member {
+        Member {
+            id: id.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn private_room(store: &RoomStore) -> Room {
+        store.create("secret", member("a1", "alice")).unwrap();
+        store
+            .update("secret", |room| room.settings.visibility = Visibility::Private)
+            .unwrap()
+    }
+
+    #[test]
+    fn rooms_let_in_identities_not_names() {
+        let store = RoomStore::in_memory().unwrap();
+        let room = private_room(&store);
+
+        assert!(room.allows("a1"));
+        assert!(room.is_admin("a1"));
+        // Someone else who took the name afterwards.
+        assert!(!room.allows("alice"));
+        assert!(!room.allows("b2"));
+
+        let room = store.update("secret", |room| room.members.push(member("b2", "bob"))).unwrap();
+        assert!(room.allows("b2"));
+        assert!(!room.is_admin("b2"));
+    }
+
+    #[test]
+    fn invites_run_out() {
+        let store = RoomStore::in_memory().unwrap();
+        private_room(&store);
+        let invite = store.create_invite("secret", "alice", None, Some(1)).unwrap();
+
+        let room = store.redeem_invite(&invite.code, member("b2", "bob")).unwrap();
+        assert!(room.allows("b2"));
+        // Members going back in don't use it up.
+        store.redeem_invite(&invite.code, member("b2", "bob")).unwrap();
+        assert!(matches!(
+            store.redeem_invite(&invite.code, member("c3", "carol")),
+            Err(RoomError::InvalidInvite)
+        ));
+
+        let expired = store
+            .create_invite("secret", "alice", Some(Duration::ZERO), None)
+            .unwrap();
+        assert!(matches!(
+            store.redeem_invite(&expired.code, member("c3", "carol")),
+            Err(RoomError::InvalidInvite)
+        ));
+        assert!(matches!(
+            store.redeem_invite("nonsense", member("c3", "carol")),
+            Err(RoomError::InvalidInvite)
+        ));
+        assert!(!store.get("secret").unwrap().allows("c3"));
+    }
+
+    #[test]
+    fn racing_updates_keep_every_change() {
+        let store = Arc::new(RoomStore::in_memory().unwrap());
+        private_room(&store);
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for j in 0..50 {
+                        let id = format!("{i}-{j}");
+                        store.update("secret", |room| room.members.push(member(&id, &id))).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.get("secret").unwrap().members.len(), 8 * 50);
+    }
+
+    #[test]
+    fn slow_mode_forgets_those_it_no_longer_holds_back() {
+        let store = RoomStore::in_memory().unwrap();
+        for name in ["slow", "quick"] {
+            store.create(name, member("a1", "alice")).unwrap();
+            store.update(name, |room| room.settings.slow_mode = 60).unwrap();
+        }
+
+        for i in 0..TRIM_LAST_POSTS_AT {
+            store.check_post("quick", &i.to_string(), "hi", false).unwrap();
+        }
+        store.update("quick", |room| room.settings.slow_mode = 0).unwrap();
+
+        store.check_post("slow", "a1", "hi", false).unwrap();
+        assert!(store.check_post("slow", "a1", "again", false).is_err());
+        assert!(store.check_post("slow", "a1", "again", true).is_ok());
+        store.check_post("slow", "b2", "hi", false).unwrap();
+        assert_eq!(store.last_posts.len(), 2);
+
+        // Those still held back are kept, and only looked at again once there
+        // are twice as many.
+        for i in 0..TRIM_LAST_POSTS_AT {
+            store.check_post("slow", &i.to_string(), "hi", false).unwrap();
+        }
+        assert_eq!(store.last_posts.len(), TRIM_LAST_POSTS_AT + 2);
+        assert_eq!(store.trim_last_posts_at.load(Ordering::Relaxed), 2 * (TRIM_LAST_POSTS_AT + 1));
+    }
+
+    #[test]
+    fn messages_have_a_length_limit_by_default() {
+        let store = RoomStore::in_memory().unwrap();
+        let longest = "a".repeat(MAX_MESSAGE_CHARS as usize);
+        assert!(store.check_post(DEFAULT_ROOM, "a1", &longest, false).is_ok());
+        assert!(store.check_post(DEFAULT_ROOM, "a1", &format!("{longest}a"), false).is_err());
+
+        let settings = RoomSettings {
+            max_length: MAX_MESSAGE_CHARS + 1,
+            ..RoomSettings::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+}
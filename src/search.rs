@@ -0,0 +1,372 @@
+use crate::{error_template::error_message, ws::JoinedAs};
+use cfg_if::cfg_if;
+use leptos::*;
+use leptos_router::A;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// A message that matched a search, with the part around the match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SearchResult {
+    pub id: Uuid,
+    pub room: String,
+    pub sender: String,
+    // Milliseconds since the Unix epoch.
+    pub sent_at: i64,
+    // The text around the match, in pieces; those marked `true` matched.
+    pub snippet: Vec<(String, bool)>,
+}
+
+// Messages matching every word of `query`, newest first, from the rooms `user`
+// can read. The other arguments narrow it down; `since` and `until` are
+// milliseconds since the Unix epoch.
+#[server(SearchMessages, "/api")]
+pub async fn search_messages(
+    user: Option<String>,
+    token: Option<String>,
+    query: String,
+    room: Option<String>,
+    author: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+) -> Result<Vec<SearchResult>, ServerFnError> {
+    use crate::{chat::AppState, error_template::{AppError, ErrorBody}, rooms::check_session};
+    use std::sync::Arc;
+
+    let state = use_context::<Arc<AppState>>()
+        .ok_or(ErrorBody::from(AppError::Internal))?;
+    // Nobody is a member of anything without a session.
+    let viewer = match (user, token) {
+        (Some(user), Some(token)) => check_session(&state, &user, &token).unwrap_or_default(),
+        _ => Default::default(),
+    };
+    let hidden_rooms = state
+        .rooms
+        .list()
+        .into_iter()
+        .filter(|room| !state.can_enter(room, &viewer))
+        .map(|room| room.name)
+        .collect();
+
+    state
+        .search
+        .search(SearchQuery {
+            query,
+            room,
+            author,
+            since,
+            until,
+            hidden_rooms,
+        })
+        .await
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::ws::ServerMessage;
+    use rusqlite::{params, Connection};
+    use std::{
+        env,
+        path::Path,
+        sync::{Arc, Mutex, PoisonError},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use thiserror::Error;
+
+    // How many results a search returns at most.
+    const MAX_RESULTS: usize = 50;
+
+    // Marks the start and end of a match in snippets. Neither can be typed into
+    // a chat message by accident.
+    const MATCH_START: char = '\u{2}';
+    const MATCH_END: char = '\u{3}';
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            room TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            msg TEXT NOT NULL,
+            sent_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS messages_sent_at ON messages (sent_at);
+        CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+            msg,
+            content = 'messages',
+            content_rowid = 'rowid'
+        );
+        CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, msg) VALUES (new.rowid, new.msg);
+        END;
+    ";
+
+    // Where what the server keeps is stored: `CHAT_DATABASE`, or `chat.sqlite3`
+    // in the working directory.
+    pub fn database_path() -> String {
+        env::var("CHAT_DATABASE").unwrap_or_else(|_| String::from("chat.sqlite3"))
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct SearchQuery {
+        pub query: String,
+        pub room: Option<String>,
+        pub author: Option<String>,
+        pub since: Option<i64>,
+        pub until: Option<i64>,
+        // Rooms whose messages are left out, e.g. private ones.
+        pub hidden_rooms: Vec<String>,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum SearchError {
+        #[error("search database: {0}")]
+        Database(#[from] rusqlite::Error),
+        #[error("search task failed: {0}")]
+        Task(#[from] tokio::task::JoinError),
+    }
+
+    // Every chat message ever sent, in SQLite with a full-text index. Unlike the
+    // history, it survives restarts.
+    #[derive(Clone)]
+    pub struct SearchIndex {
+        db: Arc<Mutex<Connection>>,
+    }
+
+    impl SearchIndex {
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, SearchError> {
+            SearchIndex::with_connection(Connection::open(path)?)
+        }
+
+        pub fn in_memory() -> Result<Self, SearchError> {
+            SearchIndex::with_connection(Connection::open_in_memory()?)
+        }
+
+        fn with_connection(db: Connection) -> Result<Self, SearchError> {
+            db.execute_batch(SCHEMA)?;
+            Ok(SearchIndex {
+                db: Arc::new(Mutex::new(db)),
+            })
+        }
+
+        // Uses `database_path()`. If that can't be opened, search still works
+        // until the server stops.
+        pub fn from_env() -> Self {
+            let path = database_path();
+            SearchIndex::open(&path)
+                .or_else(|err| {
+                    tracing::error!("Couldn't open {path}, keeping messages in memory: {err}");
+                    SearchIndex::in_memory()
+                })
+                .expect("SQLite can always open an in-memory database")
+        }
+
+        // Runs `f` on the database without holding up the async runtime.
+        async fn with_db<T, F>(&self, f: F) -> Result<T, SearchError>
+        where
+            T: Send + 'static,
+            F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+        {
+            let db = self.db.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let db = db.lock().unwrap_or_else(PoisonError::into_inner);
+                f(&db)
+            })
+            .await?;
+
+            Ok(result?)
+        }
+
+        // Adds a message sent to `room` just now. Messages that were already
+        // added are left alone.
+        pub async fn index(&self, room: &str, message: &ServerMessage) -> Result<(), SearchError> {
+            let sent_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as i64);
+            let (id, room, sender, msg) = (
+                message.id.to_string(),
+                room.to_owned(),
+                message.sender.clone(),
+                message.msg.clone(),
+            );
+
+            self.with_db(move |db| {
+                db.execute(
+                    "INSERT OR IGNORE INTO messages (id, room, sender, msg, sent_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![id, room, sender, msg, sent_at],
+                )
+                .map(|_| ())
+            })
+            .await
+        }
+
+        pub async fn search(&self, query: SearchQuery) -> Result<Vec<SearchResult>, SearchError> {
+            let Some(pattern) = match_pattern(&query.query) else {
+                return Ok(Vec::new());
+            };
+
+            self.with_db(move |db| {
+                let mut statement = db.prepare_cached(
+                    "SELECT m.id, m.room, m.sender, m.sent_at,
+                            snippet(messages_fts, 0, char(2), char(3), '…', 24)
+                     FROM messages_fts JOIN messages m ON m.rowid = messages_fts.rowid
+                     WHERE messages_fts MATCH ?1
+                       AND (?2 IS NULL OR m.room = ?2)
+                       AND (?3 IS NULL OR m.sender = ?3)
+                       AND (?4 IS NULL OR m.sent_at >= ?4)
+                       AND (?5 IS NULL OR m.sent_at < ?5)
+                       AND m.room NOT IN (SELECT value FROM json_each(?7))
+                     ORDER BY m.sent_at DESC
+                     LIMIT ?6",
+                )?;
+
+                let hidden_rooms = serde_json::to_string(&query.hidden_rooms).unwrap_or_else(|_| String::from("[]"));
+                let rows = statement.query_map(
+                    params![pattern, query.room, query.author, query.since, query.until, MAX_RESULTS, hidden_rooms],
+                    |row| {
+                        let id: String = row.get(0)?;
+                        let snippet: String = row.get(4)?;
+                        Ok(SearchResult {
+                            id: id.parse().unwrap_or_default(),
+                            room: row.get(1)?,
+                            sender: row.get(2)?,
+                            sent_at: row.get(3)?,
+                            snippet: split_snippet(&snippet),
+                        })
+                    },
+                )?;
+                rows.collect()
+            })
+            .await
+        }
+    }
+
+    // Turns what someone typed into an FTS5 query matching all of its words, so
+    // that quotes, `NEAR` and the like are searched for rather than obeyed. The
+    // last word also matches as a prefix, for searching while typing.
+    fn match_pattern(query: &str) -> Option<String> {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect();
+        let last = words.last()?;
+
+        let mut pattern = words[..words.len() - 1].join(" ");
+        if !pattern.is_empty() {
+            pattern.push(' ');
+        }
+        pattern.push_str(last);
+        pattern.push('*');
+        Some(pattern)
+    }
+
+    fn split_snippet(snippet: &str) -> Vec<(String, bool)> {
+        let mut pieces = Vec::new();
+        let mut rest = snippet;
+
+        while let Some(start) = rest.find(MATCH_START) {
+            if start > 0 {
+                pieces.push((rest[..start].to_owned(), false));
+            }
+            rest = &rest[start + MATCH_START.len_utf8()..];
+            let end = rest.find(MATCH_END).unwrap_or(rest.len());
+            pieces.push((rest[..end].to_owned(), true));
+            rest = rest.get(end + MATCH_END.len_utf8()..).unwrap_or("");
+        }
+        if !rest.is_empty() {
+            pieces.push((rest.to_owned(), false));
+        }
+
+        pieces
+    }
+}}
+
+// Milliseconds since the Unix epoch for a `<input type="date">` value, which is
+// midnight UTC that day. Empty or unreadable dates don't narrow the search.
+fn date_millis(date: &str) -> Option<i64> {
+    let millis = js_sys::Date::parse(date);
+    (!date.is_empty() && millis.is_finite()).then_some(millis as i64)
+}
+
+fn format_date(millis: i64) -> String {
+    js_sys::Date::new(&wasm_bindgen::JsValue::from_f64(millis as f64))
+        .to_locale_string("default", &wasm_bindgen::JsValue::UNDEFINED)
+        .into()
+}
+
+// Searches what was said, in a panel next to the chat. Each result links back
+// to its message in the chat, if it's still in the history there.
+#[component]
+pub fn SearchPage() -> impl IntoView {
+    let (query, set_query) = create_signal(String::new());
+    let (author, set_author) = create_signal(String::new());
+    let (since, set_since) = create_signal(String::new());
+    let (until, set_until) = create_signal(String::new());
+
+    let joined_as = use_context::<JoinedAs>();
+    let search = create_action(move |_: &()| {
+        let (user, token) = joined_as.map(JoinedAs::credentials).unwrap_or_default();
+        let optional = |value: String| Some(value.trim().to_owned()).filter(|value| !value.is_empty());
+        // `until` is inclusive, so the search runs to the end of that day.
+        let until = date_millis(&until.get_untracked()).map(|until| until + 24 * 60 * 60 * 1000);
+        search_messages(
+            user,
+            token,
+            query.get_untracked(),
+            None,
+            optional(author.get_untracked()),
+            date_millis(&since.get_untracked()),
+            until,
+        )
+    });
+
+    let on_submit = move |ev: web_sys::SubmitEvent| {
+        ev.prevent_default();
+        search.dispatch(());
+    };
+
+    let results = move || match search.value().get() {
+        None => ().into_view(),
+        Some(Err(err)) => view! { <p class="search__error">{error_message(&err)}</p> }.into_view(),
+        Some(Ok(results)) if results.is_empty() => view! { <p class="search__empty">"Nothing found."</p> }.into_view(),
+        Some(Ok(results)) => results
+            .into_iter()
+            .map(|result| {
+                let snippet = result
+                    .snippet
+                    .into_iter()
+                    .map(|(text, matched)| if matched { view! { <mark>{text}</mark> }.into_view() } else { text.into_view() })
+                    .collect_view();
+                view! {
+                    <li class="search__result">
+                        <span class="chat-message__sender">{result.sender}</span>
+                        <time class="search__date">{format_date(result.sent_at)}</time>
+                        <p class="search__snippet">{snippet}</p>
+                        <A href=format!("/#message-{}", result.id)>"Show in chat"</A>
+                    </li>
+                }
+            })
+            .collect_view(),
+    };
+
+    view! {
+        <section class="search">
+            <form class="search__form" on:submit=on_submit>
+                <input
+                    type="search"
+                    placeholder="Search messages"
+                    prop:value=query
+                    on:input=move |ev| set_query.set(event_target_value(&ev))
+                />
+                <input placeholder="From" prop:value=author on:input=move |ev| set_author.set(event_target_value(&ev))/>
+                <label>"Since " <input type="date" prop:value=since on:input=move |ev| set_since.set(event_target_value(&ev))/></label>
+                <label>"Until " <input type="date" prop:value=until on:input=move |ev| set_until.set(event_target_value(&ev))/></label>
+                <button type="submit" disabled=move || query.get().trim().is_empty()>"search"</button>
+                <A href="/">"close"</A>
+            </form>
+            <Show when=move || search.pending().get() fallback=|| ()>
+                <p class="search__pending">"Searching…"</p>
+            </Show>
+            <ol class="search__results">{results}</ol>
+        </section>
+    }
+}
@@ -0,0 +1,156 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{header, HeaderName, HeaderValue, Request, StatusCode},
+        middleware::Next,
+        response::Response,
+    };
+    use leptos::{nonce::use_nonce, use_context};
+    use leptos_axum::{RequestParts, ResponseOptions};
+    use serde_json::Value;
+    use std::env;
+
+    // Where browsers send reports of what the policy blocked.
+    pub const CSP_REPORT_PATH: &str = "/csp-report";
+    // Reports are small; anything much bigger isn't one.
+    pub const MAX_CSP_REPORT_BYTES: usize = 64 * 1024;
+
+    // Names `CSP_REPORT_PATH` for the policy's `report-to`.
+    const REPORTING_ENDPOINTS: &str = "csp=\"/csp-report\"";
+
+    // Responses that aren't pages have nothing to run or load, and nobody
+    // should put them in a frame.
+    const BASE_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+
+    // Browsers only take this over HTTPS, where it keeps them there for two
+    // years.
+    const HSTS: &str = "max-age=63072000; includeSubDomains";
+
+    // What happens to the Content Security Policy, from `CHAT_CSP`: `enforce`
+    // (the default), `report-only` to try a policy out without breaking
+    // anything, or `off`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum CspMode {
+        #[default]
+        Enforce,
+        ReportOnly,
+        Off,
+    }
+
+    impl CspMode {
+        pub fn from_env() -> Self {
+            match env::var("CHAT_CSP").as_deref() {
+                Ok("report-only") => CspMode::ReportOnly,
+                Ok("off") => CspMode::Off,
+                Ok("enforce") | Err(_) => CspMode::Enforce,
+                Ok(other) => {
+                    tracing::error!("Ignoring CHAT_CSP={other:?}, enforcing the policy");
+                    CspMode::Enforce
+                }
+            }
+        }
+
+        fn header(self) -> Option<HeaderName> {
+            match self {
+                CspMode::Enforce => Some(header::CONTENT_SECURITY_POLICY),
+                CspMode::ReportOnly => Some(header::CONTENT_SECURITY_POLICY_REPORT_ONLY),
+                CspMode::Off => None,
+            }
+        }
+    }
+
+    // The policy for a page: scripts only from us and from the page itself when
+    // they carry its nonce, which Leptos puts on its hydration scripts. Images
+    // may come from anywhere, for link previews.
+    pub fn page_policy(nonce: &str, host: Option<&str>) -> String {
+        // Older browsers don't count websockets to the same host as 'self'.
+        let websocket = host.map(|host| format!(" ws://{host} wss://{host}")).unwrap_or_default();
+        format!(
+            "default-src 'self'; \
+             script-src 'self' 'nonce-{nonce}' 'wasm-unsafe-eval'; \
+             style-src 'self' 'nonce-{nonce}'; \
+             img-src 'self' https: data:; \
+             connect-src 'self'{websocket}; \
+             object-src 'none'; \
+             base-uri 'none'; \
+             form-action 'self'; \
+             frame-ancestors 'none'; \
+             report-uri {CSP_REPORT_PATH}; \
+             report-to csp"
+        )
+    }
+
+    // Gives the page being rendered its policy, with the nonce Leptos made for
+    // it. `security_headers` sends it as configured.
+    pub fn provide_page_policy() {
+        let (Some(nonce), Some(response)) = (use_nonce(), use_context::<ResponseOptions>()) else {
+            return;
+        };
+        let host = use_context::<RequestParts>()
+            .and_then(|request| request.headers.get(header::HOST)?.to_str().ok().map(str::to_owned));
+        match HeaderValue::try_from(page_policy(&nonce, host.as_deref())) {
+            Ok(policy) => response.insert_header(header::CONTENT_SECURITY_POLICY, policy),
+            Err(err) => tracing::error!("Not sending a policy with host {host:?}: {err}"),
+        }
+    }
+
+    // Adds our security headers to every response. Pages bring their own
+    // Content Security Policy; everything else gets `BASE_POLICY`.
+    pub async fn security_headers<B>(State(csp): State<CspMode>, req: Request<B>, next: Next<B>) -> Response {
+        let mut res = next.run(req).await;
+        let headers = res.headers_mut();
+
+        headers.insert(header::STRICT_TRANSPORT_SECURITY, HeaderValue::from_static(HSTS));
+        headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("strict-origin-when-cross-origin"));
+        // For browsers that don't know `frame-ancestors`.
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+
+        let policy = headers
+            .remove(header::CONTENT_SECURITY_POLICY)
+            .unwrap_or(HeaderValue::from_static(BASE_POLICY));
+        if let Some(name) = csp.header() {
+            headers.insert(name, policy);
+            headers.insert(
+                HeaderName::from_static("reporting-endpoints"),
+                HeaderValue::from_static(REPORTING_ENDPOINTS),
+            );
+        }
+        res
+    }
+
+    // Logs what the policy blocked. `report-uri` sends `{"csp-report": {...}}`
+    // with dashed names, `report-to` a list of `{"type": "csp-violation",
+    // "body": {...}}` with camel-cased ones.
+    pub async fn csp_report_handler(body: Bytes) -> StatusCode {
+        let reports = match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Array(reports)) => reports
+                .into_iter()
+                .filter(|report| report["type"] == "csp-violation")
+                .map(|report| report["body"].clone())
+                .collect(),
+            Ok(report) => vec![report["csp-report"].clone()],
+            Err(_) => return StatusCode::BAD_REQUEST,
+        };
+
+        for report in reports.iter().filter(|report| report.is_object()) {
+            let field = |names: [&str; 2]| {
+                names
+                    .into_iter()
+                    .find_map(|name| report[name].as_str())
+                    .unwrap_or("?")
+                    .to_owned()
+            };
+            tracing::warn!(
+                "Content Security Policy blocked {} on {} ({})",
+                field(["blocked-uri", "blockedURL"]),
+                field(["document-uri", "documentURL"]),
+                field(["effective-directive", "effectiveDirective"]),
+            );
+        }
+        StatusCode::NO_CONTENT
+    }
+}}
@@ -0,0 +1,260 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::listen::ACCEPT_ERROR_PAUSE;
+    use axum::{
+        extract::ConnectInfo,
+        http::{header, uri::Authority, Request, StatusCode, Uri},
+        response::{IntoResponse, Redirect, Response},
+        Router,
+    };
+    use hyper::{server::conn::Http, Body};
+    use rustls_pemfile::Item;
+    use std::{
+        env,
+        fs::{self, File},
+        io::{self, BufReader},
+        net::{SocketAddr, TcpListener as StdTcpListener},
+        path::{Path, PathBuf},
+        sync::{Arc, RwLock},
+        time::{Duration, SystemTime},
+    };
+    use thiserror::Error;
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            server::{ClientHello, ResolvesServerCert},
+            sign::{self, CertifiedKey},
+            Certificate, PrivateKey, ServerConfig,
+        },
+        TlsAcceptor,
+    };
+    use tower::ServiceExt;
+
+    // How often the certificate files are checked for changes.
+    pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+    // Handshakes that take longer than this are given up on, so half-open
+    // connections don't pile up.
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Debug, Error)]
+    pub enum TlsError {
+        #[error("reading {0}: {1}")]
+        Read(PathBuf, io::Error),
+        #[error("{0} has no certificates")]
+        NoCertificates(PathBuf),
+        #[error("{0} has no private key")]
+        NoKey(PathBuf),
+        #[error("the key in {0} isn't one we can use")]
+        UnsupportedKey(PathBuf),
+    }
+
+    // HTTPS straight from us, without a proxy in front, from `CHAT_TLS_CERT`
+    // (PEM certificate chain) and `CHAT_TLS_KEY` (PEM private key). With
+    // `CHAT_HTTP_REDIRECT_ADDR` set, plain HTTP there is sent on to HTTPS.
+    #[derive(Debug, Clone)]
+    pub struct TlsConfig {
+        pub cert: PathBuf,
+        pub key: PathBuf,
+        pub redirect_addr: Option<SocketAddr>,
+    }
+
+    impl TlsConfig {
+        pub fn from_env() -> Option<Self> {
+            let cert = env::var_os("CHAT_TLS_CERT")?;
+            let key = env::var_os("CHAT_TLS_KEY")?;
+            let redirect_addr = env::var("CHAT_HTTP_REDIRECT_ADDR").ok().and_then(|addr| match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(err) => {
+                    tracing::error!("Not redirecting HTTP from {addr:?}: {err}");
+                    None
+                }
+            });
+
+            Some(TlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+                redirect_addr,
+            })
+        }
+    }
+
+    // The certificate we present, read again whenever its files change, so
+    // renewing it doesn't take a restart. Connections already open keep the
+    // certificate they started with.
+    pub struct ReloadingCert {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+    }
+
+    impl ReloadingCert {
+        pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Self, TlsError> {
+            let (cert_path, key_path) = (cert_path.into(), key_path.into());
+            let modified = last_modified(&cert_path, &key_path);
+            let key = load_certified_key(&cert_path, &key_path)?;
+
+            Ok(ReloadingCert {
+                cert_path,
+                key_path,
+                current: RwLock::new((Arc::new(key), modified)),
+            })
+        }
+
+        // Reads the files again if either changed since we last did. A broken
+        // pair, like a certificate renewed before its key, leaves the old one
+        // in place until both are there.
+        pub fn reload_if_changed(&self) -> bool {
+            let modified = last_modified(&self.cert_path, &self.key_path);
+            if modified == self.current.read().unwrap_or_else(|err| err.into_inner()).1 {
+                return false;
+            }
+
+            match load_certified_key(&self.cert_path, &self.key_path) {
+                Ok(key) => {
+                    *self.current.write().unwrap_or_else(|err| err.into_inner()) = (Arc::new(key), modified);
+                    tracing::info!("Reloaded the TLS certificate from {}", self.cert_path.display());
+                    true
+                }
+                Err(err) => {
+                    tracing::error!("Keeping the old TLS certificate: {err}");
+                    false
+                }
+            }
+        }
+
+        // Checks for changes every `interval`, for as long as the server runs.
+        pub fn spawn_reload(self: &Arc<Self>, interval: Duration) {
+            let cert = self.clone();
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(interval);
+                loop {
+                    ticks.tick().await;
+                    let cert = cert.clone();
+                    let _ = tokio::task::spawn_blocking(move || cert.reload_if_changed()).await;
+                }
+            });
+        }
+    }
+
+    impl ResolvesServerCert for ReloadingCert {
+        fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+            Some(self.current.read().unwrap_or_else(|err| err.into_inner()).0.clone())
+        }
+    }
+
+    // When the later of the two files last changed, if we can tell.
+    fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        modified(cert_path).max(modified(key_path))
+    }
+
+    fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+        let open = |path: &Path| File::open(path).map(BufReader::new).map_err(|err| TlsError::Read(path.to_owned(), err));
+
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(cert_path)?)
+            .map_err(|err| TlsError::Read(cert_path.to_owned(), err))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(cert_path.to_owned()));
+        }
+
+        let key = rustls_pemfile::read_all(&mut open(key_path)?)
+            .map_err(|err| TlsError::Read(key_path.to_owned(), err))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| TlsError::NoKey(key_path.to_owned()))?;
+        let key = sign::any_supported_type(&key).map_err(|_| TlsError::UnsupportedKey(key_path.to_owned()))?;
+
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    // Serves `app` over TLS to everyone connecting to `listener`, with each
+    // request knowing who it came from like `into_make_service_with_connect_info`
+    // would.
+    pub async fn serve_tls(listener: StdTcpListener, app: Router, cert: Arc<ReloadingCert>) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(cert);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!("Accepting a connection failed: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let app = app.clone();
+            tokio::spawn(async move {
+                let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        tracing::debug!("TLS handshake with {peer} failed: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!("TLS handshake with {peer} timed out");
+                        return;
+                    }
+                };
+
+                let service = app.map_request(move |mut req: Request<Body>| {
+                    req.extensions_mut().insert(ConnectInfo(peer));
+                    req
+                });
+                // Upgrades are how websockets get their connection.
+                if let Err(err) = Http::new().serve_connection(stream, service).with_upgrades().await {
+                    tracing::debug!("Connection with {peer} failed: {err}");
+                }
+            });
+        }
+    }
+
+    // Sends everyone connecting to `listener` over plain HTTP on to the same
+    // place over HTTPS, on `https_port`.
+    pub async fn serve_redirect(listener: StdTcpListener, https_port: u16) -> Result<(), hyper::Error> {
+        let app = Router::new().fallback(move |req: Request<Body>| async move { to_https(&req, https_port) });
+        axum::Server::from_tcp(listener)?
+            .serve(app.into_make_service())
+            .await
+    }
+
+    // A permanent redirect that keeps the method, so forms posted to the old
+    // address still arrive.
+    fn to_https(req: &Request<Body>, https_port: u16) -> Response {
+        let Some(host) = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+        else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+
+        let authority = match https_port {
+            443 => host.host().to_owned(),
+            port => format!("{}:{port}", host.host()),
+        };
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        match Uri::builder().scheme("https").authority(authority).path_and_query(path).build() {
+            Ok(uri) => Redirect::permanent(&uri.to_string()).into_response(),
+            Err(_) => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}}
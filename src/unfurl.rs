@@ -0,0 +1,462 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::ws::LinkPreview;
+    use dashmap::DashMap;
+    use hyper::client::connect::dns::Name;
+    use reqwest::{
+        dns::{Addrs, Resolve, Resolving},
+        header, redirect, Client,
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use url::{Host, Url};
+
+    // How many redirects we follow before giving up on a link.
+    const MAX_REDIRECTS: usize = 5;
+
+    // Longer titles and descriptions are cut short.
+    const MAX_TITLE_CHARS: usize = 200;
+    const MAX_DESCRIPTION_CHARS: usize = 300;
+
+    #[derive(Debug, Clone)]
+    pub struct UnfurlConfig {
+        // For the whole fetch, redirects and body included.
+        pub timeout: Duration,
+        // Pages are read up to this many bytes; the metadata is in the head anyway.
+        pub max_bytes: usize,
+        // How long a preview, or the lack of one, is remembered.
+        pub cache_ttl: Duration,
+        pub cache_capacity: usize,
+        // Whether links may point into private networks, loopback included. Only
+        // ever for tests: otherwise anyone in the chat could have the server fetch
+        // pages that only it can reach.
+        pub allow_private: bool,
+    }
+
+    impl Default for UnfurlConfig {
+        fn default() -> Self {
+            UnfurlConfig {
+                timeout: Duration::from_secs(5),
+                max_bytes: 512 * 1024,
+                cache_ttl: Duration::from_secs(60 * 60),
+                cache_capacity: 1024,
+                allow_private: false,
+            }
+        }
+    }
+
+    // Fetches the Open Graph (or Twitter card) metadata of links posted in the
+    // chat, to show them as preview cards.
+    pub struct Unfurler {
+        client: Client,
+        config: UnfurlConfig,
+        cache: DashMap<Url, (Instant, Option<LinkPreview>)>,
+    }
+
+    impl Unfurler {
+        pub fn new(config: UnfurlConfig) -> Self {
+            // Redirects are followed by hand so that every hop is checked, and
+            // proxies are off since they would resolve names for us.
+            let client = Client::builder()
+                .redirect(redirect::Policy::none())
+                .no_proxy()
+                .connect_timeout(config.timeout)
+                .user_agent(concat!("web-app-axum/", env!("CARGO_PKG_VERSION"), " (link preview)"))
+                .dns_resolver(Arc::new(PublicResolver {
+                    allow_private: config.allow_private,
+                }))
+                .build()
+                .expect("the TLS backend is available");
+
+            Unfurler {
+                client,
+                config,
+                cache: DashMap::new(),
+            }
+        }
+
+        // The preview for `url`, if it leads to a page that has one. Failures
+        // are cached too, so a dead link posted twice is only fetched once.
+        pub async fn unfurl(&self, url: &Url) -> Option<LinkPreview> {
+            if let Some(entry) = self.cache.get(url) {
+                let (fetched_at, preview) = entry.value();
+                if fetched_at.elapsed() < self.config.cache_ttl {
+                    return preview.clone();
+                }
+            }
+
+            let preview = match tokio::time::timeout(self.config.timeout, self.fetch(url)).await {
+                Ok(Ok(preview)) => preview,
+                Ok(Err(err)) => {
+                    tracing::debug!("Couldn't unfurl {url}: {err}");
+                    None
+                }
+                Err(_) => {
+                    tracing::debug!("Unfurling {url} timed out");
+                    None
+                }
+            };
+
+            self.remember(url.clone(), preview.clone());
+            preview
+        }
+
+        fn remember(&self, url: Url, preview: Option<LinkPreview>) {
+            if self.cache.len() >= self.config.cache_capacity {
+                let ttl = self.config.cache_ttl;
+                self.cache.retain(|_, (fetched_at, _)| fetched_at.elapsed() < ttl);
+            }
+            if self.cache.len() >= self.config.cache_capacity {
+                // Still full of fresh entries, so the oldest goes.
+                let oldest = self.cache.iter().min_by_key(|entry| entry.value().0).map(|entry| entry.key().clone());
+                if let Some(oldest) = oldest {
+                    self.cache.remove(&oldest);
+                }
+            }
+
+            self.cache.insert(url, (Instant::now(), preview));
+        }
+
+        async fn fetch(&self, url: &Url) -> Result<Option<LinkPreview>, UnfurlError> {
+            let mut url = url.clone();
+            let mut redirects = 0;
+
+            let mut response = loop {
+                self.check(&url)?;
+
+                let response = self
+                    .client
+                    .get(url.clone())
+                    .header(header::ACCEPT, "text/html,application/xhtml+xml")
+                    .send()
+                    .await?;
+                if !response.status().is_redirection() {
+                    break response;
+                }
+
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(UnfurlError::TooManyRedirects);
+                }
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(UnfurlError::Refused("a redirect without a location"))?;
+                url = url.join(location)?;
+            };
+
+            if !response.status().is_success() {
+                return Ok(None);
+            }
+            let is_html = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| {
+                    let content_type = content_type.to_ascii_lowercase();
+                    content_type.starts_with("text/html") || content_type.starts_with("application/xhtml+xml")
+                });
+            if !is_html {
+                return Ok(None);
+            }
+
+            // Read no more than we allow, whatever `Content-Length` says.
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                let room = self.config.max_bytes - body.len();
+                if chunk.len() >= room {
+                    body.extend_from_slice(&chunk[..room]);
+                    break;
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            Ok(parse_preview(&url, &String::from_utf8_lossy(&body)))
+        }
+
+        // Names are checked as they are resolved (see `PublicResolver`), but
+        // addresses written into the link never get that far.
+        fn check(&self, url: &Url) -> Result<(), UnfurlError> {
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(UnfurlError::Refused("a link that isn't http or https"));
+            }
+
+            let ip = match url.host() {
+                Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+                Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+                Some(Host::Domain(_)) => return Ok(()),
+                None => return Err(UnfurlError::Refused("a link without a host")),
+            };
+            if self.config.allow_private || is_public(ip) {
+                Ok(())
+            } else {
+                Err(UnfurlError::Refused("a private address"))
+            }
+        }
+    }
+
+    impl Default for Unfurler {
+        fn default() -> Self {
+            Self::new(UnfurlConfig::default())
+        }
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    enum UnfurlError {
+        #[error("refusing to fetch {0}")]
+        Refused(&'static str),
+        #[error("too many redirects")]
+        TooManyRedirects,
+        #[error(transparent)]
+        Url(#[from] url::ParseError),
+        #[error(transparent)]
+        Http(#[from] reqwest::Error),
+    }
+
+    // Resolves names as usual, but only hands out public addresses. Checking
+    // here, rather than before the request, means a name can't resolve to
+    // something harmless for the check and somewhere private for the fetch.
+    struct PublicResolver {
+        allow_private: bool,
+    }
+
+    impl Resolve for PublicResolver {
+        fn resolve(&self, name: Name) -> Resolving {
+            let allow_private = self.allow_private;
+            Box::pin(async move {
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                    .await?
+                    .filter(|addr| allow_private || is_public(addr.ip()))
+                    .collect();
+                if addrs.is_empty() {
+                    return Err(format!("{} has no public addresses", name.as_str()).into());
+                }
+
+                Ok(Box::new(addrs.into_iter()) as Addrs)
+            })
+        }
+    }
+
+    // Whether `ip` is somewhere on the public internet, as opposed to loopback,
+    // a private or link-local network, or a range reserved for something else.
+    pub fn is_public(ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => is_public_v4(ip),
+            IpAddr::V6(ip) => {
+                // Addresses with an IPv4 address inside count as that address.
+                if let Some(v4) = ip.to_ipv4_mapped() {
+                    return is_public_v4(v4);
+                }
+                let segments = ip.segments();
+                if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                    // NAT64, 64:ff9b::/96.
+                    let octets = ip.octets();
+                    return is_public_v4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+                }
+
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7.
+                    || segments[0] & 0xfe00 == 0xfc00
+                    // Link-local and the old site-local, fe80::/10 and fec0::/10.
+                    || segments[0] & 0xffc0 == 0xfe80
+                    || segments[0] & 0xffc0 == 0xfec0
+                    // Documentation, 2001:db8::/32.
+                    || segments[..2] == [0x2001, 0xdb8]
+                    // IPv4-compatible, ::/96, loopback included.
+                    || segments[..6] == [0; 6])
+            }
+        }
+    }
+
+    fn is_public_v4(ip: Ipv4Addr) -> bool {
+        let [a, b, c, _] = ip.octets();
+        !(ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_broadcast()
+            || ip.is_documentation()
+            || ip.is_multicast()
+            // "This network", 0.0.0.0/8.
+            || a == 0
+            // Carrier-grade NAT, 100.64.0.0/10.
+            || (a == 100 && b & 0xc0 == 64)
+            // IETF protocol assignments, 192.0.0.0/24.
+            || (a == 192 && b == 0 && c == 0)
+            // Benchmarking, 198.18.0.0/15.
+            || (a == 198 && b & 0xfe == 18)
+            // Reserved, 240.0.0.0/4.
+            || a >= 240)
+    }
+
+    // The first http(s) link in a chat message, if any.
+    pub fn find_link(msg: &str) -> Option<Url> {
+        msg.split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '`'))
+            .filter_map(|word| {
+                let start = word.find("http://").or_else(|| word.find("https://"))?;
+                // Punctuation after a link usually belongs to the sentence, and a
+                // Markdown link ends in a parenthesis.
+                let link = word[start..].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '*', '_']);
+                Url::parse(link).ok()
+            })
+            .find(|url| url.host().is_some())
+    }
+
+    // Pulls a preview out of a page's `<meta>` tags, preferring Open Graph over
+    // Twitter cards over plain HTML. Pages without a title get no preview.
+    pub fn parse_preview(url: &Url, html: &str) -> Option<LinkPreview> {
+        let mut meta: Vec<(String, String)> = Vec::new();
+        let mut title_tag = None;
+
+        let lower = html.to_ascii_lowercase();
+        let mut pos = 0;
+        while let Some(start) = lower[pos..].find('<').map(|start| pos + start) {
+            let rest = &lower[start + 1..];
+            if rest.starts_with("meta") && rest[4..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/') {
+                let end = html[start..].find('>').map_or(html.len(), |end| start + end);
+                let attributes = parse_attributes(&html[start + 5..end]);
+                let attribute = |name: &str| attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+                if let (Some(key), Some(content)) = (attribute("property").or(attribute("name")), attribute("content")) {
+                    meta.push((key.to_ascii_lowercase(), content.clone()));
+                }
+                pos = end;
+            } else if rest.starts_with("title") && title_tag.is_none() {
+                let Some(open_end) = lower[start..].find('>').map(|end| start + end + 1) else {
+                    break;
+                };
+                let close = lower[open_end..].find("</title").map_or(html.len(), |close| open_end + close);
+                title_tag = Some(decode_entities(&html[open_end..close]));
+                pos = close;
+            } else if rest.starts_with("/head") || rest.starts_with("body") {
+                // Everything we look for is in the head.
+                break;
+            } else {
+                pos = start + 1;
+            }
+        }
+
+        let find = |keys: &[&str]| {
+            keys.iter().find_map(|key| {
+                meta.iter()
+                    .find(|(name, content)| name == key && !content.trim().is_empty())
+                    .map(|(_, content)| content.trim().to_owned())
+            })
+        };
+
+        let title = find(&["og:title", "twitter:title"])
+            .or(title_tag)
+            .map(|title| clip(&title, MAX_TITLE_CHARS))
+            .filter(|title| !title.is_empty())?;
+        let description = find(&["og:description", "twitter:description", "description"])
+            .map(|description| clip(&description, MAX_DESCRIPTION_CHARS));
+        let image = find(&["og:image", "og:image:url", "twitter:image", "twitter:image:src"])
+            .and_then(|image| url.join(&image).ok())
+            .filter(|image| matches!(image.scheme(), "http" | "https"))
+            .map(String::from);
+        let site_name = find(&["og:site_name"]).map(|site_name| clip(&site_name, MAX_TITLE_CHARS));
+
+        Some(LinkPreview {
+            url: url.to_string(),
+            title,
+            description,
+            image,
+            site_name,
+        })
+    }
+
+    // `key="value"`, `key='value'` and `key=value` pairs, with keys lowercased
+    // and entities in values decoded.
+    fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+        let mut attributes = Vec::new();
+        let mut rest = tag;
+
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+            let key_end = rest
+                .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+                .unwrap_or(rest.len());
+            if key_end == 0 {
+                return attributes;
+            }
+            let key = rest[..key_end].to_ascii_lowercase();
+            rest = rest[key_end..].trim_start();
+
+            let Some(value) = rest.strip_prefix('=') else {
+                attributes.push((key, String::new()));
+                continue;
+            };
+            let value = value.trim_start();
+            let (raw, after) = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = value[1..].find(quote).map_or(value.len(), |end| end + 1);
+                    (&value[1..end], value.get(end + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let end = value.find(|c: char| c.is_ascii_whitespace()).unwrap_or(value.len());
+                    (&value[..end], &value[end..])
+                }
+            };
+            attributes.push((key, decode_entities(raw)));
+            rest = after;
+        }
+    }
+
+    // The handful of character references that turn up in titles.
+    fn decode_entities(text: &str) -> String {
+        let mut decoded = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find('&') {
+            decoded.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let entity = rest[1..].find(';').filter(|&end| end <= 10).map(|end| &rest[1..end + 1]);
+            let character = entity.and_then(|entity| match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => {
+                    let number = entity.strip_prefix('#')?;
+                    let code = match number.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => number.parse().ok()?,
+                    };
+                    char::from_u32(code)
+                }
+            });
+
+            match (entity, character) {
+                (Some(entity), Some(character)) => {
+                    decoded.push(character);
+                    rest = &rest[entity.len() + 2..];
+                }
+                _ => {
+                    decoded.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        }
+
+        decoded.push_str(rest);
+        decoded
+    }
+
+    // Collapses whitespace and cuts `text` down to `max` characters.
+    fn clip(text: &str, max: usize) -> String {
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        match text.char_indices().nth(max) {
+            Some((end, _)) => format!("{}…", text[..end].trim_end()),
+            None => text,
+        }
+    }
+}}
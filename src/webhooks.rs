@@ -0,0 +1,226 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::{
+        chat::AppState,
+        rooms::Visibility,
+        ws::{ServerFrame, ServerMessage},
+    };
+    use hmac::{Hmac, Mac};
+    use reqwest::{header, Client, StatusCode};
+    use serde::Serialize;
+    use sha2::Sha256;
+    use std::{
+        env,
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+    use tokio::sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, error::TrySendError},
+    };
+    use url::Url;
+    use uuid::Uuid;
+
+    // Deliveries are tried this many times, waiting twice as long after each
+    // failure, starting from `FIRST_RETRY`.
+    const MAX_ATTEMPTS: u32 = 5;
+    const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+    // How many events may wait for each webhook. A webhook that is down for
+    // longer than these take misses the rest, rather than them piling up.
+    const QUEUE_LENGTH: usize = 256;
+
+    // Sent as JSON to every webhook for everything said in a room.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct WebhookEvent {
+        // Stays the same across retries, so receivers can skip repeats.
+        pub id: Uuid,
+        pub event: &'static str,
+        pub room: String,
+        pub message: ServerMessage,
+    }
+
+    // Where room events are posted, from `CHAT_WEBHOOKS` (comma-separated URLs).
+    // With `CHAT_WEBHOOK_SECRET` set, each request carries `X-Chat-Timestamp`
+    // and `X-Chat-Signature: sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`.
+    pub struct Webhooks {
+        urls: Vec<Url>,
+        secret: Option<Vec<u8>>,
+        client: Client,
+    }
+
+    impl Webhooks {
+        pub fn new(urls: Vec<Url>, secret: Option<Vec<u8>>) -> Self {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(10))
+                .user_agent(concat!("web-app-axum/", env!("CARGO_PKG_VERSION"), " (webhook)"))
+                .build()
+                .expect("the TLS backend is available");
+
+            Webhooks { urls, secret, client }
+        }
+
+        pub fn from_env() -> Self {
+            let urls = env::var("CHAT_WEBHOOKS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .filter_map(|url| match Url::parse(url) {
+                    Ok(url) => Some(url),
+                    Err(err) => {
+                        tracing::error!("Ignoring webhook {url:?}: {err}");
+                        None
+                    }
+                })
+                .collect();
+            let secret = env::var("CHAT_WEBHOOK_SECRET").ok().map(String::into_bytes);
+
+            Webhooks::new(urls, secret)
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.urls.is_empty()
+        }
+
+        fn sign(&self, timestamp: u64, body: &[u8]) -> Option<String> {
+            let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_ref()?).ok()?;
+            mac.update(timestamp.to_string().as_bytes());
+            mac.update(b".");
+            mac.update(body);
+            Some(format!("sha256={}", hex::encode(mac.finalize().into_bytes())))
+        }
+
+        // Posts `event` to `url`, retrying on network errors, rate limits and
+        // server errors. Anything else the receiver didn't like won't get better.
+        async fn deliver(&self, url: &Url, event: &WebhookEvent) {
+            let Ok(body) = serde_json::to_vec(event) else {
+                return;
+            };
+
+            let mut wait = FIRST_RETRY;
+            for attempt in 1..=MAX_ATTEMPTS {
+                // Signed afresh each time, so retries don't look like replays.
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                let mut request = self
+                    .client
+                    .post(url.clone())
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header("X-Chat-Event", event.event)
+                    .header("X-Chat-Timestamp", timestamp.to_string())
+                    .body(body.clone());
+                if let Some(signature) = self.sign(timestamp, &body) {
+                    request = request.header("X-Chat-Signature", signature);
+                }
+
+                let retry = match request.send().await {
+                    Ok(response) if response.status().is_success() => return,
+                    Ok(response) => {
+                        let status = response.status();
+                        tracing::warn!("Webhook {url} answered {status} to event {}", event.id);
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                    }
+                    Err(err) => {
+                        tracing::warn!("Webhook {url} failed for event {}: {err}", event.id);
+                        true
+                    }
+                };
+                if !retry || attempt == MAX_ATTEMPTS {
+                    break;
+                }
+
+                tokio::time::sleep(wait).await;
+                wait *= 2;
+            }
+
+            tracing::error!("Gave up delivering event {} to {url}", event.id);
+        }
+    }
+
+    // Posts everything said in public rooms to the configured webhooks, for as
+    // long as the server runs. Each webhook gets its events one at a time, in
+    // order, from its own queue, so a slow one holds up nobody else.
+    pub fn spawn_webhooks(state: &Arc<AppState>) {
+        if state.webhooks.is_empty() {
+            return;
+        }
+
+        let queues: Vec<(Url, mpsc::Sender<WebhookEvent>)> = state
+            .webhooks
+            .urls
+            .iter()
+            .map(|url| {
+                let (queue, mut events) = mpsc::channel::<WebhookEvent>(QUEUE_LENGTH);
+                let (state, worker_url) = (state.clone(), url.clone());
+                tokio::spawn(async move {
+                    while let Some(event) = events.recv().await {
+                        state.webhooks.deliver(&worker_url, &event).await;
+                    }
+                });
+                (url.clone(), queue)
+            })
+            .collect();
+
+        let mut rx = state.tx.subscribe();
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = match rx.recv().await {
+                    Ok(frame) => match &frame.frame {
+                        ServerFrame::Chat(message) => message.clone(),
+                        _ => continue,
+                    },
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Webhooks fell behind and missed {missed} events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                // What's said in rooms that aren't public stays among their members.
+                let public = state
+                    .rooms
+                    .get(&message.room)
+                    .is_some_and(|room| room.settings.visibility == Visibility::Public);
+                if !public {
+                    continue;
+                }
+
+                let event = WebhookEvent {
+                    id: Uuid::new_v4(),
+                    event: "message",
+                    room: message.room.clone(),
+                    message,
+                };
+                for (url, queue) in &queues {
+                    if let Err(TrySendError::Full(event)) = queue.try_send(event.clone()) {
+                        tracing::warn!("Webhook {url} is too far behind, dropping event {}", event.id);
+                    }
+                }
+            }
+        });
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        let webhooks = Webhooks::new(Vec::new(), Some(b"shh".to_vec()));
+        assert_eq!(
+            webhooks.sign(1_700_000_000, br#"{"a":1}"#).as_deref(),
+            Some("sha256=be310eac0f84daf469d630347a950258de768b365563ab68f29f2f783473d547")
+        );
+        assert_ne!(webhooks.sign(1_700_000_001, br#"{"a":1}"#), webhooks.sign(1_700_000_000, br#"{"a":1}"#));
+    }
+
+    #[test]
+    fn unsigned_without_a_secret() {
+        let webhooks = Webhooks::new(Vec::new(), None);
+        assert_eq!(webhooks.sign(1_700_000_000, b"{}"), None);
+    }
+}
@@ -1,59 +1,182 @@
+use crate::rooms::Room;
 use leptos::*;
 
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-pub type ClientMessage = String;
+// The room everyone starts out in. It always exists.
+pub const DEFAULT_ROOM: &str = "general";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_owned()
+}
+
+// Frames a client sends over the websocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Must be the first frame. `token` lets another tab or device join under a
+    // name that is already online, and keeps who we are across names.
+    Join { name: String, token: Option<String> },
+    Chat {
+        id: Uuid,
+        msg: String,
+        // Something uploaded to `/attachments` beforehand.
+        #[serde(default)]
+        attachment: Option<Attachment>,
+    },
+    // The newest message this device has shown the user.
+    Read { id: Uuid },
+    // The user has seen their mentions.
+    ReadMentions,
+    // A `/command` line, as typed.
+    Command { line: String },
+    // Leave the room we are in for `room`. Chat goes to the room we are in.
+    JoinRoom { room: String },
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerMessage {
+    pub id: Uuid,
+    #[serde(default = "default_room")]
+    pub room: String,
     pub sender: String,
     pub msg: String,
+    #[serde(default)]
+    pub attachment: Option<Attachment>,
+    // Filled in by the server for the first link in `msg`, if it has a preview.
+    #[serde(default)]
+    pub preview: Option<LinkPreview>,
+    // The users `msg` mentions, as checked by the server.
+    #[serde(default)]
+    pub mentions: Vec<String>,
 }
 
-pub fn create_ws_signal() -> ReadSignal<Option<ServerMessage>> {
-    let (get, set) = create_signal(None);
-    create_server_ws_signal(set);
+// A message that mentioned us, as kept in our inbox.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mention {
+    pub id: Uuid,
+    #[serde(default = "default_room")]
+    pub room: String,
+    pub sender: String,
+    pub msg: String,
+}
 
-    get
+// A file shared in the chat. `id` is the server's for this one upload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: usize,
 }
 
-use js_sys::{Function, JsString};
-use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
-use web_sys::{MessageEvent, WebSocket};
+impl Attachment {
+    pub fn url(&self) -> String {
+        format!("{ATTACHMENTS_URL}/{}", self.id)
+    }
 
-fn create_server_ws_signal(set: WriteSignal<Option<ServerMessage>>) {
-    let ws = use_context::<ServerWS>();
+    // Only images have thumbnails; `width` is one of the sizes the server makes.
+    pub fn thumbnail_url(&self, width: u32) -> String {
+        format!("{ATTACHMENTS_URL}/{}/thumbnail/{width}", self.id)
+    }
 
-    match ws {
-        Some(ServerWS(ws)) => {
-            create_effect(move |_| {
-                let callback = Closure::wrap(Box::new(move |event: MessageEvent| {
-                    log::info!("Received a message from the server!");
-                    let ws_string = event
-                        .data()
-                        .dyn_into::<JsString>()
-                        .unwrap()
-                        .as_string()
-                        .unwrap();
-                    let parsed = serde_json::from_str::<ServerMessage>(&ws_string);
-                    match parsed {
-                        Ok(parsed) => {
-                            log::info!("Parsed: {parsed:?}");
-                            set.set(Some(parsed));
-                        }
-                        Err(err) => {
-                            log::error!("Failed to parse: {err:?}");
-                        }
-                    }
-                }) as Box<dyn FnMut(_)>);
-                let function: &Function = callback.as_ref().unchecked_ref();
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+}
+
+// What a linked page says about itself, shown as a card under the message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+}
 
-                ws.set_onmessage(Some(function));
-                callback.forget();
-            });
+// Frames the server sends over the websocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Chat(ServerMessage),
+    // Sent once the join went through.
+    Session { name: String, token: String },
+    // The latest messages, oldest first, sent right after `Session`.
+    History { messages: Vec<ServerMessage> },
+    // Another device of ours has read up to `id`.
+    Read { id: Uuid },
+    // The preview for a link in message `id`, fetched after it went out.
+    Preview { id: Uuid, room: String, preview: LinkPreview },
+    // Someone mentioned us, wherever we are. `unread` counts our unseen mentions.
+    Mention { mention: Mention, unread: usize },
+    // Our whole mentions inbox, on joining and whenever another device reads it.
+    Mentions { mentions: Vec<Mention>, unread: usize },
+    // We are in `room` now; its `History` follows. `admin` says whether we may
+    // change its settings.
+    EnteredRoom { room: Room, admin: bool },
+    // Someone changed a room's settings or members.
+    RoomUpdated { room: Room },
+    // Which rooms we can see may have changed.
+    RoomsChanged,
+    // Something for this client only, such as a command's answer.
+    Notice { msg: String },
+    // Our chat message `id` didn't go out, because of `msg`.
+    Rejected { id: Uuid, msg: String },
+    Error { msg: String },
+}
+
+impl ServerFrame {
+    // The room this frame is about, for frames only that room's members get.
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            ServerFrame::Chat(message) => Some(&message.room),
+            ServerFrame::Preview { room, .. } => Some(room),
+            _ => None,
         }
+    }
+}
+
+pub fn create_ws_signal() -> ReadSignal<Option<ServerFrame>> {
+    match use_context::<ServerWS>() {
+        Some(ws) => ws.frames.read_only(),
         None => {
             leptos::logging::error!(r#"No websocket provided at root of app"#);
+            create_signal(None).0
+        }
+    }
+}
+
+use crate::codec::{decode, Encoding, Payload};
+use js_sys::{Array, ArrayBuffer, Function, JsString, Uint8Array};
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    BinaryType, EventSource, File, FormData, Headers, MessageEvent, RequestInit, Response,
+    WebSocket,
+};
+
+// Where the fallback transport streams frames from, and posts ours to.
+const EVENTS_URL: &str = "/events";
+// Where files are uploaded to, and served back from.
+const ATTACHMENTS_URL: &str = "/attachments";
+
+fn receive_frame(frames: RwSignal<Option<ServerFrame>>, event: MessageEvent) {
+    log::info!("Received a message from the server!");
+    let data = event.data();
+    let payload = match data.dyn_into::<ArrayBuffer>() {
+        Ok(buffer) => Payload::Binary(Uint8Array::new(&buffer).to_vec()),
+        Err(data) => Payload::Text(data.dyn_into::<JsString>().unwrap().as_string().unwrap()),
+    };
+    let parsed = decode::<ServerFrame>(&payload);
+    match parsed {
+        Ok(parsed) => {
+            log::info!("Parsed: {parsed:?}");
+            frames.set(Some(parsed));
+        }
+        Err(err) => {
+            log::error!("Failed to parse: {err:?}");
         }
     }
 }
@@ -77,31 +200,199 @@ where
     }
 }
 
-pub fn send_msg(msg: &str) -> Result<(), ()> {
+pub fn send_msg(msg: &ClientMessage) -> Result<(), ()> {
     let ws = use_context::<ServerWS>();
     match ws {
-        Some(ServerWS(ws)) => {
-            let msg: ClientMessage = msg.to_owned();
-            let str = serde_json::to_string(&msg);
+        Some(ws) => {
+            let str = serde_json::to_string(msg);
             log::info!("{str:?}");
-            let _ = match str {
-                Ok(str) => ws.send_with_str(str.as_str()),
-                Err(_) => return Err(()),
-            };
-            Ok(())
-        },
+            let str = str.map_err(|_| ())?;
+            ws.transport.with_value(|transport| match transport {
+                // Speak whatever encoding the server picked for this socket.
+                Some(Transport::Socket(socket)) => {
+                    let encoding = Encoding::from_protocol(&socket.protocol()).unwrap_or(Encoding::Json);
+                    let sent = match encoding.encode(msg).map_err(|_| ())? {
+                        Payload::Text(text) => socket.send_with_str(text.as_str()),
+                        Payload::Binary(bytes) => socket.send_with_u8_array(&bytes),
+                    };
+                    sent.map_err(|_| ())
+                }
+                Some(Transport::Events(source)) => match ws.post_url.get_value() {
+                    Some(url) if source.ready_state() == EventSource::OPEN => {
+                        post_frame(&url, &str).map_err(|_| ())
+                    }
+                    // The event stream hasn't told us where to post yet, or is gone.
+                    _ => Err(()),
+                },
+                None => Err(()),
+            })
+        }
         None => Err(()),
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-struct ServerWS(WebSocket);
+// We prefer a websocket, and fall back to server-sent events plus POSTs when a
+// proxy won't let the websocket through.
+#[derive(Clone, Debug)]
+enum Transport {
+    Socket(WebSocket),
+    Events(EventSource),
+}
+
+#[derive(Clone, Copy)]
+struct ServerWS {
+    transport: StoredValue<Option<Transport>>,
+    // Where to post our frames on the fallback transport, once the server said.
+    post_url: StoredValue<Option<String>>,
+    // The latest frame from the server, whichever way it came.
+    frames: RwSignal<Option<ServerFrame>>,
+}
+
+impl ServerWS {
+    fn connect_socket(self, url: &str) -> Result<(), JsValue> {
+        // Offer every encoding we speak; the server answers with the one it picked.
+        let protocols = Encoding::PREFERENCE
+            .into_iter()
+            .map(|encoding| JsValue::from_str(encoding.protocol()))
+            .collect::<Array>();
+        let socket = WebSocket::new_with_str_sequence(url, &protocols)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let opened = store_value(false);
+
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            receive_frame(self.frames, event);
+        }) as Box<dyn FnMut(_)>);
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref::<Function>()));
+        on_message.forget();
+
+        let on_open = Closure::wrap(Box::new(move || {
+            opened.set_value(true);
+        }) as Box<dyn FnMut()>);
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref::<Function>()));
+        on_open.forget();
+
+        // A socket that closes before it ever opened means the upgrade failed.
+        let on_close = Closure::wrap(Box::new(move || {
+            if !opened.get_value() {
+                log::warn!("WebSocket upgrade failed, falling back to server-sent events");
+                if self.connect_events().is_err() {
+                    log::error!("Failed to connect to {EVENTS_URL}!");
+                }
+            }
+        }) as Box<dyn FnMut()>);
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref::<Function>()));
+        on_close.forget();
+
+        self.transport.set_value(Some(Transport::Socket(socket)));
+        Ok(())
+    }
+
+    fn connect_events(self) -> Result<(), JsValue> {
+        let source = EventSource::new(EVENTS_URL)?;
+
+        // The first event carries the id of our session on the server.
+        let on_connection = Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let Some(id) = event.data().as_string() {
+                self.post_url.set_value(Some(format!("{EVENTS_URL}/{id}")));
+            }
+        }) as Box<dyn FnMut(_)>);
+        source.add_event_listener_with_callback(
+            "connection",
+            on_connection.as_ref().unchecked_ref::<Function>(),
+        )?;
+        on_connection.forget();
+
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            receive_frame(self.frames, event);
+        }) as Box<dyn FnMut(_)>);
+        source.set_onmessage(Some(on_message.as_ref().unchecked_ref::<Function>()));
+        on_message.forget();
+
+        self.transport.set_value(Some(Transport::Events(source)));
+        Ok(())
+    }
+}
+
+fn post_frame(url: &str, body: &str) -> Result<(), JsValue> {
+    let headers = Headers::new()?;
+    headers.set("Content-Type", "application/json")?;
+
+    let mut init = RequestInit::new();
+    init.method("POST")
+        .headers(&headers)
+        .body(Some(&JsValue::from_str(body)));
+
+    // Nobody waits on the response; replies come back over the event stream.
+    let _ = window().fetch_with_str_and_init(url, &init);
+    Ok(())
+}
+
+// Uploads `file` so it can be attached to a message. Only those in the chat
+// may, so it goes with our name and session token.
+pub async fn upload_attachment(file: &File, user: &str, token: &str) -> Result<Attachment, JsValue> {
+    let form = FormData::new()?;
+    form.append_with_str("user", user)?;
+    form.append_with_str("token", token)?;
+    form.append_with_blob_and_filename("file", file, &file.name())?;
+
+    let mut init = RequestInit::new();
+    init.method("POST").body(Some(&form));
+
+    let response: Response = JsFuture::from(window().fetch_with_str_and_init(ATTACHMENTS_URL, &init))
+        .await?
+        .dyn_into()?;
+    let body = JsFuture::from(response.text()?).await?.as_string().unwrap_or_default();
+    if !response.ok() {
+        return Err(JsValue::from_str(&body));
+    }
+
+    serde_json::from_str(&body).map_err(|err| JsValue::from_str(&err.to_string()))
+}
 
 pub fn provide_websocket(url: &str) -> Result<(), JsValue> {
     if use_context::<ServerWS>().is_none() {
-        let ws = WebSocket::new(url)?;
-        provide_context(ServerWS(ws));
+        let ws = ServerWS {
+            transport: store_value(None),
+            post_url: store_value(None),
+            frames: create_rw_signal(None),
+        };
+        provide_context(ws);
+
+        if let Err(err) = ws.connect_socket(url) {
+            log::warn!("WebSocket unavailable ({err:?}), falling back to server-sent events");
+            ws.connect_events()?;
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Where we keep our session token. It is who we are to the rooms we are a
+// member of, whatever name we go by, so it outlasts the name.
+const SESSION_KEY: &str = "session-token";
+
+// The token the server gave us the last time we joined, so that other tabs can
+// join as the same user and we come back as the same identity.
+pub fn stored_token() -> Option<String> {
+    let storage = window().local_storage().ok()??;
+    storage.get_item(SESSION_KEY).ok()?
+}
+
+pub fn store_token(token: &str) {
+    if let Ok(Some(storage)) = window().local_storage() {
+        let _ = storage.set_item(SESSION_KEY, token);
+    }
+}
+
+// Who we joined the chat as, for the pages inside it that act on our behalf.
+#[derive(Clone, Copy)]
+pub struct JoinedAs(pub ReadSignal<Option<String>>);
+
+impl JoinedAs {
+    // Our name and session token, for calling server functions as us.
+    pub fn credentials(self) -> (Option<String>, Option<String>) {
+        let name = self.0.get_untracked();
+        let token = name.as_ref().and_then(|_| stored_token());
+        (name, token)
+    }
+}
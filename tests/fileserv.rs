@@ -0,0 +1,179 @@
+// The static file fallback against a site root in a temporary directory, with
+// a file next to it that must never be served.
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, HeaderMap, Request, StatusCode},
+    Router,
+};
+use leptos::LeptosOptions;
+use std::{fs, path::PathBuf};
+use tower::ServiceExt;
+use uuid::Uuid;
+use web_app_axum::{error_template::ErrorBody, fileserv::file_and_error_handler};
+
+const SECRET: &str = "not for the web";
+
+struct Site {
+    dir: PathBuf,
+    app: Router,
+}
+
+impl Drop for Site {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn site() -> Site {
+    let dir = std::env::temp_dir().join(format!("fileserv-{}", Uuid::new_v4()));
+    let root = dir.join("site");
+    fs::create_dir_all(root.join("pkg")).unwrap();
+    fs::write(root.join("pkg/app.css"), "body {}").unwrap();
+    fs::write(root.join("pkg/app.css.gz"), "pretend this is gzip").unwrap();
+    fs::write(root.join("pkg/app.3f2a9c1d.js"), "main()").unwrap();
+    fs::write(root.join("robots.txt"), "User-agent: *").unwrap();
+    fs::write(dir.join("secret.txt"), SECRET).unwrap();
+
+    let options = LeptosOptions::builder()
+        .output_name("web-app-axum")
+        .site_root(root.to_string_lossy())
+        .site_pkg_dir("pkg")
+        .build();
+    let app = Router::new().fallback(file_and_error_handler).with_state(options);
+    Site { dir, app }
+}
+
+async fn send(site: &Site, request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let mut response = site.app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let mut body = Vec::new();
+    while let Some(chunk) = response.body_mut().data().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    (status, response.headers().clone(), String::from_utf8_lossy(&body).into_owned())
+}
+
+async fn get(site: &Site, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let (status, _headers, body) = send(site, request).await;
+    (status, body)
+}
+
+#[tokio::test]
+async fn serves_files_in_the_site_root() {
+    let site = site();
+    assert_eq!(get(&site, "/pkg/app.css").await, (StatusCode::OK, "body {}".to_owned()));
+}
+
+#[tokio::test]
+async fn missing_static_files_are_not_found() {
+    let site = site();
+    for uri in ["/pkg/missing.js", "/favicon.ico", "/pkg/"] {
+        let (status, body) = get(&site, uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        let body: ErrorBody = serde_json::from_str(&body).unwrap();
+        assert_eq!(body.error, "not_found", "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn traversal_stays_inside_the_site_root() {
+    let site = site();
+    for uri in [
+        "/../secret.txt",
+        "/pkg/../../secret.txt",
+        "/%2e%2e/secret.txt",
+        "/pkg/..%2f..%2fsecret.txt",
+        "/pkg/..%5c..%5csecret.txt",
+        "//secret.txt",
+    ] {
+        let (status, body) = get(&site, uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        assert!(!body.contains(SECRET), "{uri} leaked the secret");
+    }
+}
+
+#[tokio::test]
+async fn malformed_paths_are_not_found() {
+    let site = site();
+    for uri in ["/%", "/%zz.css", "/%ff%fe.js", "/pkg/%00", "/pkg/app.css%00.js"] {
+        let (status, _body) = get(&site, uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn unknown_pages_get_the_apps_not_found_page() {
+    let site = site();
+    let (status, body) = get(&site, "/no/such/page").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(body.contains("error-page"), "{body}");
+}
+
+#[tokio::test]
+async fn precompressed_variants_are_served_when_accepted() {
+    let site = site();
+    let request = Request::builder()
+        .uri("/pkg/app.css")
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .body(Body::empty())
+        .unwrap();
+    let (status, headers, body) = send(&site, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(headers[header::VARY], "accept-encoding");
+    assert_eq!(body, "pretend this is gzip");
+}
+
+#[tokio::test]
+async fn etags_answer_if_none_match() {
+    let site = site();
+    let request = Request::builder().uri("/pkg/app.css").body(Body::empty()).unwrap();
+    let (_status, headers, _body) = send(&site, request).await;
+    let etag = headers[header::ETAG].to_str().unwrap().to_owned();
+    assert!(etag.starts_with('"') && etag.ends_with('"'), "{etag}");
+
+    let request = Request::builder()
+        .uri("/pkg/app.css")
+        .header(header::IF_NONE_MATCH, format!("\"other\", {etag}"))
+        .body(Body::empty())
+        .unwrap();
+    let (status, headers, body) = send(&site, request).await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    assert_eq!(headers[header::ETAG], etag.as_str());
+    assert!(body.is_empty());
+
+    let request = Request::builder()
+        .uri("/pkg/app.css")
+        .header(header::IF_NONE_MATCH, "\"other\"")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(&site, request).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rewritten_files_get_new_etags() {
+    let site = site();
+    let etag = |headers: HeaderMap| headers[header::ETAG].to_str().unwrap().to_owned();
+    let request = || Request::builder().uri("/pkg/app.css").body(Body::empty()).unwrap();
+    let before = etag(send(&site, request()).await.1);
+
+    // Most likely within the same second, which is all `Last-Modified` says.
+    fs::write(site.dir.join("site/pkg/app.css"), "body { margin: 0 }").unwrap();
+    let (_status, headers, body) = send(&site, request()).await;
+    assert_eq!(body, "body { margin: 0 }");
+    assert_ne!(etag(headers), before);
+}
+
+// The package directory's files keep their names across deploys, so nothing
+// may be kept without asking.
+#[tokio::test]
+async fn everything_is_revalidated() {
+    let site = site();
+    for uri in ["/pkg/app.3f2a9c1d.js", "/pkg/app.css", "/robots.txt"] {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let (_status, headers, _body) = send(&site, request).await;
+        assert_eq!(headers[header::CACHE_CONTROL], "no-cache", "{uri}");
+    }
+}
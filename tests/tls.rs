@@ -0,0 +1,158 @@
+// HTTPS with the self-signed certificates in `tests/fixtures`, made with
+//
+//     openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+//         -days 36500 -subj "/CN=localhost" -addext "subjectAltName=DNS:localhost" \
+//         -addext "basicConstraints=critical,CA:FALSE" \
+//         -keyout localhost-a.key -out localhost-a.crt
+
+use axum::{extract::ConnectInfo, routing::get, Router};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+    TlsConnector,
+};
+use uuid::Uuid;
+use web_app_axum::tls::{serve_redirect, serve_tls, ReloadingCert};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+// The certificate and key the server reads, starting out as a copy of
+// `localhost-a`.
+struct CertFiles {
+    dir: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl CertFiles {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("tls-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = CertFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            dir,
+        };
+        files.replace_with("localhost-a");
+        files
+    }
+
+    fn replace_with(&self, name: &str) {
+        fs::copy(fixture(&format!("{name}.crt")), &self.cert).unwrap();
+        fs::copy(fixture(&format!("{name}.key")), &self.key).unwrap();
+    }
+}
+
+impl Drop for CertFiles {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn start_server(cert: Arc<ReloadingCert>) -> SocketAddr {
+    let app = Router::new().route(
+        "/",
+        get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { format!("hello {}", peer.ip()) }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_tls(listener, app, cert));
+    addr
+}
+
+// Fetches `/` over HTTPS, trusting only the certificate in `trusted`.
+async fn get_trusting(addr: SocketAddr, trusted: &str) -> io::Result<String> {
+    let mut roots = RootCertStore::empty();
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(fixture(trusted))?))?;
+    for cert in certs {
+        roots.add(&Certificate(cert)).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let stream = TcpStream::connect(addr).await?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response).await {
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err),
+        _ => {}
+    }
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+#[tokio::test]
+async fn serves_https_and_knows_the_peer() {
+    let files = CertFiles::new();
+    let cert = Arc::new(ReloadingCert::load(&files.cert, &files.key).unwrap());
+    let addr = start_server(cert);
+
+    let response = get_trusting(addr, "localhost-a.crt").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("hello 127.0.0.1"), "{response}");
+
+    assert!(get_trusting(addr, "localhost-b.crt").await.is_err());
+}
+
+#[tokio::test]
+async fn reloads_certificates_that_change_on_disk() {
+    let files = CertFiles::new();
+    let cert = Arc::new(ReloadingCert::load(&files.cert, &files.key).unwrap());
+    let addr = start_server(cert.clone());
+    assert!(!cert.reload_if_changed());
+
+    files.replace_with("localhost-b");
+    assert!(cert.reload_if_changed());
+    assert!(get_trusting(addr, "localhost-b.crt").await.is_ok());
+    assert!(get_trusting(addr, "localhost-a.crt").await.is_err());
+}
+
+#[tokio::test]
+async fn keeps_the_old_certificate_when_the_new_one_is_broken() {
+    let files = CertFiles::new();
+    let cert = Arc::new(ReloadingCert::load(&files.cert, &files.key).unwrap());
+    let addr = start_server(cert.clone());
+
+    fs::write(&files.cert, "not a certificate").unwrap();
+    assert!(!cert.reload_if_changed());
+    assert!(get_trusting(addr, "localhost-a.crt").await.is_ok());
+}
+
+#[tokio::test]
+async fn redirects_http_to_https() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_redirect(listener, 8443));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"POST /search?q=tls HTTP/1.1\r\nHost: chat.example.com:8080\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 308"), "{response}");
+    assert!(
+        response.contains("location: https://chat.example.com:8443/search?q=tls\r\n"),
+        "{response}"
+    );
+}
@@ -0,0 +1,246 @@
+// The unfurler against a small local server standing in for the web.
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+use std::{
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use url::Url;
+use web_app_axum::{
+    unfurl::{find_link, is_public, parse_preview, UnfurlConfig, Unfurler},
+    ws::LinkPreview,
+};
+
+const ARTICLE: &str = r#"<!doctype html>
+<html>
+<head>
+    <title>Fallback title</title>
+    <meta property="og:title" content="Rust &amp; the Web">
+    <meta property="og:description" content="  How we   build things.  ">
+    <meta property="og:image" content="/images/cover.png">
+    <meta property="og:site_name" content='Example Blog'>
+</head>
+<body><p>Hello</p></body>
+</html>"#;
+
+struct StandIn {
+    addr: SocketAddr,
+    hits: Arc<AtomicUsize>,
+}
+
+impl StandIn {
+    fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://{}{path}", self.addr)).unwrap()
+    }
+}
+
+fn html(body: String) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], body)
+}
+
+async fn stand_in() -> StandIn {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+        .route(
+            "/article",
+            get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                html(ARTICLE.to_owned())
+            }),
+        )
+        .route(
+            "/plain",
+            get(|| async {
+                html(String::from(
+                    r#"<html><head><title>Just a title</title><meta name="twitter:description" content="From the card"></head></html>"#,
+                ))
+            }),
+        )
+        .route("/untitled", get(|| async { html(String::from("<html><body>Nothing here</body></html>")) }))
+        .route("/image.png", get(|| async { ([(header::CONTENT_TYPE, "image/png")], vec![0u8; 16]) }))
+        .route(
+            "/huge",
+            get(|| async {
+                html(format!("<html><head>{}<title>Too far in</title></head></html>", " ".repeat(64 * 1024)))
+            }),
+        )
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                html(ARTICLE.to_owned())
+            }),
+        )
+        .route("/redirect", get(|| async { Redirect::temporary("/article") }))
+        .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+        .with_state(hits.clone());
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+
+    StandIn { addr, hits }
+}
+
+// The stand-in lives on loopback, which the real configuration refuses.
+fn unfurler() -> Unfurler {
+    Unfurler::new(UnfurlConfig {
+        timeout: Duration::from_millis(500),
+        max_bytes: 16 * 1024,
+        allow_private: true,
+        ..UnfurlConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn reads_open_graph_metadata() {
+    let server = stand_in().await;
+    let url = server.url("/article");
+
+    let preview = unfurler().unfurl(&url).await;
+
+    assert_eq!(
+        preview,
+        Some(LinkPreview {
+            url: url.to_string(),
+            title: String::from("Rust & the Web"),
+            description: Some(String::from("How we build things.")),
+            image: Some(server.url("/images/cover.png").to_string()),
+            site_name: Some(String::from("Example Blog")),
+        })
+    );
+}
+
+#[tokio::test]
+async fn falls_back_to_title_and_twitter_card() {
+    let server = stand_in().await;
+
+    let preview = unfurler().unfurl(&server.url("/plain")).await.unwrap();
+
+    assert_eq!(preview.title, "Just a title");
+    assert_eq!(preview.description.as_deref(), Some("From the card"));
+    assert_eq!(preview.image, None);
+}
+
+#[tokio::test]
+async fn skips_pages_without_a_preview() {
+    let server = stand_in().await;
+    let unfurler = unfurler();
+
+    assert_eq!(unfurler.unfurl(&server.url("/untitled")).await, None);
+    assert_eq!(unfurler.unfurl(&server.url("/image.png")).await, None);
+    assert_eq!(unfurler.unfurl(&server.url("/missing")).await, None);
+}
+
+#[tokio::test]
+async fn reads_no_more_than_the_size_cap() {
+    let server = stand_in().await;
+
+    assert_eq!(unfurler().unfurl(&server.url("/huge")).await, None);
+}
+
+#[tokio::test]
+async fn gives_up_on_slow_pages() {
+    let server = stand_in().await;
+
+    let started = std::time::Instant::now();
+    assert_eq!(unfurler().unfurl(&server.url("/slow")).await, None);
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn follows_redirects_but_not_forever() {
+    let server = stand_in().await;
+    let unfurler = unfurler();
+
+    let preview = unfurler.unfurl(&server.url("/redirect")).await.unwrap();
+    assert_eq!(preview.title, "Rust & the Web");
+    assert_eq!(preview.url, server.url("/article").to_string());
+
+    assert_eq!(unfurler.unfurl(&server.url("/loop")).await, None);
+}
+
+#[tokio::test]
+async fn caches_previews() {
+    let server = stand_in().await;
+    let unfurler = unfurler();
+    let url = server.url("/article");
+
+    let first = unfurler.unfurl(&url).await;
+    let second = unfurler.unfurl(&url).await;
+
+    assert!(first.is_some());
+    assert_eq!(first, second);
+    assert_eq!(server.hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn refuses_private_addresses() {
+    let server = stand_in().await;
+    let unfurler = Unfurler::default();
+
+    assert_eq!(unfurler.unfurl(&server.url("/article")).await, None);
+    let by_name = Url::parse(&format!("http://localhost:{}/article", server.addr.port())).unwrap();
+    assert_eq!(unfurler.unfurl(&by_name).await, None);
+    assert_eq!(server.hits.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn tells_public_addresses_from_private_ones() {
+    let public = ["93.184.216.34", "2606:4700::6810:85e5", "::ffff:93.184.216.34"];
+    let private = [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "255.255.255.255",
+        "::1",
+        "::",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+        "::ffff:10.0.0.1",
+        "64:ff9b::a00:1",
+    ];
+
+    for ip in public {
+        assert!(is_public(ip.parse::<IpAddr>().unwrap()), "{ip} should be public");
+    }
+    for ip in private {
+        assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{ip} should be private");
+    }
+}
+
+#[test]
+fn finds_the_first_link_in_a_message() {
+    let link = |msg: &str| find_link(msg).map(String::from);
+
+    assert_eq!(link("see https://example.com/a."), Some(String::from("https://example.com/a")));
+    assert_eq!(
+        link("[docs](https://docs.rs/leptos) and http://example.org"),
+        Some(String::from("https://docs.rs/leptos"))
+    );
+    assert_eq!(link("ftp://example.com and no web links"), None);
+    assert_eq!(link("nothing here"), None);
+}
+
+#[test]
+fn ignores_metadata_outside_the_head() {
+    let url = Url::parse("https://example.com/").unwrap();
+    let html = r#"<head><title>Head</title></head><body><meta property="og:title" content="Body"></body>"#;
+
+    assert_eq!(parse_preview(&url, html).unwrap().title, "Head");
+}